@@ -0,0 +1,111 @@
+use std::{fmt, net::IpAddr, str::FromStr};
+
+use crate::packet::Result;
+
+/// A block of addresses, written as `192.0.2.0/24` or `2001:db8::/32`. A bare address stands for
+/// just itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Network {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Network {
+    /// Networks of IPv4 addresses mapped into IPv6, written as `::ffff:192.0.2.0/120`, are taken
+    /// as the IPv4 network they carry, which requires a prefix that covers the mapping.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<Self> {
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix_len > max {
+            return Err(format!("Prefix length {} too long for {}", prefix_len, addr).into());
+        }
+
+        if let IpAddr::V6(v6) = addr {
+            if let Some(v4) = v6.to_ipv4_mapped() {
+                let prefix_len = prefix_len.checked_sub(96).ok_or_else(|| {
+                    format!("Prefix length {} too short for {}", prefix_len, addr)
+                })?;
+                return Ok(Self {
+                    addr: IpAddr::V4(v4),
+                    prefix_len,
+                });
+            }
+        }
+
+        Ok(Self { addr, prefix_len })
+    }
+
+    /// How many leading bits of an address have to match.
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// Whether `addr` lies within the network. IPv4 addresses mapped into IPv6, as seen on
+    /// dual-stack sockets, count as the IPv4 address they carry.
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                prefix_matches(&net.octets(), &addr.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                prefix_matches(&net.octets(), &addr.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether the first `prefix_len` bits of `a` and `b` are the same.
+fn prefix_matches(a: &[u8], b: &[u8], prefix_len: u8) -> bool {
+    let full = prefix_len as usize / 8;
+    let rest = prefix_len % 8;
+
+    if a[..full] != b[..full] {
+        return false;
+    }
+    if rest == 0 {
+        return true;
+    }
+
+    let mask = 0xFF << (8 - rest);
+    (a[full] & mask) == (b[full] & mask)
+}
+
+impl FromStr for Network {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once('/') {
+            Some((addr, prefix_len)) => Self::new(addr.parse()?, prefix_len.parse()?),
+            None => {
+                let addr: IpAddr = s.parse()?;
+                Self::new(addr, if addr.is_ipv4() { 32 } else { 128 })
+            }
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// An access control list of networks. Nothing is allowed unless it's on the list.
+#[derive(Debug, Clone, Default)]
+pub struct Acl {
+    networks: Vec<Network>,
+}
+
+impl Acl {
+    pub fn new(networks: Vec<Network>) -> Self {
+        Self { networks }
+    }
+
+    /// Whether a client at `addr` is allowed.
+    pub fn allows(&self, addr: &IpAddr) -> bool {
+        self.networks.iter().any(|network| network.contains(addr))
+    }
+}
@@ -0,0 +1,464 @@
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::packet::{Class, DnsPacket, DnsRecord, EdnsOption, QueryType, Result, ResultCode};
+
+/// The TTL of the answers made up for blocked names.
+const BLOCKED_TTL: u32 = 60;
+/// How often the counters are reported.
+const REPORT_INTERVAL: Duration = Duration::from_secs(60);
+/// Queries are counted for this many different names at most, the total goes on regardless.
+const MAX_COUNTED_NAMES: usize = 10_000;
+/// Names in hosts files that are about the host itself rather than anything to block.
+const HOST_NAMES: [&str; 8] = [
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "ip6-localnet",
+    "0.0.0.0",
+];
+
+/// Which names an entry in a [`DomainSet`] covers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Coverage {
+    /// Just the name itself.
+    Exact,
+    /// Only the names below it, as for `*.example.com`.
+    Below,
+    /// The name and everything below it.
+    Subtree,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    children: HashMap<Box<str>, Node>,
+    exact: bool,
+    below: bool,
+}
+
+/// A set of names and whole subtrees of the namespace, kept as a tree of labels starting from
+/// the top level domain. Looking up a name takes a step per label, no matter how many entries
+/// there are.
+#[derive(Debug, Default)]
+pub struct DomainSet {
+    root: Node,
+    len: usize,
+}
+
+impl DomainSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, name: &str, coverage: Coverage) {
+        let mut node = &mut self.root;
+        for label in labels(name) {
+            node = node.children.entry(label.into()).or_default();
+        }
+
+        if !node.exact && !node.below {
+            self.len += 1;
+        }
+        match coverage {
+            Coverage::Exact => node.exact = true,
+            Coverage::Below => node.below = true,
+            Coverage::Subtree => {
+                node.exact = true;
+                node.below = true;
+            }
+        }
+    }
+
+    /// Whether `name` is one of the names in the set, or below one of its subtrees.
+    pub fn contains(&self, name: &str) -> bool {
+        let mut node = &self.root;
+        for label in labels(name) {
+            if node.below {
+                return true;
+            }
+            match node.children.get(label.as_str()) {
+                Some(child) => node = child,
+                None => return false,
+            }
+        }
+
+        node.exact
+    }
+
+    /// How many names there are entries for.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// The labels of `name` from the top level domain down, in lowercase.
+fn labels(name: &str) -> impl Iterator<Item = String> + '_ {
+    name.trim_end_matches('.')
+        .rsplit('.')
+        .filter(|label| !label.is_empty())
+        .map(str::to_lowercase)
+}
+
+/// What clients asking for a blocked name are told.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum BlockResponse {
+    /// That the name doesn't exist.
+    #[default]
+    NxDomain,
+    /// That the name is at `0.0.0.0` and `::`, which goes nowhere.
+    NullAddress,
+    /// Nothing, the query is refused.
+    Refused,
+    /// That the name is at these addresses, where a server tells users why they got there.
+    Sinkhole(Vec<IpAddr>),
+}
+
+impl FromStr for BlockResponse {
+    type Err = Box<dyn std::error::Error>;
+
+    /// Parse `nxdomain`, `null`, `refused` or `sinkhole:ADDR[,ADDR]`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "nxdomain" => Ok(BlockResponse::NxDomain),
+            "null" => Ok(BlockResponse::NullAddress),
+            "refused" => Ok(BlockResponse::Refused),
+            other => {
+                let addrs = other
+                    .strip_prefix("sinkhole:")
+                    .ok_or_else(|| format!("Unknown block response {}", s))?;
+                let addrs = addrs
+                    .split(',')
+                    .map(str::parse)
+                    .collect::<std::result::Result<Vec<IpAddr>, _>>()?;
+
+                Ok(BlockResponse::Sinkhole(addrs))
+            }
+        }
+    }
+}
+
+impl fmt::Display for BlockResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockResponse::NxDomain => write!(f, "nxdomain"),
+            BlockResponse::NullAddress => write!(f, "null"),
+            BlockResponse::Refused => write!(f, "refused"),
+            BlockResponse::Sinkhole(addrs) => {
+                let addrs: Vec<String> = addrs.iter().map(IpAddr::to_string).collect();
+                write!(f, "sinkhole:{}", addrs.join(","))
+            }
+        }
+    }
+}
+
+/// Names not to resolve for clients, typically those of ad networks and malware.
+///
+/// Rules come from lists in any of the usual formats, which can be mixed freely:
+///
+/// * hosts files, where every name on a line is blocked, whatever the address
+/// * plain lists with a name on every line, or `*.example.com` for just the names below one
+/// * adblock-style rules, where `||example.com^` blocks the name along with everything below it
+///   and `@@||example.com^` makes an exception
+///
+/// Comments start with `#` or `!`, and anything else adblock-style lists have to say about web
+/// pages is skipped. Names on the allowlist are never blocked, whatever the blocklists say.
+#[derive(Debug, Default)]
+pub struct Blocklist {
+    blocked: DomainSet,
+    allowed: DomainSet,
+    response: BlockResponse,
+    /// Queries answered with `response` so far.
+    total: AtomicU64,
+    /// Queries answered with `response` so far, by name.
+    counts: Mutex<HashMap<String, u64>>,
+}
+
+impl Blocklist {
+    pub fn new(response: BlockResponse) -> Self {
+        Self {
+            response,
+            ..Self::default()
+        }
+    }
+
+    /// Add the rules in the list at `path`.
+    pub fn load(&mut self, path: &Path) -> Result<()> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        self.add_rules(&text, false);
+
+        Ok(())
+    }
+
+    /// Add the names in the list at `path` to the allowlist. It may be in any of the formats
+    /// blocklists come in.
+    pub fn load_allowlist(&mut self, path: &Path) -> Result<()> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        self.add_rules(&text, true);
+
+        Ok(())
+    }
+
+    /// Add the rules in `text`, to the allowlist if `allow` is set.
+    pub fn add_rules(&mut self, text: &str, allow: bool) {
+        for (name, coverage, exception) in text.lines().flat_map(parse_rules) {
+            if allow || exception {
+                self.allowed.insert(&name, coverage);
+            } else {
+                self.blocked.insert(&name, coverage);
+            }
+        }
+    }
+
+    /// Whether queries for `name` are blocked.
+    pub fn is_blocked(&self, name: &str) -> bool {
+        self.blocked.contains(name) && !self.allowed.contains(name)
+    }
+
+    /// The response to a query for `qname` if it's blocked, which is counted.
+    pub fn check(&self, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
+        if !self.is_blocked(qname) {
+            return None;
+        }
+
+        self.count(qname);
+        Some(self.blocked_response(qname, qtype))
+    }
+
+    /// Like `check`, but for the names `response` to a query for `qname` is an alias for. Trackers
+    /// like to hide behind an alias in the domain of the site that uses them.
+    pub fn check_response(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        response: &DnsPacket,
+    ) -> Option<DnsPacket> {
+        let aliased = response.answers.iter().any(|record| match record {
+            DnsRecord::CNAME { host, .. } => self.is_blocked(host),
+            _ => false,
+        });
+        if !aliased {
+            return None;
+        }
+
+        self.count(qname);
+        Some(self.blocked_response(qname, qtype))
+    }
+
+    fn count(&self, qname: &str) {
+        self.total.fetch_add(1, Ordering::Relaxed);
+
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(qname) {
+            *count += 1;
+        } else if counts.len() < MAX_COUNTED_NAMES {
+            counts.insert(qname.to_string(), 1);
+        }
+    }
+
+    /// The response to a query for the blocked name `qname`, which carries an Extended DNS Error
+    /// saying so.
+    pub fn blocked_response(&self, qname: &str, qtype: QueryType) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+
+        let addrs: Vec<IpAddr> = match &self.response {
+            BlockResponse::NxDomain => {
+                packet.header.rescode = ResultCode::NXDOMAIN;
+                Vec::new()
+            }
+            BlockResponse::Refused => {
+                packet.header.rescode = ResultCode::REFUSED;
+                Vec::new()
+            }
+            BlockResponse::NullAddress => {
+                vec![Ipv4Addr::UNSPECIFIED.into(), Ipv6Addr::UNSPECIFIED.into()]
+            }
+            BlockResponse::Sinkhole(addrs) => addrs.clone(),
+        };
+
+        for addr in addrs {
+            let record = match (addr, qtype) {
+                (IpAddr::V4(addr), QueryType::A) => DnsRecord::A {
+                    domain: qname.to_string(),
+                    class: Class::IN,
+                    addr,
+                    ttl: BLOCKED_TTL,
+                },
+                (IpAddr::V6(addr), QueryType::AAAA) => DnsRecord::AAAA {
+                    domain: qname.to_string(),
+                    class: Class::IN,
+                    addr,
+                    ttl: BLOCKED_TTL,
+                },
+                _ => continue,
+            };
+            packet.answers.push(record);
+        }
+
+        // Blocked
+        packet.resources.push(DnsRecord::OPT {
+            packet_len: 512,
+            flags: 0,
+            options: vec![EdnsOption::extended_error(15, "Blocked by a blocklist")],
+        });
+
+        packet
+    }
+
+    /// How many queries have been blocked so far.
+    pub fn blocked_count(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    /// The `limit` names blocked most often so far, with how often that was.
+    pub fn top_blocked(&self, limit: usize) -> Vec<(String, u64)> {
+        let mut counts: Vec<(String, u64)> = self
+            .counts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, count)| (name.clone(), *count))
+            .collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        counts.truncate(limit);
+
+        counts
+    }
+
+    /// How many names there are rules for, blocking and allowing them.
+    pub fn rule_counts(&self) -> (usize, usize) {
+        (self.blocked.len(), self.allowed.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocked.is_empty()
+    }
+}
+
+/// A name a list has a rule for, which names the rule covers and whether it's an exception.
+type Rule = (String, Coverage, bool);
+
+/// The rules on a line of a list, none for comments and anything else there's nothing to take
+/// from.
+fn parse_rules(line: &str) -> Vec<Rule> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with(['#', '!', '[']) {
+        return Vec::new();
+    }
+
+    // Adblock-style, only rules for whole domains are of interest.
+    if let Some(rule) = line.strip_prefix("@@||") {
+        return adblock_domain(rule)
+            .map(|name| (name, Coverage::Subtree, true))
+            .into_iter()
+            .collect();
+    }
+    if let Some(rule) = line.strip_prefix("||") {
+        return adblock_domain(rule)
+            .map(|name| (name, Coverage::Subtree, false))
+            .into_iter()
+            .collect();
+    }
+
+    // Comments are set apart by whitespace, a `#` right after a name makes it an adblock rule
+    // for hiding parts of pages.
+    let line = match line.split_once('#') {
+        Some((rule, _)) if !rule.ends_with(char::is_whitespace) => return Vec::new(),
+        Some((rule, _)) => rule,
+        None => line,
+    };
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let names = match fields.as_slice() {
+        [_] => &fields[..1],
+        [addr, names @ ..] if addr.parse::<IpAddr>().is_ok() => names,
+        _ => return Vec::new(),
+    };
+
+    names
+        .iter()
+        .filter_map(|name| {
+            let name = name.trim_end_matches('.').to_lowercase();
+            if HOST_NAMES.contains(&name.as_str()) {
+                return None;
+            }
+
+            match name.strip_prefix("*.") {
+                Some(parent) if is_domain(parent) => {
+                    Some((parent.to_string(), Coverage::Below, false))
+                }
+                Some(_) => None,
+                None if is_domain(&name) => Some((name, Coverage::Exact, false)),
+                None => None,
+            }
+        })
+        .collect()
+}
+
+/// The domain of an adblock rule that starts right after `||`, if the rule is for the whole of
+/// it. Rules with options or paths are about web pages and skipped.
+fn adblock_domain(rule: &str) -> Option<String> {
+    let name = rule.strip_suffix('^').or_else(|| rule.strip_suffix("^|"))?;
+    let name = name.trim_end_matches('.').to_lowercase();
+
+    is_domain(&name).then_some(name)
+}
+
+fn is_domain(name: &str) -> bool {
+    !name.is_empty()
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+}
+
+/// Report how many queries `blocklist` blocked, and for which names, every so often for as long
+/// as there's something new to tell.
+pub fn report(blocklist: &Blocklist) {
+    if blocklist.is_empty() {
+        return;
+    }
+
+    let mut reported = 0;
+    loop {
+        thread::sleep(REPORT_INTERVAL);
+
+        let total = blocklist.blocked_count();
+        if total == reported {
+            continue;
+        }
+        reported = total;
+
+        let top: Vec<String> = blocklist
+            .top_blocked(5)
+            .iter()
+            .map(|(name, count)| format!("{} ({})", name, count))
+            .collect();
+        println!(
+            "Blocked {} queries so far, most often for {}",
+            total,
+            top.join(", ")
+        );
+    }
+}
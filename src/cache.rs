@@ -0,0 +1,162 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::packet::{DnsPacket, DnsRecord, QueryType, ResultCode};
+
+/// Bounds on what the cache keeps, and for how long.
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    /// How many responses are kept at most, zero turns the cache off.
+    pub max_entries: usize,
+    /// Records are dropped after this long, whatever their TTL says.
+    pub max_ttl: Duration,
+    /// Like `max_ttl`, but for negative answers, see
+    /// [RFC2308](https://datatracker.ietf.org/doc/html/rfc2308#section-5).
+    pub max_negative_ttl: Duration,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: 10_000,
+            max_ttl: Duration::from_secs(86_400),
+            max_negative_ttl: Duration::from_secs(3 * 3600),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    response: DnsPacket,
+    stored: Instant,
+    expires: Instant,
+}
+
+/// Responses from upstream, kept for as long as the records in them may be.
+///
+/// Negative answers are kept as well, for as long as the `SOA` record that came with them says,
+/// which is the lower of its own TTL and its `minimum` field. Those that came without one aren't
+/// kept at all, and neither are failures.
+#[derive(Debug)]
+pub struct Cache {
+    config: CacheConfig,
+    entries: Mutex<HashMap<(String, QueryType), Entry>>,
+}
+
+impl Cache {
+    pub fn new(config: CacheConfig) -> Self {
+        Self {
+            config,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The response for `qname` and `qtype`, if we have one that hasn't expired yet. The TTLs of
+    /// the records in it are counted down by the time it spent in the cache.
+    pub fn get(&self, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
+        let mut entries = self.entries.lock().unwrap();
+        let key = (qname.to_string(), qtype);
+        let now = Instant::now();
+
+        let entry = entries.get(&key)?;
+        if entry.expires <= now {
+            entries.remove(&key);
+            return None;
+        }
+
+        let elapsed = now.duration_since(entry.stored).as_secs() as u32;
+        let mut response = entry.response.clone();
+        for record in response
+            .answers
+            .iter_mut()
+            .chain(response.authorities.iter_mut())
+            .chain(response.resources.iter_mut())
+        {
+            record.set_ttl(record.ttl().saturating_sub(elapsed));
+        }
+
+        Some(response)
+    }
+
+    /// Keep `response` to a query for `qname` and `qtype`, if it's worth keeping.
+    pub fn insert(&self, qname: &str, qtype: QueryType, response: &DnsPacket) {
+        if self.config.max_entries == 0 {
+            return;
+        }
+
+        let ttl = match lifetime(response) {
+            Some((ttl, negative)) => {
+                let max = if negative {
+                    self.config.max_negative_ttl
+                } else {
+                    self.config.max_ttl
+                };
+                Duration::from_secs(ttl.into()).min(max)
+            }
+            None => return,
+        };
+        if ttl.is_zero() {
+            return;
+        }
+
+        // Whatever was sent along for the query that went upstream means nothing to those asking
+        // later.
+        let mut response = response.clone();
+        response
+            .resources
+            .retain(|record| !matches!(record, DnsRecord::OPT { .. }));
+
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        if entries.len() >= self.config.max_entries {
+            entries.retain(|_, entry| entry.expires > now);
+        }
+        // Still full, so make room by dropping whatever would have expired first.
+        while entries.len() >= self.config.max_entries {
+            let soonest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires)
+                .map(|(key, _)| key.clone());
+            match soonest {
+                Some(key) => entries.remove(&key),
+                None => break,
+            };
+        }
+
+        entries.insert(
+            (qname.to_string(), qtype),
+            Entry {
+                response,
+                stored: now,
+                expires: now + ttl,
+            },
+        );
+    }
+}
+
+/// How many seconds `response` may be kept, and whether it's a negative answer. `None` if it
+/// mustn't be kept at all.
+fn lifetime(response: &DnsPacket) -> Option<(u32, bool)> {
+    let negative = match response.header.rescode {
+        ResultCode::NOERROR => response.answers.is_empty(),
+        ResultCode::NXDOMAIN => true,
+        _ => return None,
+    };
+
+    if !negative {
+        return response
+            .answers
+            .iter()
+            .map(DnsRecord::ttl)
+            .min()
+            .map(|ttl| (ttl, false));
+    }
+
+    response.authorities.iter().find_map(|record| match record {
+        DnsRecord::SOA { ttl, minimum, .. } => Some(((*ttl).min(*minimum), true)),
+        _ => None,
+    })
+}
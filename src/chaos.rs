@@ -0,0 +1,61 @@
+use std::fs;
+
+use crate::packet::{Class, DnsPacket, DnsQuestion, DnsRecord, QueryType, ResultCode};
+
+/// Answers queries in the `CH` class about the server itself, by the conventions that started
+/// with BIND and are described for `id.server` in
+/// [RFC4892](https://datatracker.ietf.org/doc/html/rfc4892).
+#[derive(Debug, Clone)]
+pub struct Chaos {
+    /// Reported for `version.bind`.
+    version: String,
+    /// Reported for `id.server` and `hostname.bind`, telling apart the instances of a service.
+    identity: String,
+}
+
+impl Chaos {
+    /// Report `identity` as the name of this instance, or the host name if there's none.
+    pub fn new(identity: Option<String>) -> Self {
+        Self {
+            version: format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+            identity: identity.unwrap_or_else(hostname),
+        }
+    }
+
+    /// The answer to `question`, which has to be in the `CH` class. Names other than the ones we
+    /// know of are refused.
+    pub fn answer(&self, question: &DnsQuestion) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header.authoritative_answer = true;
+
+        let text = match question.name.to_lowercase().as_str() {
+            "version.bind" | "version.server" => &self.version,
+            "id.server" | "hostname.bind" => &self.identity,
+            _ => {
+                packet.header.rescode = ResultCode::REFUSED;
+                return packet;
+            }
+        };
+
+        if question.qtype == QueryType::TXT || question.qtype == QueryType::ANY {
+            packet.answers.push(DnsRecord::TXT {
+                domain: question.name.clone(),
+                class: Class::CH,
+                data: vec![text.clone()],
+                ttl: 0,
+            });
+        }
+
+        packet
+    }
+}
+
+/// The name of the host we run on, as far as the system lets us know.
+fn hostname() -> String {
+    ["/proc/sys/kernel/hostname", "/etc/hostname"]
+        .iter()
+        .filter_map(|path| fs::read_to_string(path).ok())
+        .map(|name| name.trim().to_string())
+        .find(|name| !name.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
@@ -0,0 +1,106 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{Arc, Condvar, Mutex},
+};
+
+/// Where the computation for a key stands, as seen by those waiting for it.
+enum State<V> {
+    Pending,
+    Done(V),
+    /// The computation panicked, and everyone waiting has to try for themselves.
+    Abandoned,
+}
+
+struct Slot<V> {
+    state: Mutex<State<V>>,
+    ready: Condvar,
+}
+
+/// Lets concurrent callers asking for the same thing share the work: the first caller for a key
+/// computes the value, and anyone asking for the same key in the meantime waits for it and gets
+/// a copy.
+///
+/// Values aren't kept around once computed, callers that come later do the work again.
+pub struct Coalescer<K, V> {
+    pending: Mutex<HashMap<K, Arc<Slot<V>>>>,
+}
+
+impl<K, V> Default for Coalescer<K, V> {
+    fn default() -> Self {
+        Self {
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> Coalescer<K, V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The value for `key`, computed with `compute` unless another caller is doing so already.
+    pub fn run(&self, key: K, compute: impl FnOnce() -> V) -> V {
+        let (slot, leader) = {
+            let mut pending = self.pending.lock().unwrap();
+            match pending.get(&key) {
+                Some(slot) => (Arc::clone(slot), false),
+                None => {
+                    let slot = Arc::new(Slot {
+                        state: Mutex::new(State::Pending),
+                        ready: Condvar::new(),
+                    });
+                    pending.insert(key.clone(), Arc::clone(&slot));
+                    (slot, true)
+                }
+            }
+        };
+
+        if !leader {
+            let mut state = slot.state.lock().unwrap();
+            loop {
+                match &*state {
+                    State::Pending => state = slot.ready.wait(state).unwrap(),
+                    State::Done(value) => return value.clone(),
+                    State::Abandoned => {
+                        drop(state);
+                        return compute();
+                    }
+                }
+            }
+        }
+
+        let mut finish = Finish {
+            coalescer: self,
+            key: Some(key),
+            slot: &slot,
+        };
+        let value = compute();
+        finish.complete(State::Done(value.clone()));
+
+        value
+    }
+}
+
+/// Hands the outcome of a computation to those waiting for it, also when it panics.
+struct Finish<'a, K: Eq + Hash, V> {
+    coalescer: &'a Coalescer<K, V>,
+    key: Option<K>,
+    slot: &'a Slot<V>,
+}
+
+impl<K: Eq + Hash, V> Finish<'_, K, V> {
+    fn complete(&mut self, outcome: State<V>) {
+        if let Some(key) = self.key.take() {
+            self.coalescer.pending.lock().unwrap().remove(&key);
+            *self.slot.state.lock().unwrap() = outcome;
+            self.slot.ready.notify_all();
+        }
+    }
+}
+
+impl<K: Eq + Hash, V> Drop for Finish<'_, K, V> {
+    fn drop(&mut self) {
+        self.complete(State::Abandoned);
+    }
+}
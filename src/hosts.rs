@@ -0,0 +1,103 @@
+use std::{collections::HashMap, fs, net::IpAddr, path::Path};
+
+use crate::packet::Result;
+
+/// A table of names and their addresses in the format of `/etc/hosts`: an address on every line,
+/// followed by the names it belongs to. Anything after a `#` is a comment.
+///
+/// Like the C library, lines that don't make sense are skipped rather than failing the whole
+/// file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Hosts {
+    /// Addresses by lowercase name, in the order they appear in the file.
+    names: HashMap<String, Vec<IpAddr>>,
+    /// The name every address maps back to, which is the first one given for it.
+    canonical: HashMap<IpAddr, String>,
+}
+
+impl Hosts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        Ok(Self::parse(&fs::read_to_string(path)?))
+    }
+
+    pub fn parse(text: &str) -> Self {
+        let mut hosts = Self::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let Some(addr) = fields.next() else {
+                continue;
+            };
+
+            let addr = match addr.parse::<IpAddr>() {
+                Ok(addr) => addr,
+                Err(_) => {
+                    eprintln!(
+                        "Skipping line {} of hosts file: bad address {}",
+                        number + 1,
+                        addr
+                    );
+                    continue;
+                }
+            };
+
+            for name in fields {
+                hosts.insert(name, addr);
+            }
+        }
+
+        hosts
+    }
+
+    /// Add `addr` to the addresses of `name`, unless it's there already.
+    pub fn insert(&mut self, name: &str, addr: IpAddr) {
+        let name = normalize(name);
+        self.canonical.entry(addr).or_insert_with(|| name.clone());
+
+        let addrs = self.names.entry(name).or_default();
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+
+    /// The addresses of `name`, which is matched regardless of case.
+    pub fn addresses(&self, name: &str) -> &[IpAddr] {
+        self.names
+            .get(&normalize(name))
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// The name `addr` maps back to, if it's in the table.
+    pub fn name_of(&self, addr: &IpAddr) -> Option<&str> {
+        self.canonical.get(addr).map(String::as_str)
+    }
+
+    /// Every name in the table along with its addresses, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[IpAddr])> {
+        self.names
+            .iter()
+            .map(|(name, addrs)| (name.as_str(), addrs.as_slice()))
+    }
+
+    /// Every address in the table along with the name it maps back to, in no particular order.
+    pub fn reverse(&self) -> impl Iterator<Item = (&IpAddr, &str)> {
+        self.canonical
+            .iter()
+            .map(|(addr, name)| (addr, name.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+}
+
+/// Names are kept in lowercase and without the trailing dot, the way queries are parsed.
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase()
+}
@@ -1 +1,23 @@
+pub mod acl;
+pub mod blocklist;
+pub mod cache;
+pub mod chaos;
+pub mod coalesce;
+pub mod hosts;
+pub mod local;
+pub mod notify;
 pub mod packet;
+pub mod pool;
+pub mod primary;
+pub mod random;
+pub mod resolver;
+pub mod rpz;
+pub mod rtt;
+pub mod secondary;
+pub mod stub;
+pub mod tcp;
+pub mod transfer;
+pub mod update;
+pub mod upstream;
+pub mod zone;
+pub mod zonefile;
@@ -0,0 +1,225 @@
+use std::{
+    collections::HashMap,
+    fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::RwLock,
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    hosts::Hosts,
+    packet::{Class, DnsPacket, DnsRecord, QueryType, Result},
+    zonefile,
+};
+
+/// How often the hosts file is checked for changes.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// The TTL of records made from the hosts file, and of records given without one. Nobody gets to
+/// cache them, so that changes are seen right away.
+const LOCAL_TTL: u32 = 0;
+
+/// Records answered straight from memory, ahead of our zones and recursion, for overriding a
+/// handful of names without setting up a zone for them.
+///
+/// They're made up of the entries of a hosts file and records given one by one. Every address
+/// gets a `PTR` record pointing back to its name, unless there's one for it already.
+#[derive(Debug, Clone, Default)]
+pub struct LocalRecords {
+    records: HashMap<String, Vec<DnsRecord>>,
+}
+
+impl LocalRecords {
+    pub fn new(hosts: &Hosts, statics: &[DnsRecord]) -> Self {
+        let mut local = Self::default();
+
+        for record in statics {
+            local.insert(record.clone());
+        }
+
+        for (name, addrs) in hosts.iter() {
+            for addr in addrs {
+                local.insert(address_record(name, *addr));
+            }
+        }
+
+        // Reverse records are only made up once all the given ones are in.
+        let mut reverse: Vec<(IpAddr, String)> = hosts
+            .reverse()
+            .map(|(addr, name)| (*addr, name.to_string()))
+            .collect();
+        for record in statics {
+            match record {
+                DnsRecord::A { domain, addr, .. } => reverse.push(((*addr).into(), domain.clone())),
+                DnsRecord::AAAA { domain, addr, .. } => {
+                    reverse.push(((*addr).into(), domain.clone()))
+                }
+                _ => {}
+            }
+        }
+        for (addr, name) in reverse {
+            let domain = reverse_name(&addr);
+            if !local.records.contains_key(&domain) {
+                local.insert(DnsRecord::PTR {
+                    domain,
+                    class: Class::IN,
+                    host: name,
+                    ttl: LOCAL_TTL,
+                });
+            }
+        }
+
+        local
+    }
+
+    fn insert(&mut self, record: DnsRecord) {
+        let records = self.records.entry(record.domain().to_string()).or_default();
+        if !records.iter().any(|other| other.same_data(&record)) {
+            records.push(record);
+        }
+    }
+
+    /// The authoritative answer for `qname`, if it's one of ours. Types other than the ones we
+    /// have for it get an empty answer, other than an alias which is handed back for the client
+    /// to follow.
+    pub fn answer(&self, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
+        let records = self.records.get(&qname.to_lowercase())?;
+
+        let mut packet = DnsPacket::new();
+        packet.header.authoritative_answer = true;
+        packet.answers = records
+            .iter()
+            .filter(|record| {
+                record.qtype() == qtype
+                    || record.qtype() == QueryType::CNAME
+                    || qtype == QueryType::ANY
+            })
+            .cloned()
+            .collect();
+
+        Some(packet)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+}
+
+fn address_record(name: &str, addr: IpAddr) -> DnsRecord {
+    match addr {
+        IpAddr::V4(addr) => DnsRecord::A {
+            domain: name.to_string(),
+            class: Class::IN,
+            addr,
+            ttl: LOCAL_TTL,
+        },
+        IpAddr::V6(addr) => DnsRecord::AAAA {
+            domain: name.to_string(),
+            class: Class::IN,
+            addr,
+            ttl: LOCAL_TTL,
+        },
+    }
+}
+
+/// The name under `in-addr.arpa` or `ip6.arpa` that the `PTR` record for `addr` is found at.
+pub fn reverse_name(addr: &IpAddr) -> String {
+    match addr {
+        IpAddr::V4(addr) => {
+            let [a, b, c, d] = addr.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", d, c, b, a)
+        }
+        IpAddr::V6(addr) => {
+            let mut name = String::new();
+            for byte in addr.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", byte & 0xF, byte >> 4));
+            }
+            name.push_str("ip6.arpa");
+            name
+        }
+    }
+}
+
+/// Parse a single record given as a master file entry, like `api.example.com A 192.0.2.10`.
+/// Names are taken as absolute, and the TTL may be left out.
+pub fn parse_record(text: &str) -> Result<DnsRecord> {
+    let input = format!("$TTL {}\n{}", LOCAL_TTL, text);
+    let mut records = zonefile::parse_str(&input, "", Path::new("."))?;
+    if records.len() != 1 {
+        return Err(format!("Expected a single record, got {}: {}", records.len(), text).into());
+    }
+
+    Ok(records.remove(0))
+}
+
+/// Where local records come from: a hosts file that's reloaded when it changes, and records
+/// given one by one.
+#[derive(Debug)]
+pub struct LocalSource {
+    pub hosts_file: Option<PathBuf>,
+    pub statics: Vec<DnsRecord>,
+    /// When the hosts file was last modified as of the version we loaded.
+    modified: Option<SystemTime>,
+}
+
+impl LocalSource {
+    pub fn new(hosts_file: Option<PathBuf>, statics: Vec<DnsRecord>) -> Self {
+        Self {
+            hosts_file,
+            statics,
+            modified: None,
+        }
+    }
+
+    /// Read the hosts file, if there is one, and make records of it and the others.
+    pub fn load(&mut self) -> Result<LocalRecords> {
+        let hosts = match &self.hosts_file {
+            Some(path) => {
+                self.modified = modified(path);
+                Hosts::load(path)
+                    .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?
+            }
+            None => Hosts::new(),
+        };
+
+        Ok(LocalRecords::new(&hosts, &self.statics))
+    }
+
+    /// Reload the hosts file into `local` if it changed since we last loaded it.
+    ///
+    /// A file that fails to load leaves the records we have in place.
+    pub fn reload_if_changed(&mut self, local: &RwLock<LocalRecords>) {
+        let Some(path) = self.hosts_file.clone() else {
+            return;
+        };
+        if modified(&path) == self.modified {
+            return;
+        }
+
+        match self.load() {
+            Ok(records) => {
+                println!("Reloaded local records from {}", path.display());
+                *local.write().unwrap() = records;
+            }
+            Err(e) => eprintln!("Unable to reload local records: {}", e),
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+/// Watch the hosts file of `source` for changes, forever.
+pub fn run(mut source: LocalSource, local: &RwLock<LocalRecords>) {
+    if source.hosts_file.is_none() {
+        return;
+    }
+
+    loop {
+        thread::sleep(CHECK_INTERVAL);
+
+        source.reload_if_changed(local);
+    }
+}
@@ -1,181 +1,868 @@
 use std::{
-    net::{Ipv4Addr, UdpSocket},
-    u16,
+    collections::HashMap,
+    env,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    path::PathBuf,
+    sync::{
+        mpsc::{self, Sender},
+        Mutex, RwLock,
+    },
+    thread,
+    time::Duration,
 };
 
-use dns_clone::packet::{BytePacketBuffer, DnsPacket, DnsQuestion, QueryType, Result, ResultCode};
+use dns_clone::{
+    acl::{Acl, Network},
+    blocklist::{self, BlockResponse, Blocklist},
+    chaos::Chaos,
+    local::{self, LocalRecords, LocalSource},
+    notify::{self, Notifier},
+    packet::{
+        BytePacketBuffer, Class, DnsPacket, DnsQuestion, DnsRecord, EdnsOption, Opcode, QueryType,
+        Result, ResultCode, UpdatePacket,
+    },
+    pool::WorkerPool,
+    primary::{self, Primary},
+    resolver::{LookupError, QnameMinimisation, Resolver, ResolverConfig},
+    rpz::{self, Hit, Policies},
+    secondary::{self, Secondary},
+    tcp, transfer, update,
+    upstream::{AddressFamily, UpstreamConfig},
+    zone::ZoneStore,
+};
 
-fn main() -> Result<()> {
-    let socket = UdpSocket::bind(("0.0.0.0", 2053))?;
+/// How long a TCP client may leave us waiting for its next message.
+const TCP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Command line options.
+#[derive(Debug, Default)]
+struct Args {
+    /// Zones to serve authoritatively, as (origin, master file) pairs.
+    zones: Vec<(String, PathBuf)>,
+    /// Networks allowed to transfer our zones.
+    allow_transfer: Vec<Network>,
+    /// Zones to copy from a primary server, as (origin, primary) pairs.
+    secondaries: Vec<(String, SocketAddr)>,
+    /// Where copies of secondary zones are kept.
+    secondary_dir: PathBuf,
+    /// Secondaries to notify of changes to our zones, as (origin, secondary)
+    /// pairs.
+    notify: Vec<(String, SocketAddr)>,
+    /// Networks allowed to send dynamic updates to our zones. Updated zones
+    /// are saved to the master file's path with `.dyn` appended.
+    allow_update: Vec<Network>,
+    /// The name to report for `id.server` queries, instead of the host name.
+    identity: Option<String>,
+    /// Whether to answer queries with more than one question.
+    multi_question: bool,
+    /// The port to listen on.
+    port: u16,
+    /// How many threads answer queries, and how many serve TCP connections.
+    workers: usize,
+    /// How many UDP queries may be waiting for an answer at a time.
+    max_in_flight: usize,
+    /// Servers to forward queries to, instead of resolving them ourselves.
+    forwarders: Vec<SocketAddr>,
+    /// Which addresses of upstream servers to use.
+    family: AddressFamily,
+    /// Whether to randomize the case of names sent upstream, as a defence against spoofing.
+    randomize_case: bool,
+    /// How much of the names we resolve is revealed to the servers along the way.
+    qname_minimisation: QnameMinimisation,
+    /// A file in the format of `/etc/hosts` with names to answer for.
+    hosts_file: Option<PathBuf>,
+    /// Further records to answer with, ahead of everything else.
+    local_records: Vec<DnsRecord>,
+    /// Lists of names not to resolve.
+    blocklists: Vec<PathBuf>,
+    /// Lists of names to resolve, whatever the blocklists say.
+    allowlists: Vec<PathBuf>,
+    /// What clients asking for a blocked name are told.
+    block_response: BlockResponse,
+    /// Zones of ours to apply as Response Policy Zones, in order.
+    policy_zones: Vec<String>,
+}
 
-    // For now, queries are handled sequentially, so an infinite loop for
-    // serving request is initiated.
-    loop {
-        if let Err(e) = handle_query(&socket) {
-            eprintln!("An error occurred: {}", e);
+fn parse_args() -> Result<Args> {
+    let mut args = Args {
+        secondary_dir: PathBuf::from("."),
+        port: 2053,
+        workers: 8,
+        max_in_flight: 256,
+        ..Args::default()
+    };
+
+    let mut iter = env::args().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--zone" => {
+                let value = iter.next().ok_or("--zone needs an ORIGIN=FILE argument")?;
+                let (origin, path) = value
+                    .split_once('=')
+                    .ok_or("--zone needs an ORIGIN=FILE argument")?;
+                args.zones.push((origin.to_string(), PathBuf::from(path)));
+            }
+            "--allow-transfer" => {
+                let value = iter
+                    .next()
+                    .ok_or("--allow-transfer needs a NETWORK argument")?;
+                args.allow_transfer.push(value.parse()?);
+            }
+            "--allow-update" => {
+                let value = iter
+                    .next()
+                    .ok_or("--allow-update needs a NETWORK argument")?;
+                args.allow_update.push(value.parse()?);
+            }
+            "--secondary" => {
+                let value = iter
+                    .next()
+                    .ok_or("--secondary needs an ORIGIN=PRIMARY argument")?;
+                let (origin, primary) = value
+                    .split_once('=')
+                    .ok_or("--secondary needs an ORIGIN=PRIMARY argument")?;
+                args.secondaries
+                    .push((origin.to_string(), parse_server_addr(primary)?));
+            }
+            "--notify" => {
+                let value = iter
+                    .next()
+                    .ok_or("--notify needs an ORIGIN=SECONDARY argument")?;
+                let (origin, secondary) = value
+                    .split_once('=')
+                    .ok_or("--notify needs an ORIGIN=SECONDARY argument")?;
+                args.notify
+                    .push((origin.to_string(), parse_server_addr(secondary)?));
+            }
+            "--identity" => {
+                let value = iter.next().ok_or("--identity needs a NAME argument")?;
+                args.identity = Some(value);
+            }
+            "--multi-question" => args.multi_question = true,
+            "--port" => {
+                let value = iter.next().ok_or("--port needs a PORT argument")?;
+                args.port = value.parse()?;
+            }
+            "--workers" => {
+                let value = iter.next().ok_or("--workers needs a COUNT argument")?;
+                args.workers = value.parse()?;
+            }
+            "--max-in-flight" => {
+                let value = iter
+                    .next()
+                    .ok_or("--max-in-flight needs a COUNT argument")?;
+                args.max_in_flight = value.parse()?;
+            }
+            "--forward" => {
+                let value = iter.next().ok_or("--forward needs a SERVER argument")?;
+                args.forwarders.push(parse_server_addr(&value)?);
+            }
+            "--family" => {
+                let value = iter.next().ok_or("--family needs a FAMILY argument")?;
+                args.family = value.parse()?;
+            }
+            "--randomize-case" => args.randomize_case = true,
+            "--qname-minimisation" => {
+                let value = iter
+                    .next()
+                    .ok_or("--qname-minimisation needs a MODE argument")?;
+                args.qname_minimisation = value.parse()?;
+            }
+            "--hosts" => {
+                let value = iter.next().ok_or("--hosts needs a FILE argument")?;
+                args.hosts_file = Some(PathBuf::from(value));
+            }
+            "--local-record" => {
+                let value = iter
+                    .next()
+                    .ok_or("--local-record needs a RECORD argument")?;
+                args.local_records.push(local::parse_record(&value)?);
+            }
+            "--blocklist" => {
+                let value = iter.next().ok_or("--blocklist needs a FILE argument")?;
+                args.blocklists.push(PathBuf::from(value));
+            }
+            "--allowlist" => {
+                let value = iter.next().ok_or("--allowlist needs a FILE argument")?;
+                args.allowlists.push(PathBuf::from(value));
+            }
+            "--block-response" => {
+                let value = iter
+                    .next()
+                    .ok_or("--block-response needs a MODE argument")?;
+                args.block_response = value.parse()?;
+            }
+            "--rpz" => {
+                let value = iter.next().ok_or("--rpz needs an ORIGIN argument")?;
+                args.policy_zones.push(value);
+            }
+            "--secondary-dir" => {
+                let value = iter.next().ok_or("--secondary-dir needs a DIR argument")?;
+                args.secondary_dir = PathBuf::from(value);
+            }
+            other => return Err(format!("Unknown argument {}", other).into()),
         }
     }
+
+    Ok(args)
 }
 
-fn lookup(qname: &str, qtype: QueryType, server: (Ipv4Addr, u16)) -> Result<DnsPacket> {
-    // forward requests to googles public DNS server
-    let socket = UdpSocket::bind(server)?;
+/// Parse the address of a name server, with the port being optional.
+fn parse_server_addr(addr: &str) -> Result<SocketAddr> {
+    match addr.parse::<SocketAddr>() {
+        Ok(addr) => Ok(addr),
+        Err(_) => Ok(SocketAddr::new(addr.parse()?, 53)),
+    }
+}
 
-    // Build query packet. We have to remember to set the `recursion_desired`
-    // flag. The packet id will be arbitrary.
-    let mut packet = DnsPacket::new();
+/// Everything needed to answer queries.
+struct Server {
+    resolver: Resolver,
+    /// Records that override whatever our zones or the rest of the world
+    /// say.
+    local: RwLock<LocalRecords>,
+    /// Names not to resolve for clients.
+    blocklist: Blocklist,
+    /// Rewrite the answers we resolve for clients.
+    policies: Policies,
+    zones: RwLock<ZoneStore>,
+    /// Who may transfer zones from us.
+    transfer_acl: Acl,
+    /// Who may send dynamic updates to our zones.
+    update_acl: Acl,
+    /// Where the zones we're the primary for are saved after dynamic updates,
+    /// by origin.
+    updates_files: HashMap<String, PathBuf>,
+    /// Held while a dynamic update is applied and saved, so that concurrent
+    /// updates can't undo each other.
+    updates: Mutex<()>,
+    /// The primaries of our secondary zones, by origin.
+    primaries: HashMap<String, SocketAddr>,
+    /// Where to pass on the origins of zones a primary notified us about.
+    notifications: Sender<String>,
+    notifier: Notifier,
+    /// Answers queries about ourselves.
+    chaos: Chaos,
+    /// Whether queries with more than one question are answered, rather than
+    /// refused with `FORMERR`.
+    multi_question: bool,
+}
 
-    packet.header.id = 6666;
-    packet.header.questions = 1;
-    packet.header.recursion_desired = true;
-    packet
-        .questions
-        .push(DnsQuestion::new(qname.to_string(), qtype));
+fn main() -> Result<()> {
+    let args = parse_args()?;
 
-    // use the `write` method to write the packet to a buffer.
-    let mut req_buffer = BytePacketBuffer::new();
-    packet.write(&mut req_buffer)?;
+    let mut local_source = LocalSource::new(args.hosts_file, args.local_records);
+    let local = local_source.load()?;
+    if let Some(path) = &local_source.hosts_file {
+        println!("Loaded local records from {}", path.display());
+    }
 
-    // send the packet to the server using our udp socket
-    socket.send_to(&req_buffer.buf[0..req_buffer.pos], server)?;
+    let mut blocklist = Blocklist::new(args.block_response);
+    for path in &args.blocklists {
+        blocklist.load(path)?;
+    }
+    for path in &args.allowlists {
+        blocklist.load_allowlist(path)?;
+    }
+    if !blocklist.is_empty() {
+        let (blocked, allowed) = blocklist.rule_counts();
+        println!(
+            "Loaded rules blocking {} names and allowing {}",
+            blocked, allowed
+        );
+    }
 
-    // create a new `BytePacketBuffer` for receiving the response and ask the
-    // socket to write the response directly to the buffer
-    let mut res_buffer = BytePacketBuffer::new();
-    socket.recv_from(&mut res_buffer.buf)?;
+    // Policies are made of zones we load or transfer like any other.
+    for origin in &args.policy_zones {
+        let same = |other: &String| {
+            other
+                .trim_end_matches('.')
+                .eq_ignore_ascii_case(origin.trim_end_matches('.'))
+        };
+        if !args.zones.iter().any(|(other, _)| same(other))
+            && !args.secondaries.iter().any(|(other, _)| same(other))
+        {
+            return Err(format!("Policy zone {} isn't one of our zones", origin).into());
+        }
+    }
+
+    let zones = RwLock::new(ZoneStore::new());
+    let mut primaries = Vec::new();
+    for (origin, path) in &args.zones {
+        let primary = Primary::load(origin, path.clone(), &zones)?;
+        println!("Loaded zone {} from {}", primary.origin, path.display());
+        primaries.push(primary);
+    }
 
-    // `DnsPacket::from_buffer` is used to parse the packet after which we can
-    // print the response
-    DnsPacket::from_buffer(&mut res_buffer)
+    let mut notifier = Notifier::new();
+    for (origin, secondary) in &args.notify {
+        notifier.add(origin, *secondary);
+    }
+
+    // Secondary zones start out with whatever copy we kept from the last run.
+    let mut secondaries = Vec::new();
+    for (origin, primary) in &args.secondaries {
+        let name = if origin.trim_end_matches('.').is_empty() {
+            "root"
+        } else {
+            origin.trim_end_matches('.')
+        };
+        let file = args.secondary_dir.join(format!("{}.zone", name));
+
+        let mut secondary = Secondary::new(origin, *primary, file);
+        if let Err(e) = secondary.load(&zones) {
+            eprintln!("Unable to load copy of zone {}: {}", secondary.origin, e);
+        }
+        secondaries.push(secondary);
+    }
+
+    // Listening on the IPv6 wildcard address accepts IPv4 clients as well on
+    // dual-stack hosts. Hosts without IPv6 get an IPv4 socket instead.
+    let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, args.port)).or_else(|e| {
+        eprintln!("Unable to listen on IPv6 ({}), falling back to IPv4", e);
+        UdpSocket::bind((Ipv4Addr::UNSPECIFIED, args.port))
+    })?;
+    let listener = TcpListener::bind((Ipv6Addr::UNSPECIFIED, args.port))
+        .or_else(|_| TcpListener::bind((Ipv4Addr::UNSPECIFIED, args.port)))?;
+    let (notifications, notified) = mpsc::channel();
+    let server = Server {
+        resolver: Resolver::new(ResolverConfig {
+            forwarders: args.forwarders,
+            qname_minimisation: args.qname_minimisation,
+            upstream: UpstreamConfig {
+                family: args.family,
+                randomize_case: args.randomize_case,
+                ..UpstreamConfig::default()
+            },
+            ..ResolverConfig::default()
+        }),
+        local: RwLock::new(local),
+        blocklist,
+        policies: Policies::new(args.policy_zones),
+        zones,
+        transfer_acl: Acl::new(args.allow_transfer),
+        update_acl: Acl::new(args.allow_update),
+        updates_files: primaries
+            .iter()
+            .map(|primary| (primary.origin.clone(), primary::updates_file(&primary.file)))
+            .collect(),
+        updates: Mutex::new(()),
+        primaries: secondaries
+            .iter()
+            .map(|secondary| (secondary.origin.clone(), secondary.primary))
+            .collect(),
+        notifications,
+        notifier,
+        chaos: Chaos::new(args.identity),
+        multi_question: args.multi_question,
+    };
+
+    thread::scope(|s| {
+        s.spawn(|| primary::run(primaries, &server.zones, &server.notifier));
+        s.spawn(|| secondary::run(secondaries, &server.zones, &server.notifier, notified));
+        s.spawn(|| local::run(local_source, &server.local));
+        s.spawn(|| blocklist::report(&server.blocklist));
+
+        // TCP connections are served on workers of their own, so that long
+        // zone transfers don't hold up UDP queries. Each one keeps its worker
+        // busy until the client hangs up, clients beyond that are turned away.
+        let connections = WorkerPool::new(s, args.workers, args.workers);
+        let server = &server;
+        s.spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        eprintln!("An error occurred: {}", e);
+                        continue;
+                    }
+                };
+
+                let accepted = connections.try_execute(move || {
+                    if let Err(e) = handle_connection(stream, server) {
+                        eprintln!("An error occurred: {}", e);
+                    }
+                });
+                if !accepted {
+                    eprintln!("Too many TCP connections, closing a new one");
+                }
+            }
+        });
+
+        // Queries are answered concurrently, so that a slow upstream doesn't
+        // hold up anyone but the clients waiting for it. Under more load than
+        // we're allowed to take on, queries are dropped and left for clients
+        // to retry.
+        let queries = WorkerPool::new(s, args.workers, args.max_in_flight);
+        let socket = &socket;
+        loop {
+            // With a socket ready, we can read a packet. This will block until
+            // one is received. We need to keep track of the source in order to
+            // send our reply later on.
+            let mut req_buffer = BytePacketBuffer::new();
+            let src = match socket.recv_from(&mut req_buffer.buf) {
+                Ok((_, src)) => src,
+                Err(e) => {
+                    eprintln!("An error occurred: {}", e);
+                    continue;
+                }
+            };
+
+            let accepted = queries.try_execute(move || {
+                if let Err(e) = handle_query(socket, req_buffer, src, server) {
+                    eprintln!("An error occurred: {}", e);
+                }
+            });
+            if !accepted {
+                eprintln!(
+                    "Dropping query from {}, {} queries in flight",
+                    src,
+                    queries.in_flight()
+                );
+            }
+        }
+    })
 }
 
-fn recursive_lookup(qname: &str, qtype: QueryType) -> Result<DnsPacket> {
-    // For now we're always starting with *a.root-servers.net*.
-    let mut ns = "198.41.0.4".parse::<Ipv4Addr>().unwrap();
+/// Handle a single incoming packet from `src`.
+fn handle_query(
+    socket: &UdpSocket,
+    mut req_buffer: BytePacketBuffer,
+    src: SocketAddr,
+    server: &Server,
+) -> Result<()> {
+    // Next, `DnsPacket::from_buffer` is used to parse the raw bytes into a
+    // `DnsPacket`.
+    let request = DnsPacket::from_buffer(&mut req_buffer)?;
+    let Some(mut packet) = dispatch(&request, &mut req_buffer, src, server) else {
+        return Ok(());
+    };
+
+    // Last thing remaining is to encode our response and send it
+    let mut res_buffer = BytePacketBuffer::new();
+    packet.write(&mut res_buffer)?;
 
-    // Since it might take an arbitrary number of steps, we enter an unbounded
-    // loop.
-    loop {
-        println!("attempting lookup of {:?} {} with ns {}", qtype, qname, ns);
+    let len = res_buffer.pos();
+    let data = res_buffer.get_range(0, len)?;
 
-        // The next step is to send the query to the active server.
-        let ns_copy = ns;
+    socket.send_to(data, src)?;
 
-        let server = (ns_copy, 53);
-        let response = lookup(qname, qtype, server)?;
+    Ok(())
+}
 
-        // If there are entries in the answer section, and no errors, we are
-        // done!
-        if !response.answers.is_empty() && response.header.rescode == ResultCode::NOERROR {
-            return Ok(response);
-        }
+/// Serve the messages sent over a single TCP connection, until the client
+/// hangs up.
+fn handle_connection(mut stream: TcpStream, server: &Server) -> Result<()> {
+    let peer = stream.peer_addr()?;
+    stream.set_read_timeout(Some(TCP_TIMEOUT))?;
+    stream.set_write_timeout(Some(TCP_TIMEOUT))?;
+
+    while let Some(mut buffer) = tcp::read_buffer(&mut stream)? {
+        let request = DnsPacket::from_buffer(&mut buffer)?;
+        let transfer = request
+            .questions
+            .first()
+            .filter(|_| request.questions.len() == 1)
+            .filter(|question| matches!(question.qtype, QueryType::AXFR | QueryType::IXFR))
+            .filter(|_| request.header.opcode == Opcode::QUERY && !request.header.response)
+            .cloned();
+
+        let Some(question) = transfer else {
+            if let Some(mut packet) = dispatch(&request, &mut buffer, peer, server) {
+                tcp::write_packet(&mut stream, &mut packet)?;
+            }
+            continue;
+        };
 
-        // We might also get an `NXDOMAIN` reply, which is the authoritative
-        // name servers way of telling us that the neame doesn't exist.
-        if response.header.rescode == ResultCode::NXDOMAIN {
-            return Ok(response);
+        // Only clients on the list get to see a zone in full, and only
+        // for zones that we're authoritative for.
+        let zone = server.zones.read().unwrap().get(&question.name);
+        match zone {
+            Some(zone) if server.transfer_acl.allows(&peer.ip()) => {
+                println!(
+                    "Transferring zone {} to {} with {:?}",
+                    zone.origin, peer, question.qtype
+                );
+                if question.qtype == QueryType::IXFR {
+                    transfer::send_ixfr(&mut stream, &zone, &request)?;
+                } else {
+                    transfer::send_axfr(&mut stream, &zone, &request)?;
+                }
+            }
+            _ => {
+                eprintln!("Refusing transfer of {} to {}", question.name, peer);
+
+                let mut packet = DnsPacket::new();
+                packet.header.id = request.header.id;
+                packet.header.response = true;
+                packet.header.rescode = ResultCode::REFUSED;
+                packet.questions.push(question);
+                tcp::write_packet(&mut stream, &mut packet)?;
+            }
         }
+    }
 
-        // Otherwise, we'll try to find a new nameserver based on `NS` and a
-        // corresponding `A` record in the additional section. If this succeeds,
-        // we chan switch name server and retry the loop.
-        if let Some(new_ns) = response.get_resolved_ns(qname) {
-            ns = new_ns;
-            continue;
+    Ok(())
+}
+
+/// Build the response to a message from `src`, whatever kind it is. The raw
+/// message is in `buffer`, for kinds which are parsed differently.
+///
+/// Messages that are responses themselves get no response at all, as
+/// answering them could have two servers bounce messages back and forth
+/// forever.
+fn dispatch(
+    request: &DnsPacket,
+    buffer: &mut BytePacketBuffer,
+    src: SocketAddr,
+    server: &Server,
+) -> Option<DnsPacket> {
+    if request.header.response {
+        eprintln!("Ignoring response {} from {}", request.header.id, src);
+        return None;
+    }
+
+    let packet = match request.header.opcode {
+        Opcode::QUERY => {
+            let mut packet = respond(request, src, server)?;
+
+            // Names are folded to lowercase when parsed, but clients get
+            // their questions back exactly as they asked them.
+            if let Ok(questions) = DnsPacket::questions_as_sent(buffer) {
+                if questions.len() == packet.questions.len() {
+                    packet.questions = questions;
+                }
+            }
+
+            packet
         }
+        Opcode::NOTIFY => handle_notify(request, src, server),
+        Opcode::UPDATE => {
+            buffer.pos = 0;
+            match UpdatePacket::from_buffer(buffer) {
+                Ok(update) => handle_update(&update, src, server),
+                Err(_) => error_response(request, ResultCode::FORMERR),
+            }
+        }
+        opcode => {
+            eprintln!("Unsupported opcode {:?} from {}", opcode, src);
+            error_response(request, ResultCode::NOTIMP)
+        }
+    };
 
-        // If not, we'll have to resolve the ip of a `NS` record. If no `NS`
-        // records exist, we'll go with what the lastt server told us.
-        let new_ns_name = match response.get_unresolved_ns(qname) {
-            Some(x) => x,
-            None => return Ok(response),
-        };
+    Some(packet)
+}
+
+/// A bare response to `request`, reporting `rescode`.
+fn error_response(request: &DnsPacket, rescode: ResultCode) -> DnsPacket {
+    let mut packet = DnsPacket::new();
+    packet.header.id = request.header.id;
+    packet.header.opcode = request.header.opcode;
+    packet.header.response = true;
+    packet.header.rescode = rescode;
 
-        // Here we go down the rabbit hole by starting _another_ lookup
-        // sequence in the midst of our current one. Hopefully, this will give
-        // us the IP of an appropriate name server.
-        let recursive_response = recursive_lookup(&new_ns_name, QueryType::A)?;
+    packet
+}
 
-        // Finally, we pick a random ip from the result, and restart the loop.
-        // If no such record is available, we again return the last result we
-        // got.
-        if let Some(new_ns) = recursive_response.get_random_a() {
-            ns = new_ns;
-        } else {
-            return Ok(response);
+/// Apply a dynamic update from `src` to one of the zones we're the primary
+/// for. The new version is saved next to the zone's master file, which is
+/// left alone, and its secondaries are notified.
+fn handle_update(update: &UpdatePacket, src: SocketAddr, server: &Server) -> DnsPacket {
+    let origin = match update.zones.as_slice() {
+        [zone] if zone.qtype == QueryType::SOA => zone.name.clone(),
+        _ => return update::response(update, ResultCode::FORMERR),
+    };
+
+    if !server.update_acl.allows(&src.ip()) {
+        eprintln!("Refusing update of {} from {}", origin, src);
+        return update::response(update, ResultCode::REFUSED);
+    }
+
+    // Updates to secondary zones would have to go to the primary, which we
+    // leave to the client.
+    let Some(file) = server.updates_files.get(&origin) else {
+        return update::response(update, ResultCode::NOTAUTH);
+    };
+
+    // Updates are applied one at a time, from reading the current version
+    // until the new one is saved. Queries only wait for the zones while the
+    // new version is put in place, not for the disk.
+    let _update = server.updates.lock().unwrap();
+    let Some(zone) = server.zones.read().unwrap().get(&origin) else {
+        return update::response(update, ResultCode::NOTAUTH);
+    };
+
+    match update::apply(&zone, update) {
+        Ok(Some(zone)) => {
+            println!(
+                "Updated zone {} to serial {} for {}",
+                origin,
+                zone.serial(),
+                src
+            );
+            server.zones.write().unwrap().insert(zone);
+            server.notifier.zone_changed(&origin);
+
+            let saved = server.zones.read().unwrap().get(&origin);
+            if let Err(e) = saved.map_or(Ok(()), |zone| zone.save(file)) {
+                eprintln!("Unable to save zone {}: {}", origin, e);
+            }
+            update::response(update, ResultCode::NOERROR)
         }
+        Ok(None) => update::response(update, ResultCode::NOERROR),
+        Err(rescode) => update::response(update, rescode),
     }
 }
 
-/// Handle a single incoming packet
-fn handle_query(socket: &UdpSocket) -> Result<()> {
-    // With a socket ready, we can read a packet. This will block until one is
-    // received.
-    let mut req_buffer = BytePacketBuffer::new();
+/// Acknowledge a `NOTIFY` from the primary of one of our secondary zones, and
+/// have the zone checked for changes. Notifications from anyone else are
+/// refused.
+fn handle_notify(request: &DnsPacket, src: SocketAddr, server: &Server) -> DnsPacket {
+    let mut packet = notify::ack(request);
+
+    let origin = match request.questions.as_slice() {
+        [question] if question.qtype == QueryType::SOA => question.name.clone(),
+        _ => {
+            packet.header.rescode = ResultCode::FORMERR;
+            return packet;
+        }
+    };
+
+    let from_primary = server
+        .primaries
+        .get(&origin)
+        .is_some_and(|primary| primary.ip().to_canonical() == src.ip().to_canonical());
+    if !from_primary {
+        eprintln!("Refusing NOTIFY for {} from {}", origin, src);
+        packet.header.rescode = ResultCode::REFUSED;
+        return packet;
+    }
 
-    // The `recv_from` function will write the data into the provided buffer,
-    // and return the length of the data as well as the source addr. We're not
-    // interested in the length, but we need to keep track of the source in
-    // order to send our reply later on.
-    let (_, src) = socket.recv_from(&mut req_buffer.buf)?;
+    println!("Received NOTIFY for {} from {}", origin, src);
+    if server.notifications.send(origin).is_err() {
+        packet.header.rescode = ResultCode::SERVFAIL;
+    }
 
-    // Next, `DnsPacket::from_buffer` is used to parse the raw bytes into a
-    // `DnsPacket`.
-    let mut request = DnsPacket::from_buffer(&mut req_buffer)?;
+    packet
+}
 
+/// Build the response to a query, wherever it came from.
+fn respond(request: &DnsPacket, src: SocketAddr, server: &Server) -> Option<DnsPacket> {
     // Create and init the response packet
     let mut packet = DnsPacket::new();
     packet.header.id = request.header.id;
     packet.header.recursion_desired = true;
     packet.header.recursion_available = true;
     packet.header.response = true;
+    packet.questions = request.questions.clone();
 
-    // In the normal case, exactly one question is present
-    if let Some(question) = request.questions.pop() {
-        println!("Received query: {:?}", question);
+    // Extended DNS Errors explaining a failure, if the client speaks EDNS.
+    let mut extended_errors = Vec::new();
+
+    // We only speak version 0 of EDNS, and have to say so to clients asking
+    // for anything newer.
+    if let Some(DnsRecord::OPT { flags, .. }) = request.get_opt() {
+        if (flags >> 16) & 0xFF != 0 {
+            packet.header.rescode = ResultCode::BADVERS;
+            return Some(with_opt(packet, request, extended_errors));
+        }
+    }
 
-        // Since all is set up and as expected, the query can be forwarded to
-        // the target server. There's always the possibility that the query will
-        // fail, in which case the `SERVFAIL` response code is set to indicate
-        // as much to the client. If rather everything goes as planned, the
-        // question and response records are copied into our response packet.
-        if let Ok(result) = recursive_lookup(&question.name, question.qtype) {
-            packet.questions.push(question.clone());
-            packet.header.rescode = result.header.rescode;
+    // Being mindful of how unreliable input data from arbitrary senders can
+    // be, we need to make sure that there's exactly one question, which is
+    // all that a standard query may carry in practice. If not, we return
+    // `FORMERR` to indicate that the sender did something wrong. Unless
+    // asked to, that is, in which case every question is answered in turn.
+    let count = request.questions.len();
+    if count == 0 || (count > 1 && !server.multi_question) {
+        eprintln!("Query {} has {} questions", request.header.id, count);
+        packet.header.rescode = ResultCode::FORMERR;
+        return Some(with_opt(packet, request, extended_errors));
+    }
 
-            for rec in result.answers {
-                println!("Answer: {:?}", rec);
-                packet.answers.push(rec);
-            }
+    // Answers are only authoritative if they all are, and the first failure
+    // is the one reported.
+    packet.header.authoritative_answer = true;
+    for question in &request.questions {
+        println!("Received query: {:?}", question);
 
-            for rec in result.authorities {
-                println!("Authority: {:?}", rec);
-                packet.authorities.push(rec);
+        match answer(question, &src.ip(), server) {
+            // A policy said not to answer at all.
+            Ok(None) => return None,
+            Ok(Some(result)) => {
+                if packet.header.rescode == ResultCode::NOERROR {
+                    packet.header.rescode = result.header.rescode;
+                }
+                packet.header.authoritative_answer &= result.header.authoritative_answer;
+
+                for rec in result.answers {
+                    println!("Answer: {:?}", rec);
+                    packet.answers.push(rec);
+                }
+
+                for rec in result.authorities {
+                    println!("Authority: {:?}", rec);
+                    packet.authorities.push(rec);
+                }
+
+                for rec in result.resources {
+                    // The answer may explain itself with Extended DNS Errors,
+                    // which go into the `OPT` record made for the client.
+                    if let DnsRecord::OPT { options, .. } = rec {
+                        extended_errors.extend(
+                            options
+                                .into_iter()
+                                .filter(|option| option.code == EdnsOption::EXTENDED_ERROR),
+                        );
+                        continue;
+                    }
+
+                    println!("Resource: {:?}", rec);
+                    packet.resources.push(rec);
+                }
             }
+            Err(failure) => {
+                // Timeouts and any other failure alike leave us without an
+                // answer to give. Where we know why, the client is told.
+                eprintln!("Failed to resolve {:?}: {}", question, failure);
+                extended_errors.extend(failure.extended_error());
+
+                packet.header.authoritative_answer = false;
+                if packet.header.rescode == ResultCode::NOERROR {
+                    packet.header.rescode = ResultCode::SERVFAIL;
+                }
+            }
+        }
+    }
 
-            for rec in result.resources {
-                println!("Resource: {:?}", rec);
-                packet.resources.push(rec);
+    Some(with_opt(packet, request, extended_errors))
+}
+
+/// Find the answer to a single `question` from a client at `client`, from our
+/// own zones if we have it or else from the resolver. There's none if the
+/// query is to be dropped.
+fn answer(
+    question: &DnsQuestion,
+    client: &IpAddr,
+    server: &Server,
+) -> std::result::Result<Option<DnsPacket>, LookupError> {
+    let mut refusal = DnsPacket::new();
+
+    // Zone transfers only happen over TCP, where they're dealt with before
+    // ending up here.
+    if matches!(question.qtype, QueryType::AXFR | QueryType::IXFR) {
+        refusal.header.rescode = ResultCode::NOTIMP;
+        return Ok(Some(refusal));
+    }
+
+    // Local records and names in the zones we're authoritative for are
+    // answered straight from memory, only everything else is resolved
+    // recursively. Apart from the Internet, the only class we know is the one
+    // queries about ourselves come in.
+    let local = match question.class {
+        Class::IN | Class::ANY => server
+            .local
+            .read()
+            .unwrap()
+            .answer(&question.name, question.qtype)
+            .or_else(|| {
+                server
+                    .zones
+                    .read()
+                    .unwrap()
+                    .answer(&question.name, question.qtype)
+            }),
+        Class::CH => Some(server.chaos.answer(question)),
+        _ => {
+            refusal.header.rescode = ResultCode::REFUSED;
+            return Ok(Some(refusal));
+        }
+    };
+
+    match local {
+        Some(answer) => Ok(Some(answer)),
+        None => {
+            // Only what we answer from our own data is authoritative. Whatever
+            // was resolved comes with the header of the server it came from,
+            // which says nothing about us.
+            let mut response = recurse(question, client, server)?;
+            if let Some(response) = &mut response {
+                response.header.authoritative_answer = false;
             }
-        } else {
-            packet.header.rescode = ResultCode::SERVFAIL;
+
+            Ok(response)
         }
-    } else {
-        // Being mindful of how unreliable input data from arbitrary senders
-        // can be, we need to make sure that a question is actually present. If
-        // not, we return `FORMERR` to indicate that the sender did something
-        // wrong.
-        packet.header.rescode = ResultCode::FORMERR;
+    }
+}
+
+/// Find the answer to `question` from a client at `client` elsewhere, with
+/// policy zones and the blocklist applied. There's none if the query is to be
+/// dropped.
+fn recurse(
+    question: &DnsQuestion,
+    client: &IpAddr,
+    server: &Server,
+) -> std::result::Result<Option<DnsPacket>, LookupError> {
+    // Policy zones have the first say about what we resolve, before and after
+    // resolving, then the blocklist does. Blocked names aren't resolved at
+    // all, and neither are aliases for them.
+    let (name, qtype) = (&question.name, question.qtype);
+    let policies = server.policies.current(&server.zones);
+    if let Some(hit) = rpz::check_query(&policies, client, name) {
+        return apply_policy(&hit, question, server);
+    }
+    if let Some(blocked) = server.blocklist.check(name, qtype) {
+        return Ok(Some(blocked));
     }
 
-    // Last thing remaining is to encode our response and send it
-    let mut res_buffer = BytePacketBuffer::new();
-    packet.write(&mut res_buffer)?;
+    let response = server.resolver.query(name, qtype)?;
+    if let Some(hit) = rpz::check_response(&policies, client, name, &response, &server.resolver) {
+        return apply_policy(&hit, question, server);
+    }
 
-    let len = res_buffer.pos();
-    let data = res_buffer.get_range(0, len)?;
+    Ok(Some(
+        server
+            .blocklist
+            .check_response(name, qtype, &response)
+            .unwrap_or(response),
+    ))
+}
 
-    socket.send_to(data, src)?;
+/// Answer `question` the way a policy that was set off says to.
+fn apply_policy(
+    hit: &Hit,
+    question: &DnsQuestion,
+    server: &Server,
+) -> std::result::Result<Option<DnsPacket>, LookupError> {
+    println!(
+        "Policy zone {} matched {} by {:?}, {:?}",
+        hit.policy.origin, question.name, hit.trigger, hit.action
+    );
+
+    hit.rewrite(&question.name, question.qtype, &server.resolver)
+}
 
-    Ok(())
+/// Attach an `OPT` record with `extended_errors` to `packet`, which may only
+/// be done if the request carried one. Without it, extended response codes
+/// can't be told apart from others, and are turned into `SERVFAIL`.
+fn with_opt(
+    mut packet: DnsPacket,
+    request: &DnsPacket,
+    extended_errors: Vec<EdnsOption>,
+) -> DnsPacket {
+    packet
+        .resources
+        .retain(|record| !matches!(record, DnsRecord::OPT { .. }));
+
+    if request.get_opt().is_some() {
+        packet.resources.push(DnsRecord::OPT {
+            packet_len: 512,
+            flags: 0,
+            options: extended_errors,
+        });
+    } else if packet.header.rescode.is_extended() {
+        packet.header.rescode = ResultCode::SERVFAIL;
+    }
+
+    packet
 }
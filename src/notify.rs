@@ -0,0 +1,147 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    packet::{BytePacketBuffer, DnsPacket, DnsQuestion, Opcode, QueryType, Result, ResultCode},
+    random,
+};
+
+/// How many times a `NOTIFY` is sent before giving up on the secondary acknowledging it.
+const ATTEMPTS: u32 = 5;
+/// How long to wait for the first acknowledgement, doubled on every retry.
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Tells secondaries that their copy of a zone is out of date, as laid out in
+/// [RFC1996](https://datatracker.ietf.org/doc/html/rfc1996).
+#[derive(Debug, Clone, Default)]
+pub struct Notifier {
+    /// The secondaries to notify, by zone origin.
+    targets: HashMap<String, Vec<SocketAddr>>,
+}
+
+impl Notifier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Notify `target` about changes to the zone at `origin`.
+    pub fn add(&mut self, origin: &str, target: SocketAddr) {
+        let origin = origin.trim_end_matches('.').to_lowercase();
+        self.targets.entry(origin).or_default().push(target);
+    }
+
+    /// Let the secondaries of the zone at `origin` know that it changed. The notifications go
+    /// out in the background, so that slow or absent secondaries don't hold us up. The new
+    /// version has to be served already, or a quick secondary asks for the `SOA`, finds nothing
+    /// new and goes back to sleep until its next refresh.
+    pub fn zone_changed(&self, origin: &str) {
+        for target in self.targets.get(origin).into_iter().flatten() {
+            let origin = origin.to_string();
+            let target = *target;
+
+            thread::spawn(move || {
+                if let Err(e) = send(&origin, target) {
+                    eprintln!("Unable to notify {} about zone {}: {}", target, origin, e);
+                }
+            });
+        }
+    }
+}
+
+/// Send a `NOTIFY` for the zone at `origin` to `target`, and wait for it to be acknowledged.
+pub fn send(origin: &str, target: SocketAddr) -> Result<()> {
+    let local: IpAddr = match target {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = UdpSocket::bind((local, 0))?;
+
+    let mut packet = DnsPacket::new();
+    packet.header.id = random::random_u16()?;
+    packet.header.opcode = Opcode::NOTIFY;
+    packet.header.authoritative_answer = true;
+    packet
+        .questions
+        .push(DnsQuestion::new(origin.to_string(), QueryType::SOA));
+
+    let mut req_buffer = BytePacketBuffer::new();
+    packet.write(&mut req_buffer)?;
+
+    let mut timeout = TIMEOUT;
+    for _ in 0..ATTEMPTS {
+        socket.send_to(&req_buffer.buf[0..req_buffer.pos], target)?;
+        if wait_for_ack(&socket, &packet, target, timeout)? {
+            return Ok(());
+        }
+        timeout *= 2;
+    }
+
+    Err(format!("No acknowledgement after {} attempts", ATTEMPTS).into())
+}
+
+/// Wait up to `timeout` for `target` to acknowledge `notify`. Anything else arriving on the
+/// socket in the meantime is ignored.
+fn wait_for_ack(
+    socket: &UdpSocket,
+    notify: &DnsPacket,
+    target: SocketAddr,
+    timeout: Duration,
+) -> Result<bool> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(false);
+        }
+        socket.set_read_timeout(Some(remaining))?;
+
+        let mut res_buffer = BytePacketBuffer::new();
+        let src = match socket.recv_from(&mut res_buffer.buf) {
+            Ok((_, src)) => src,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Ok(false);
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if src != target {
+            continue;
+        }
+
+        let Ok(response) = DnsPacket::from_buffer(&mut res_buffer) else {
+            continue;
+        };
+        if response.header.response
+            && response.header.id == notify.header.id
+            && response.header.opcode == Opcode::NOTIFY
+        {
+            if response.header.rescode != ResultCode::NOERROR {
+                return Err(
+                    format!("Notification refused with {:?}", response.header.rescode).into(),
+                );
+            }
+            return Ok(true);
+        }
+    }
+}
+
+/// The acknowledgement of the `NOTIFY` message `request`.
+pub fn ack(request: &DnsPacket) -> DnsPacket {
+    let mut packet = DnsPacket::new();
+    packet.header.id = request.header.id;
+    packet.header.opcode = Opcode::NOTIFY;
+    packet.header.response = true;
+    packet.header.authoritative_answer = true;
+    packet.questions = request.questions.clone();
+
+    packet
+}
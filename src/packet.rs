@@ -1,10 +1,16 @@
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+use crate::random;
 
 /// Convenience type for a `Result` which return a generic `Error`
 pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
 pub struct BytePacketBuffer {
-    pub buf: [u8; 512],
+    pub buf: Vec<u8>,
     pub pos: usize,
 }
 
@@ -15,13 +21,21 @@ impl Default for BytePacketBuffer {
 }
 
 impl BytePacketBuffer {
-    const BUF_LEN: usize = 512;
+    /// The largest message that may be sent over UDP without EDNS.
+    pub const UDP_LEN: usize = 512;
+    /// The largest message that fits behind the two byte length prefix used over TCP.
+    pub const TCP_LEN: usize = 65535;
 
     /// This gives us a fresh buffer for holding the packet contents, and a field for keeping track
     /// of where we are.
     pub fn new() -> Self {
+        Self::with_len(Self::UDP_LEN)
+    }
+
+    /// A buffer for messages of up to `len` bytes, such as those sent over TCP.
+    pub fn with_len(len: usize) -> Self {
         Self {
-            buf: [0; Self::BUF_LEN],
+            buf: vec![0; len],
             pos: 0,
         }
     }
@@ -60,7 +74,7 @@ impl BytePacketBuffer {
 
     /// Read a single byte and move the position one step forward.
     fn read(&mut self) -> Result<u8> {
-        if self.pos > Self::BUF_LEN {
+        if self.pos >= self.buf.len() {
             return Err("End of buffer".into());
         }
 
@@ -72,7 +86,7 @@ impl BytePacketBuffer {
 
     /// Get a single byte without changing the buffer position.
     fn get(&mut self, pos: usize) -> Result<u8> {
-        if pos > Self::BUF_LEN {
+        if pos >= self.buf.len() {
             return Err("End of buffer".into());
         }
 
@@ -81,7 +95,7 @@ impl BytePacketBuffer {
 
     /// Get a range of bytes.
     pub fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]> {
-        if start + len > Self::BUF_LEN {
+        if start + len > self.buf.len() {
             return Err("End of buffer".into());
         }
 
@@ -110,6 +124,23 @@ impl BytePacketBuffer {
     /// The tricky part: reading domain names, taking labels into consideration.
     /// Will take something like `[3]www[6]google[3]com[0]` and append www.google.com to `outstr`.
     fn read_qname(&mut self, outstr: &mut String) -> Result<()> {
+        self.read_qname_with_case(outstr, false)
+    }
+
+    /// Read the qname at `pos` exactly as it appears in the packet, without folding it to
+    /// lowercase and without moving the buffer position.
+    pub fn read_qname_at(&mut self, pos: usize) -> Result<String> {
+        let saved = self.pos();
+        self.seek(pos)?;
+
+        let mut outstr = String::new();
+        let res = self.read_qname_with_case(&mut outstr, true);
+        self.seek(saved)?;
+
+        res.map(|_| outstr)
+    }
+
+    fn read_qname_with_case(&mut self, outstr: &mut String, preserve_case: bool) -> Result<()> {
         // Since we might encounter jumps, we'll keep track of our position locally as opposed to
         // using the position within the struct. This allows us to move the shared position to a
         // point past our current qname, while keeping track of our progress on the current qname
@@ -175,7 +206,12 @@ impl BytePacketBuffer {
                 // Extract the actual ASCII bytes for this label and append them to the output
                 // buffer.
                 let str_buffer = self.get_range(pos, len as usize)?;
-                outstr.push_str(&String::from_utf8_lossy(str_buffer).to_lowercase());
+                let label = String::from_utf8_lossy(str_buffer);
+                if preserve_case {
+                    outstr.push_str(&label);
+                } else {
+                    outstr.push_str(&label.to_lowercase());
+                }
 
                 delim = ".";
 
@@ -192,7 +228,7 @@ impl BytePacketBuffer {
     }
 
     fn write(&mut self, val: u8) -> Result<()> {
-        if self.pos > Self::BUF_LEN {
+        if self.pos >= self.buf.len() {
             return Err("End of buffer".into());
         }
 
@@ -223,7 +259,8 @@ impl BytePacketBuffer {
     }
 
     fn write_qname(&mut self, qname: &str) -> Result<()> {
-        for label in qname.split('.') {
+        // The root domain is nothing but the terminating empty label.
+        for label in qname.split('.').filter(|label| !label.is_empty()) {
             let len = label.len();
             if len > 0x3F {
                 return Err("Single label exceeds 63 characters of length".into());
@@ -241,26 +278,143 @@ impl BytePacketBuffer {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The response code of a message, covering the values registered with
+/// [IANA](https://www.iana.org/assignments/dns-parameters/dns-parameters.xhtml#dns-parameters-6).
+///
+/// Only the lower 4 bits fit in the header, the upper 8 bits of the extended
+/// codes are carried by the `OPT` record, see
+/// [RFC6891](https://datatracker.ietf.org/doc/html/rfc6891#section-6.1.3).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[allow(clippy::upper_case_acronyms)]
 pub enum ResultCode {
-    NOERROR = 0,
-    FORMERR = 1,
-    SERVFAIL = 2,
-    NXDOMAIN = 3,
-    NOTIMP = 4,
-    REFUSED = 5,
+    NOERROR,
+    FORMERR,
+    SERVFAIL,
+    NXDOMAIN,
+    NOTIMP,
+    REFUSED,
+    YXDOMAIN,
+    YXRRSET,
+    NXRRSET,
+    NOTAUTH,
+    NOTZONE,
+    DSOTYPENI,
+    /// Also known as `BADSIG` in TSIG records, which share the value.
+    BADVERS,
+    BADKEY,
+    BADTIME,
+    BADMODE,
+    BADNAME,
+    BADALG,
+    BADTRUNC,
+    BADCOOKIE,
+    Unknown(u16),
 }
 
 impl ResultCode {
-    fn from_num(num: u8) -> Self {
+    pub fn as_num(&self) -> u16 {
+        match *self {
+            Self::NOERROR => 0,
+            Self::FORMERR => 1,
+            Self::SERVFAIL => 2,
+            Self::NXDOMAIN => 3,
+            Self::NOTIMP => 4,
+            Self::REFUSED => 5,
+            Self::YXDOMAIN => 6,
+            Self::YXRRSET => 7,
+            Self::NXRRSET => 8,
+            Self::NOTAUTH => 9,
+            Self::NOTZONE => 10,
+            Self::DSOTYPENI => 11,
+            Self::BADVERS => 16,
+            Self::BADKEY => 17,
+            Self::BADTIME => 18,
+            Self::BADMODE => 19,
+            Self::BADNAME => 20,
+            Self::BADALG => 21,
+            Self::BADTRUNC => 22,
+            Self::BADCOOKIE => 23,
+            Self::Unknown(x) => x,
+        }
+    }
+
+    pub fn from_num(num: u16) -> Self {
         match num {
+            0 => Self::NOERROR,
             1 => Self::FORMERR,
             2 => Self::SERVFAIL,
             3 => Self::NXDOMAIN,
             4 => Self::NOTIMP,
             5 => Self::REFUSED,
-            _ => Self::NOERROR,
+            6 => Self::YXDOMAIN,
+            7 => Self::YXRRSET,
+            8 => Self::NXRRSET,
+            9 => Self::NOTAUTH,
+            10 => Self::NOTZONE,
+            11 => Self::DSOTYPENI,
+            16 => Self::BADVERS,
+            17 => Self::BADKEY,
+            18 => Self::BADTIME,
+            19 => Self::BADMODE,
+            20 => Self::BADNAME,
+            21 => Self::BADALG,
+            22 => Self::BADTRUNC,
+            23 => Self::BADCOOKIE,
+            _ => Self::Unknown(num),
+        }
+    }
+
+    /// Whether the code doesn't fit in the header alone, and can thus only be
+    /// sent along with an `OPT` record.
+    pub fn is_extended(&self) -> bool {
+        self.as_num() > 0x0F
+    }
+}
+
+/// The kind of a message, as registered with
+/// [IANA](https://www.iana.org/assignments/dns-parameters/dns-parameters.xhtml#dns-parameters-5).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Opcode {
+    /// A standard query.
+    QUERY,
+    /// An inverse query, long obsolete.
+    IQUERY,
+    STATUS,
+    /// A change to a zone, see
+    /// [RFC1996](https://datatracker.ietf.org/doc/html/rfc1996).
+    NOTIFY,
+    /// A dynamic update, see
+    /// [RFC2136](https://datatracker.ietf.org/doc/html/rfc2136).
+    UPDATE,
+    /// DNS Stateful Operations, see
+    /// [RFC8490](https://datatracker.ietf.org/doc/html/rfc8490).
+    DSO,
+    Unknown(u8),
+}
+
+impl Opcode {
+    pub fn as_num(&self) -> u8 {
+        match *self {
+            Self::QUERY => 0,
+            Self::IQUERY => 1,
+            Self::STATUS => 2,
+            Self::NOTIFY => 4,
+            Self::UPDATE => 5,
+            Self::DSO => 6,
+            Self::Unknown(x) => x,
+        }
+    }
+
+    pub fn from_num(num: u8) -> Self {
+        match num {
+            0 => Self::QUERY,
+            1 => Self::IQUERY,
+            2 => Self::STATUS,
+            4 => Self::NOTIFY,
+            5 => Self::UPDATE,
+            6 => Self::DSO,
+            _ => Self::Unknown(num),
         }
     }
 }
@@ -289,8 +443,8 @@ pub struct DnsHeader {
     pub authoritative_answer: bool, // 1 bit
     /// # Operation Code
     ///
-    /// Typically always 0, see [RFC1035](https://datatracker.ietf.org/doc/html/rfc1035) for details.
-    pub opcode: u8, // 4 bits
+    /// The kind of message, typically a standard query, see [Opcode].
+    pub opcode: Opcode, // 4 bits
     /// # Query Response
     ///
     /// 0 for queries, 1 for response.
@@ -300,6 +454,9 @@ pub struct DnsHeader {
     /// Set by the server to indicate the status of the response, i.e. whether or not it was
     /// successful or failed, and in the latter case providing details about the cause of the
     /// failure.
+    ///
+    /// Only the lower 4 bits are in the header, the rest is read from and
+    /// written to the `OPT` record of the message.
     pub rescode: ResultCode, // 4 bits
     pub checking_disabled: bool, // 1 bit
     pub authed_data: bool,       // 1 bit
@@ -336,7 +493,7 @@ impl DnsHeader {
             recursion_desired: false,
             truncated_message: false,
             authoritative_answer: false,
-            opcode: 0,
+            opcode: Opcode::QUERY,
             response: false,
             rescode: ResultCode::NOERROR,
             checking_disabled: false,
@@ -359,10 +516,10 @@ impl DnsHeader {
         self.recursion_desired = (a & (1 << 0)) > 0;
         self.truncated_message = (a & (1 << 1)) > 0;
         self.authoritative_answer = (a & (1 << 2)) > 0;
-        self.opcode = (a >> 3) & 0x0F;
+        self.opcode = Opcode::from_num((a >> 3) & 0x0F);
         self.response = (a & (1 << 7)) > 0;
 
-        self.rescode = ResultCode::from_num(b & 0x0F);
+        self.rescode = ResultCode::from_num((b & 0x0F) as u16);
         self.checking_disabled = (b & (1 << 4)) > 0;
         self.authed_data = (b & (1 << 5)) > 0;
         self.z = (b & (1 << 6)) > 0;
@@ -383,12 +540,12 @@ impl DnsHeader {
             (self.recursion_desired as u8)
                 | ((self.truncated_message as u8) << 1)
                 | ((self.authoritative_answer as u8) << 2)
-                | (self.opcode << 3 as u8)
-                | ((self.response as u8) << 7) as u8,
+                | (self.opcode.as_num() << 3)
+                | ((self.response as u8) << 7),
         )?;
 
         buffer.write_u8(
-            (self.rescode as u8)
+            (self.rescode.as_num() & 0x0F) as u8
                 | ((self.checking_disabled as u8) << 4)
                 | ((self.authed_data as u8) << 5)
                 | ((self.z as u8) << 6)
@@ -410,49 +567,154 @@ pub enum QueryType {
     A,     // 1
     NS,    // 2
     CNAME, // 3
-    MX,    // 4
-    AAAA,  // 5
+    SOA,   // 6
+    PTR,   // 12
+    MX,    // 15
+    TXT,   // 16
+    AAAA,  // 28
+    OPT,   // 41
+    IXFR,  // 251
+    AXFR,  // 252
+    /// Asks for records of every type.
+    ANY, // 255
 }
 
 impl QueryType {
-    fn as_num(&self) -> u16 {
+    pub fn as_num(&self) -> u16 {
         match *self {
             QueryType::Unknown(x) => x,
             QueryType::A => 1,
             QueryType::NS => 2,
             QueryType::CNAME => 5,
+            QueryType::SOA => 6,
+            QueryType::PTR => 12,
             QueryType::MX => 15,
+            QueryType::TXT => 16,
             QueryType::AAAA => 28,
+            QueryType::OPT => 41,
+            QueryType::IXFR => 251,
+            QueryType::AXFR => 252,
+            QueryType::ANY => 255,
         }
     }
 
-    fn from_num(num: u16) -> Self {
+    pub fn from_num(num: u16) -> Self {
         match num {
             1 => QueryType::A,
             2 => QueryType::NS,
             5 => QueryType::CNAME,
+            6 => QueryType::SOA,
+            12 => QueryType::PTR,
             15 => QueryType::MX,
+            16 => QueryType::TXT,
             28 => QueryType::AAAA,
+            41 => QueryType::OPT,
+            251 => QueryType::IXFR,
+            252 => QueryType::AXFR,
+            255 => QueryType::ANY,
             _ => QueryType::Unknown(num),
         }
     }
 }
 
+/// The class of a question or record, registered with
+/// [IANA](https://www.iana.org/assignments/dns-parameters/dns-parameters.xhtml#dns-parameters-2).
+///
+/// Next to the Internet, there's hardly any use for the others these days, apart from `CH`
+/// which servers answer queries about themselves in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Class {
+    Unknown(u16),
+    IN, // 1
+    CH, // 3
+    HS, // 4
+    /// Only used in `UPDATE` messages, to delete a specific record.
+    NONE, // 254
+    /// Matches any class in questions, and stands for whole RRsets or names in `UPDATE`
+    /// messages.
+    ANY, // 255
+}
+
+impl Class {
+    pub fn as_num(&self) -> u16 {
+        match *self {
+            Class::Unknown(x) => x,
+            Class::IN => 1,
+            Class::CH => 3,
+            Class::HS => 4,
+            Class::NONE => 254,
+            Class::ANY => 255,
+        }
+    }
+
+    pub fn from_num(num: u16) -> Self {
+        match num {
+            1 => Class::IN,
+            3 => Class::CH,
+            4 => Class::HS,
+            254 => Class::NONE,
+            255 => Class::ANY,
+            _ => Class::Unknown(num),
+        }
+    }
+}
+
+impl fmt::Display for Class {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Class::Unknown(x) => write!(f, "CLASS{}", x),
+            Class::IN => f.write_str("IN"),
+            Class::CH => f.write_str("CH"),
+            Class::HS => f.write_str("HS"),
+            Class::NONE => f.write_str("NONE"),
+            Class::ANY => f.write_str("ANY"),
+        }
+    }
+}
+
+impl FromStr for Class {
+    type Err = Box<dyn std::error::Error>;
+
+    /// Parse a class by its mnemonic, or in the `CLASS<number>` form of
+    /// [RFC3597](https://datatracker.ietf.org/doc/html/rfc3597#section-5).
+    fn from_str(s: &str) -> Result<Self> {
+        let upper = s.to_uppercase();
+        match upper.as_str() {
+            "IN" => Ok(Class::IN),
+            "CH" => Ok(Class::CH),
+            "HS" => Ok(Class::HS),
+            "NONE" => Ok(Class::NONE),
+            "ANY" => Ok(Class::ANY),
+            _ => upper
+                .strip_prefix("CLASS")
+                .and_then(|num| num.parse().ok())
+                .map(Class::from_num)
+                .ok_or_else(|| format!("Invalid class {}", s).into()),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DnsQuestion {
     pub name: String,
     pub qtype: QueryType,
+    pub class: Class,
 }
 
 impl DnsQuestion {
+    /// A question about `name` in the Internet class.
     pub fn new(name: String, qtype: QueryType) -> Self {
-        Self { name, qtype }
+        Self {
+            name,
+            qtype,
+            class: Class::IN,
+        }
     }
 
     fn read(&mut self, buffer: &mut BytePacketBuffer) -> Result<()> {
         buffer.read_qname(&mut self.name)?;
         self.qtype = QueryType::from_num(buffer.read_u16()?); // qtype
-        let _ = buffer.read_u16()?; // class
+        self.class = Class::from_num(buffer.read_u16()?); // class
 
         Ok(())
     }
@@ -462,7 +724,7 @@ impl DnsQuestion {
 
         let type_num = self.qtype.as_num();
         buffer.write_u16(type_num)?;
-        buffer.write_u16(1)?;
+        buffer.write_u16(self.class.as_num())?;
 
         Ok(())
     }
@@ -472,49 +734,251 @@ impl DnsQuestion {
 pub enum DnsRecord {
     Unknown {
         domain: String,
+        class: Class,
         qtype: u16,
         data_len: u16,
         ttl: u32,
     }, // 0
     A {
         domain: String,
+        class: Class,
         addr: Ipv4Addr,
         ttl: u32,
     }, // 1
     NS {
         domain: String,
+        class: Class,
         host: String,
         ttl: u32,
     }, // 2
     CNAME {
         domain: String,
+        class: Class,
         host: String,
         ttl: u32,
     }, // 5
+    SOA {
+        domain: String,
+        class: Class,
+        m_name: String,
+        r_name: String,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+        ttl: u32,
+    }, // 6
+    /// Points from a name under `in-addr.arpa` or `ip6.arpa` back to the host the address belongs
+    /// to.
+    PTR {
+        domain: String,
+        class: Class,
+        host: String,
+        ttl: u32,
+    }, // 12
     MX {
         domain: String,
+        class: Class,
         priority: u16,
         host: String,
         ttl: u32,
     }, // 15
+    /// Any number of strings of up to 255 bytes each, in a single record.
+    TXT {
+        domain: String,
+        class: Class,
+        data: Vec<String>,
+        ttl: u32,
+    }, // 16
     AAAA {
         domain: String,
+        class: Class,
         addr: Ipv6Addr,
         ttl: u32,
     }, // 28
+    /// # EDNS Pseudo-Record
+    ///
+    /// Always owned by the root. The class and TTL fields are repurposed to carry the sender's
+    /// UDP payload size and the extended flags, see
+    /// [RFC6891](https://datatracker.ietf.org/doc/html/rfc6891).
+    OPT {
+        packet_len: u16,
+        flags: u32,
+        options: Vec<EdnsOption>,
+    }, // 41
+}
+
+/// A single option from the data of an `OPT` record.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
+impl EdnsOption {
+    /// Option code of an Extended DNS Error, see
+    /// [RFC8914](https://datatracker.ietf.org/doc/html/rfc8914).
+    pub const EXTENDED_ERROR: u16 = 15;
+
+    /// Build an Extended DNS Error option from an info code and a human readable explanation.
+    pub fn extended_error(info_code: u16, extra_text: &str) -> Self {
+        let mut data = info_code.to_be_bytes().to_vec();
+        data.extend_from_slice(extra_text.as_bytes());
+
+        Self {
+            code: Self::EXTENDED_ERROR,
+            data,
+        }
+    }
 }
 
 impl DnsRecord {
+    /// The owner name of the record.
+    pub fn domain(&self) -> &str {
+        match self {
+            DnsRecord::Unknown { domain, .. }
+            | DnsRecord::A { domain, .. }
+            | DnsRecord::NS { domain, .. }
+            | DnsRecord::CNAME { domain, .. }
+            | DnsRecord::SOA { domain, .. }
+            | DnsRecord::PTR { domain, .. }
+            | DnsRecord::MX { domain, .. }
+            | DnsRecord::TXT { domain, .. }
+            | DnsRecord::AAAA { domain, .. } => domain,
+            DnsRecord::OPT { .. } => "",
+        }
+    }
+
+    /// Change the owner name of the record. Has no effect on `OPT` records, which are always
+    /// owned by the root.
+    pub fn set_domain(&mut self, name: &str) {
+        match self {
+            DnsRecord::Unknown { domain, .. }
+            | DnsRecord::A { domain, .. }
+            | DnsRecord::NS { domain, .. }
+            | DnsRecord::CNAME { domain, .. }
+            | DnsRecord::SOA { domain, .. }
+            | DnsRecord::PTR { domain, .. }
+            | DnsRecord::MX { domain, .. }
+            | DnsRecord::TXT { domain, .. }
+            | DnsRecord::AAAA { domain, .. } => *domain = name.to_string(),
+            DnsRecord::OPT { .. } => {}
+        }
+    }
+
+    /// The time to live of the record, or zero for `OPT` records which don't have one.
+    pub fn ttl(&self) -> u32 {
+        match self {
+            DnsRecord::Unknown { ttl, .. }
+            | DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::PTR { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::TXT { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. } => *ttl,
+            DnsRecord::OPT { .. } => 0,
+        }
+    }
+
+    /// Change the time to live of the record. Has no effect on `OPT` records.
+    pub fn set_ttl(&mut self, new_ttl: u32) {
+        match self {
+            DnsRecord::Unknown { ttl, .. }
+            | DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::PTR { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::TXT { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. } => *ttl = new_ttl,
+            DnsRecord::OPT { .. } => {}
+        }
+    }
+
+    /// The class of the record. `OPT` records, which repurpose the field, count as `IN`.
+    pub fn class(&self) -> Class {
+        match self {
+            DnsRecord::Unknown { class, .. }
+            | DnsRecord::A { class, .. }
+            | DnsRecord::NS { class, .. }
+            | DnsRecord::CNAME { class, .. }
+            | DnsRecord::SOA { class, .. }
+            | DnsRecord::PTR { class, .. }
+            | DnsRecord::MX { class, .. }
+            | DnsRecord::TXT { class, .. }
+            | DnsRecord::AAAA { class, .. } => *class,
+            DnsRecord::OPT { .. } => Class::IN,
+        }
+    }
+
+    /// Change the class of the record. Has no effect on `OPT` records.
+    pub fn set_class(&mut self, new_class: Class) {
+        match self {
+            DnsRecord::Unknown { class, .. }
+            | DnsRecord::A { class, .. }
+            | DnsRecord::NS { class, .. }
+            | DnsRecord::CNAME { class, .. }
+            | DnsRecord::SOA { class, .. }
+            | DnsRecord::PTR { class, .. }
+            | DnsRecord::MX { class, .. }
+            | DnsRecord::TXT { class, .. }
+            | DnsRecord::AAAA { class, .. } => *class = new_class,
+            DnsRecord::OPT { .. } => {}
+        }
+    }
+
+    /// Whether the two records are the same apart from their time to live.
+    pub fn same_data(&self, other: &DnsRecord) -> bool {
+        let (mut a, mut b) = (self.clone(), other.clone());
+        a.set_ttl(0);
+        b.set_ttl(0);
+
+        a == b
+    }
+
+    /// The type of the record.
+    pub fn qtype(&self) -> QueryType {
+        match self {
+            DnsRecord::Unknown { qtype, .. } => QueryType::from_num(*qtype),
+            DnsRecord::A { .. } => QueryType::A,
+            DnsRecord::NS { .. } => QueryType::NS,
+            DnsRecord::CNAME { .. } => QueryType::CNAME,
+            DnsRecord::SOA { .. } => QueryType::SOA,
+            DnsRecord::PTR { .. } => QueryType::PTR,
+            DnsRecord::MX { .. } => QueryType::MX,
+            DnsRecord::TXT { .. } => QueryType::TXT,
+            DnsRecord::AAAA { .. } => QueryType::AAAA,
+            DnsRecord::OPT { .. } => QueryType::OPT,
+        }
+    }
+
     fn read(buffer: &mut BytePacketBuffer) -> Result<Self> {
         let mut domain = String::new();
         buffer.read_qname(&mut domain)?;
 
         let qtype_num = buffer.read_u16()?;
         let qtype = QueryType::from_num(qtype_num);
-        let _ = buffer.read_u16()?;
+        let class_num = buffer.read_u16()?;
+        let class = Class::from_num(class_num);
         let ttl = buffer.read_u32()?;
         let data_len = buffer.read_u16()?;
 
+        // Records without any data only make sense in `UPDATE` messages, where they stand for
+        // all records of their type. There's nothing to decode.
+        if data_len == 0 && qtype != QueryType::OPT {
+            return Ok(Self::Unknown {
+                domain,
+                class,
+                qtype: qtype_num,
+                data_len,
+                ttl,
+            });
+        }
+
         let record = match qtype {
             QueryType::A => {
                 let raw_addr = buffer.read_u32()?;
@@ -525,7 +989,12 @@ impl DnsRecord {
                     ((raw_addr) & 0xFF) as u8,
                 );
 
-                Self::A { domain, addr, ttl }
+                Self::A {
+                    domain,
+                    class,
+                    addr,
+                    ttl,
+                }
             }
             QueryType::NS => {
                 let mut ns = String::new();
@@ -533,6 +1002,7 @@ impl DnsRecord {
 
                 Self::NS {
                     domain,
+                    class,
                     host: ns,
                     ttl,
                 }
@@ -543,10 +1013,48 @@ impl DnsRecord {
 
                 Self::CNAME {
                     domain,
+                    class,
                     host: cname,
                     ttl,
                 }
             }
+            QueryType::PTR => {
+                let mut host = String::new();
+                buffer.read_qname(&mut host)?;
+
+                Self::PTR {
+                    domain,
+                    class,
+                    host,
+                    ttl,
+                }
+            }
+            QueryType::SOA => {
+                let mut m_name = String::new();
+                buffer.read_qname(&mut m_name)?;
+
+                let mut r_name = String::new();
+                buffer.read_qname(&mut r_name)?;
+
+                let serial = buffer.read_u32()?;
+                let refresh = buffer.read_u32()?;
+                let retry = buffer.read_u32()?;
+                let expire = buffer.read_u32()?;
+                let minimum = buffer.read_u32()?;
+
+                Self::SOA {
+                    domain,
+                    class,
+                    m_name,
+                    r_name,
+                    serial,
+                    refresh,
+                    retry,
+                    expire,
+                    minimum,
+                    ttl,
+                }
+            }
             QueryType::MX => {
                 let priority = buffer.read_u16()?;
                 let mut mx = String::new();
@@ -554,11 +1062,29 @@ impl DnsRecord {
 
                 Self::MX {
                     domain,
+                    class,
                     priority,
                     host: mx,
                     ttl,
                 }
             }
+            QueryType::TXT => {
+                let end = buffer.pos() + data_len as usize;
+                let mut data = Vec::new();
+                while buffer.pos() < end {
+                    let len = buffer.read()? as usize;
+                    let text = buffer.get_range(buffer.pos(), len)?;
+                    data.push(String::from_utf8_lossy(text).into_owned());
+                    buffer.step(len)?;
+                }
+
+                Self::TXT {
+                    domain,
+                    class,
+                    data,
+                    ttl,
+                }
+            }
             QueryType::AAAA => {
                 let raw_addr1 = buffer.read_u32()?;
                 let raw_addr2 = buffer.read_u32()?;
@@ -566,22 +1092,48 @@ impl DnsRecord {
                 let raw_addr4 = buffer.read_u32()?;
                 let addr = Ipv6Addr::new(
                     ((raw_addr1 >> 16) & 0xFFFF) as u16,
-                    ((raw_addr1 >> 0) & 0xFFFF) as u16,
+                    (raw_addr1 & 0xFFFF) as u16,
                     ((raw_addr2 >> 16) & 0xFFFF) as u16,
-                    ((raw_addr2 >> 0) & 0xFFFF) as u16,
+                    (raw_addr2 & 0xFFFF) as u16,
                     ((raw_addr3 >> 16) & 0xFFFF) as u16,
-                    ((raw_addr3 >> 0) & 0xFFFF) as u16,
+                    (raw_addr3 & 0xFFFF) as u16,
                     ((raw_addr4 >> 16) & 0xFFFF) as u16,
-                    ((raw_addr4 >> 0) & 0xFFFF) as u16,
+                    (raw_addr4 & 0xFFFF) as u16,
                 );
 
-                Self::AAAA { domain, addr, ttl }
+                Self::AAAA {
+                    domain,
+                    class,
+                    addr,
+                    ttl,
+                }
+            }
+            QueryType::OPT => {
+                let end = buffer.pos() + data_len as usize;
+                let mut options = Vec::new();
+                while buffer.pos() < end {
+                    let code = buffer.read_u16()?;
+                    let len = buffer.read_u16()? as usize;
+                    let data = buffer.get_range(buffer.pos(), len)?.to_vec();
+                    buffer.step(len)?;
+
+                    options.push(EdnsOption { code, data });
+                }
+
+                Self::OPT {
+                    packet_len: class_num,
+                    flags: ttl,
+                    options,
+                }
             }
-            QueryType::Unknown(_) => {
+            // Transfers and `ANY` are only ever asked for in questions, there are no records of
+            // their types.
+            QueryType::Unknown(_) | QueryType::IXFR | QueryType::AXFR | QueryType::ANY => {
                 buffer.step(data_len as usize)?;
 
                 Self::Unknown {
                     domain,
+                    class,
                     qtype: qtype_num,
                     data_len,
                     ttl,
@@ -592,18 +1144,19 @@ impl DnsRecord {
         Ok(record)
     }
 
-    fn write(&self, buffer: &mut BytePacketBuffer) -> Result<usize> {
+    pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<usize> {
         let start_pos = buffer.pos();
 
         match *self {
             DnsRecord::A {
                 ref domain,
+                class,
                 ref addr,
                 ttl,
             } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::A.as_num())?;
-                buffer.write_u16(1)?;
+                buffer.write_u16(class.as_num())?;
                 buffer.write_u32(ttl)?;
                 buffer.write_u16(4)?;
 
@@ -615,12 +1168,13 @@ impl DnsRecord {
             }
             DnsRecord::NS {
                 ref domain,
+                class,
                 ref host,
                 ttl,
             } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::NS.as_num())?;
-                buffer.write_u16(1)?;
+                buffer.write_u16(class.as_num())?;
                 buffer.write_u32(ttl)?;
 
                 let pos = buffer.pos();
@@ -633,12 +1187,32 @@ impl DnsRecord {
             }
             DnsRecord::CNAME {
                 ref domain,
+                class,
                 ref host,
                 ttl,
             } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::CNAME.as_num())?;
-                buffer.write_u16(1)?;
+                buffer.write_u16(class.as_num())?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(host)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::PTR {
+                ref domain,
+                class,
+                ref host,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::PTR.as_num())?;
+                buffer.write_u16(class.as_num())?;
                 buffer.write_u32(ttl)?;
 
                 let pos = buffer.pos();
@@ -649,15 +1223,47 @@ impl DnsRecord {
                 let size = buffer.pos() - (pos + 2);
                 buffer.set_u16(pos, size as u16)?;
             }
+            DnsRecord::SOA {
+                ref domain,
+                class,
+                ref m_name,
+                ref r_name,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::SOA.as_num())?;
+                buffer.write_u16(class.as_num())?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(m_name)?;
+                buffer.write_qname(r_name)?;
+                buffer.write_u32(serial)?;
+                buffer.write_u32(refresh)?;
+                buffer.write_u32(retry)?;
+                buffer.write_u32(expire)?;
+                buffer.write_u32(minimum)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
             DnsRecord::MX {
                 ref domain,
+                class,
                 priority,
                 ref host,
                 ttl,
             } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::MX.as_num())?;
-                buffer.write_u16(1)?;
+                buffer.write_u16(class.as_num())?;
                 buffer.write_u32(ttl)?;
 
                 let pos = buffer.pos();
@@ -669,14 +1275,43 @@ impl DnsRecord {
                 let size = buffer.pos() - (pos + 2);
                 buffer.set_u16(pos, size as u16)?;
             }
+            DnsRecord::TXT {
+                ref domain,
+                class,
+                ref data,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::TXT.as_num())?;
+                buffer.write_u16(class.as_num())?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                for text in data {
+                    if text.len() > 0xFF {
+                        return Err("Single TXT string exceeds 255 bytes of length".into());
+                    }
+
+                    buffer.write_u8(text.len() as u8)?;
+                    for b in text.as_bytes() {
+                        buffer.write_u8(*b)?;
+                    }
+                }
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
             DnsRecord::AAAA {
                 ref domain,
+                class,
                 addr,
                 ttl,
             } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::AAAA.as_num())?;
-                buffer.write_u16(1)?;
+                buffer.write_u16(class.as_num())?;
                 buffer.write_u32(ttl)?;
                 buffer.write_u16(16)?;
 
@@ -684,6 +1319,45 @@ impl DnsRecord {
                     buffer.write_u16(*octet)?;
                 }
             }
+            DnsRecord::OPT {
+                packet_len,
+                flags,
+                ref options,
+            } => {
+                // The root domain
+                buffer.write_u8(0)?;
+                buffer.write_u16(QueryType::OPT.as_num())?;
+                buffer.write_u16(packet_len)?;
+                buffer.write_u32(flags)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                for option in options {
+                    buffer.write_u16(option.code)?;
+                    buffer.write_u16(option.data.len() as u16)?;
+                    for b in &option.data {
+                        buffer.write_u8(*b)?;
+                    }
+                }
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            // Records without data, as used in `UPDATE` messages, can be written as they are.
+            DnsRecord::Unknown {
+                ref domain,
+                class,
+                qtype,
+                data_len: 0,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(qtype)?;
+                buffer.write_u16(class.as_num())?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(0)?;
+            }
             DnsRecord::Unknown { .. } => {
                 println!("Skipping record: {:?}", self);
             }
@@ -761,9 +1435,32 @@ impl DnsPacket {
             result.resources.push(rec);
         }
 
+        read_extended_rescode(&mut result.header.rescode, &mut result.resources);
+
         Ok(result)
     }
 
+    /// The questions of the message in `buffer`, with their names exactly as they were sent.
+    /// [`DnsPacket::from_buffer`] folds them to lowercase, which is what everything but echoing
+    /// them back to the sender wants.
+    pub fn questions_as_sent(buffer: &mut BytePacketBuffer) -> Result<Vec<DnsQuestion>> {
+        buffer.seek(0)?;
+        let mut header = DnsHeader::new();
+        header.read(buffer)?;
+
+        let mut questions = Vec::new();
+        for _ in 0..header.questions {
+            let mut name = String::new();
+            buffer.read_qname_with_case(&mut name, true)?;
+            let qtype = QueryType::from_num(buffer.read_u16()?);
+            let class = Class::from_num(buffer.read_u16()?);
+
+            questions.push(DnsQuestion { name, qtype, class });
+        }
+
+        Ok(questions)
+    }
+
     pub fn write(&mut self, buffer: &mut BytePacketBuffer) -> Result<()> {
         self.header.questions = self.questions.len() as u16;
         self.header.answers = self.answers.len() as u16;
@@ -784,8 +1481,33 @@ impl DnsPacket {
             rec.write(buffer)?;
         }
 
+        if self.header.rescode.is_extended() && self.get_opt().is_none() {
+            return Err(format!(
+                "Response code {:?} can't be sent without an OPT record",
+                self.header.rescode
+            )
+            .into());
+        }
+
         for rec in &self.resources {
-            rec.write(buffer)?;
+            match rec {
+                DnsRecord::OPT {
+                    packet_len,
+                    flags,
+                    options,
+                } => {
+                    let upper = (self.header.rescode.as_num() >> 4) as u32;
+                    DnsRecord::OPT {
+                        packet_len: *packet_len,
+                        flags: (flags & 0x00FF_FFFF) | (upper << 24),
+                        options: options.clone(),
+                    }
+                    .write(buffer)?;
+                }
+                _ => {
+                    rec.write(buffer)?;
+                }
+            }
         }
 
         Ok(())
@@ -795,18 +1517,53 @@ impl DnsPacket {
     /// we get multiple IP's for a single name, it doesn't matter which one we
     /// choose, so in those cases we can now pick one at random.
     pub fn get_random_a(&self) -> Option<Ipv4Addr> {
+        let addrs = self.get_all_a();
+        let idx = random::random_below(addrs.len() as u32).unwrap_or(0);
+
+        addrs.get(idx as usize).copied()
+    }
+
+    /// All `AAAA` record addresses in the answer section.
+    pub fn get_all_aaaa(&self) -> Vec<Ipv6Addr> {
+        self.answers
+            .iter()
+            .filter_map(|record| match record {
+                DnsRecord::AAAA { addr, .. } => Some(*addr),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The `OPT` pseudo-record from the additional section, present if the sender supports EDNS.
+    pub fn get_opt(&self) -> Option<&DnsRecord> {
+        self.resources
+            .iter()
+            .find(|record| matches!(record, DnsRecord::OPT { .. }))
+    }
+
+    /// All `A` record addresses in the answer section.
+    pub fn get_all_a(&self) -> Vec<Ipv4Addr> {
         self.answers
             .iter()
             .filter_map(|record| match record {
                 DnsRecord::A { addr, .. } => Some(*addr),
                 _ => None,
             })
-            .next()
+            .collect()
     }
 
     /// A helper function which returns an iterator over all name servers in the
     /// authorities section, represented as (domain, host) tuples.
-    fn get_ns<'a>(&'a self, qname: &'a str) -> impl Iterator<Item = (&'a str, &'a str)> {
+    ///
+    /// `zone` is the zone cut of the server which sent the packet. A referral
+    /// is only usable if it delegates to a zone which is a parent of `qname`
+    /// and lies strictly below `zone`, anything else is either a step
+    /// backwards or an attempt to claim authority for an unrelated domain.
+    fn get_ns<'a>(
+        &'a self,
+        qname: &'a str,
+        zone: &'a str,
+    ) -> impl Iterator<Item = (&'a str, &'a str)> {
         self.authorities
             .iter()
             // In practice, these are always `NS` records in well formed
@@ -817,42 +1574,179 @@ impl DnsPacket {
                 _ => None,
             })
             // Discard servers which aren't authoritative to our query
-            .filter(move |(domain, _)| qname.ends_with(*domain))
+            .filter(move |(domain, _)| in_bailiwick(qname, domain))
+            // Discard delegations which don't take us further down the tree
+            .filter(move |(domain, _)| *domain != zone && in_bailiwick(domain, zone))
     }
 
     /// We'll use the fact that name servers often bundle the corresponding `A`
-    /// records when replying to an `NS` query to implement a function that
-    /// returns the actual IP for an NS record if possible.
-    pub fn get_resolved_ns(&self, qname: &str) -> Option<Ipv4Addr> {
+    /// and `AAAA` records when replying to an `NS` query to implement a
+    /// function that returns the actual IP for an NS record if possible.
+    ///
+    /// Glue is only trusted when it's in-bailiwick for `zone`, since the
+    /// server which sent it has no authority over addresses outside of it.
+    pub fn get_resolved_ns(&self, qname: &str, zone: &str) -> Option<IpAddr> {
+        // Since we just want the first valid record, we can just pick the first
+        // entry.
+        self.resolved_ns(qname, zone).next()
+    }
+
+    /// Like `get_resolved_ns`, but returns the addresses of every name server
+    /// that came with glue, so that the caller has somewhere to go if the first
+    /// one doesn't answer.
+    pub fn get_all_resolved_ns(&self, qname: &str, zone: &str) -> Vec<IpAddr> {
+        let mut addrs: Vec<IpAddr> = Vec::new();
+        for addr in self.resolved_ns(qname, zone) {
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+
+        addrs
+    }
+
+    fn resolved_ns<'a>(
+        &'a self,
+        qname: &'a str,
+        zone: &'a str,
+    ) -> impl Iterator<Item = IpAddr> + 'a {
         // Get an iterator over the nameservers in the authorities section
-        self.get_ns(qname)
-            // Now we need to look for a matching `A` record in the additional
-            // section. Since we just want the first valid record, we can just
-            // build a stream of matching records.
-            .flat_map(|(_, host)| {
+        self.get_ns(qname, zone)
+            // Now we need to look for matching `A` or `AAAA` records in the
+            // additional section, building a stream of matching records.
+            .flat_map(move |(_, host)| {
                 self.resources
                     .iter()
-                    // Filter for `A` records where the domain matches the host
-                    // of the `NS` record that we are currently processing
-                    .filter_map(move |record| match record {
-                        DnsRecord::A { domain, addr, .. } if domain == host => Some(addr),
+                    // Filter for address records where the domain matches the
+                    // host of the `NS` record that we are currently processing
+                    .filter(move |record| {
+                        record.domain() == host && in_bailiwick(record.domain(), zone)
+                    })
+                    .filter_map(|record| match record {
+                        DnsRecord::A { addr, .. } => Some(IpAddr::V4(*addr)),
+                        DnsRecord::AAAA { addr, .. } => Some(IpAddr::V6(*addr)),
                         _ => None,
                     })
             })
-            .map(|addr| *addr)
-            // Finally, pick the first valid entry
-            .next()
     }
 
     /// However, not all name servers are that nice. In certain cases there
     /// won't be any `A` records in the additional section, and we'll have to
     /// perform *another* lookup in the midst. For this, we introduce a method
     /// for returning the host name of an appropriate name server.
-    pub fn get_unresolved_ns<'a>(&'a self, qname: &'a str) -> Option<&'a str> {
+    pub fn get_unresolved_ns<'a>(&'a self, qname: &'a str, zone: &'a str) -> Option<&'a str> {
         // Get an iterator over the nameservers in the authorities section
-        self.get_ns(qname)
+        self.get_ns(qname, zone)
             .map(|(_, host)| host)
             // Pick the first valid entry
             .next()
     }
+
+    /// The host names of every name server in a referral, in the order they
+    /// appear in the packet.
+    pub fn get_all_unresolved_ns<'a>(&'a self, qname: &'a str, zone: &'a str) -> Vec<&'a str> {
+        self.get_ns(qname, zone).map(|(_, host)| host).collect()
+    }
+
+    /// Returns the zone that the authorities section delegates `qname` to,
+    /// provided that the referral passes the same checks as `get_ns`.
+    pub fn get_delegation<'a>(&'a self, qname: &'a str, zone: &'a str) -> Option<&'a str> {
+        self.get_ns(qname, zone).map(|(domain, _)| domain).next()
+    }
+
+    /// Drop every record which the server that sent the packet, being
+    /// authoritative for `zone`, has no business telling us about. This has to
+    /// happen before the packet is handed on to anyone, since out-of-zone data
+    /// is the classic vehicle for cache poisoning.
+    pub fn sanitize(&mut self, zone: &str) {
+        let in_zone = |record: &DnsRecord| in_bailiwick(record.domain(), zone);
+
+        self.answers.retain(in_zone);
+        self.authorities.retain(in_zone);
+        self.resources.retain(in_zone);
+    }
+}
+
+/// An `UPDATE` message as laid out in
+/// [RFC2136](https://datatracker.ietf.org/doc/html/rfc2136#section-2), which reuses the sections
+/// of a regular message under different names.
+#[derive(Debug, Clone)]
+pub struct UpdatePacket {
+    pub header: DnsHeader,
+    /// The zone to update, in place of the question section.
+    pub zones: Vec<DnsQuestion>,
+    /// Conditions the zone has to meet for the update to go ahead, in place of the answers.
+    ///
+    /// What they mean depends on their class. Records without data come as
+    /// [`DnsRecord::Unknown`] of their type.
+    pub prerequisites: Vec<DnsRecord>,
+    /// The changes to make, in place of the authorities, which also depend on their class.
+    pub updates: Vec<DnsRecord>,
+    pub additional: Vec<DnsRecord>,
+}
+
+impl UpdatePacket {
+    pub fn from_buffer(buffer: &mut BytePacketBuffer) -> Result<Self> {
+        let mut header = DnsHeader::new();
+        header.read(buffer)?;
+
+        let mut zones = Vec::new();
+        for _ in 0..header.questions {
+            let mut question = DnsQuestion::new("".to_string(), QueryType::Unknown(0));
+            question.read(buffer)?;
+            zones.push(question);
+        }
+
+        let mut prerequisites = Vec::new();
+        for _ in 0..header.answers {
+            prerequisites.push(DnsRecord::read(buffer)?);
+        }
+
+        let mut updates = Vec::new();
+        for _ in 0..header.authoritative_entries {
+            updates.push(DnsRecord::read(buffer)?);
+        }
+
+        let mut additional = Vec::new();
+        for _ in 0..header.resource_entries {
+            additional.push(DnsRecord::read(buffer)?);
+        }
+        read_extended_rescode(&mut header.rescode, &mut additional);
+
+        Ok(Self {
+            header,
+            zones,
+            prerequisites,
+            updates,
+            additional,
+        })
+    }
+}
+
+/// Complete `rescode`, as read from the header, with the upper 8 bits from the
+/// `OPT` record among `resources` if there is one. They're taken out of the
+/// record's flags, so that they are only ever found in one place.
+fn read_extended_rescode(rescode: &mut ResultCode, resources: &mut [DnsRecord]) {
+    for record in resources {
+        if let DnsRecord::OPT { flags, .. } = record {
+            let upper = (*flags >> 24) as u16;
+            *rescode = ResultCode::from_num((upper << 4) | rescode.as_num());
+            *flags &= 0x00FF_FFFF;
+            return;
+        }
+    }
+}
+
+/// Checks whether `name` is equal to or below `zone`, comparing whole labels
+/// so that `notexample.com` isn't considered part of `example.com`. The root
+/// zone is represented by an empty string.
+pub fn in_bailiwick(name: &str, zone: &str) -> bool {
+    if zone.is_empty() {
+        return true;
+    }
+
+    let name = name.to_lowercase();
+    let zone = zone.to_lowercase();
+
+    name == zone || name.ends_with(&format!(".{}", zone))
 }
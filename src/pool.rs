@@ -0,0 +1,84 @@
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Sender},
+        Arc, Mutex,
+    },
+    thread::Scope,
+};
+
+/// A unit of work for the pool, which may borrow anything that outlives the pool's scope.
+type Job<'env> = Box<dyn FnOnce() + Send + 'env>;
+
+/// A fixed number of threads working through jobs in the order they come in, with a limit on
+/// how many jobs may be waiting or running at any one time.
+///
+/// The threads belong to a [`std::thread::Scope`], so that jobs can borrow the state of the
+/// server rather than having to share it behind an `Arc`.
+pub struct WorkerPool<'env> {
+    jobs: Sender<Job<'env>>,
+    /// Jobs that were accepted and haven't finished yet.
+    in_flight: Arc<AtomicUsize>,
+    limit: usize,
+}
+
+impl<'env> WorkerPool<'env> {
+    /// Start `workers` threads in `scope`, accepting up to `limit` jobs at a time.
+    pub fn new<'scope>(scope: &'scope Scope<'scope, 'env>, workers: usize, limit: usize) -> Self {
+        let (jobs, queue) = mpsc::channel::<Job<'env>>();
+        let queue = Arc::new(Mutex::new(queue));
+        let in_flight = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..workers.max(1) {
+            let queue = Arc::clone(&queue);
+            let in_flight = Arc::clone(&in_flight);
+
+            scope.spawn(move || loop {
+                // The lock is only held while waiting, not while the job runs.
+                let job = match queue.lock().unwrap().recv() {
+                    Ok(job) => job,
+                    Err(_) => return,
+                };
+
+                // A job gone wrong shouldn't take the worker down with it.
+                if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                    eprintln!("A worker job panicked");
+                }
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+
+        Self {
+            jobs,
+            in_flight,
+            limit: limit.max(1),
+        }
+    }
+
+    /// Queue `job` to be run on one of the workers, unless the pool is at its limit already.
+    /// Returns whether the job was accepted.
+    pub fn try_execute(&self, job: impl FnOnce() + Send + 'env) -> bool {
+        let accepted = self
+            .in_flight
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                (count < self.limit).then_some(count + 1)
+            })
+            .is_ok();
+        if !accepted {
+            return false;
+        }
+
+        if self.jobs.send(Box::new(job)).is_err() {
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            return false;
+        }
+
+        true
+    }
+
+    /// How many jobs are waiting or running.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,123 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::RwLock,
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    notify::Notifier,
+    packet::Result,
+    zone::{serial_newer, Zone, ZoneStore},
+};
+
+/// How often master files are checked for changes.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A zone we're the primary for, served from a master file that is reloaded when it changes.
+///
+/// Dynamic updates leave the master file as the operator wrote it, comments and directives and
+/// all. The updated zone is saved to a file of its own next to it instead, see [`updates_file`].
+/// Whichever of the two has the newer serial is served after a restart.
+#[derive(Debug)]
+pub struct Primary {
+    pub origin: String,
+    pub file: PathBuf,
+    /// When the file was last modified as of the version we loaded.
+    modified: Option<SystemTime>,
+}
+
+impl Primary {
+    /// Load the zone at `origin` from `file` into `zones`, or from the dynamic updates saved
+    /// next to it if they're of a newer version.
+    pub fn load(origin: &str, file: PathBuf, zones: &RwLock<ZoneStore>) -> Result<Self> {
+        let modified = fs::metadata(&file).and_then(|meta| meta.modified()).ok();
+        let mut zone = Zone::load(origin, &file)?;
+
+        let updated = updates_file(&file);
+        if updated.exists() {
+            match Zone::load(origin, &updated) {
+                Ok(updated) if serial_newer(updated.serial(), zone.serial()) => zone = updated,
+                Ok(_) => {}
+                Err(e) => eprintln!("Unable to load dynamic updates of zone {}: {}", origin, e),
+            }
+        }
+
+        let primary = Self {
+            origin: zone.origin.clone(),
+            file,
+            modified,
+        };
+        zones.write().unwrap().insert(zone);
+
+        Ok(primary)
+    }
+
+    /// Reload the zone if its file changed since we last loaded it, and let its secondaries
+    /// know.
+    ///
+    /// A file that fails to load leaves the version we have in place. One that loads replaces it,
+    /// dynamic updates included, so edits have to carry them over to keep them.
+    pub fn reload_if_changed(&mut self, zones: &RwLock<ZoneStore>, notifier: &Notifier) {
+        let modified = fs::metadata(&self.file)
+            .and_then(|meta| meta.modified())
+            .ok();
+        if modified == self.modified {
+            return;
+        }
+        self.modified = modified;
+
+        match Zone::load(&self.origin, &self.file) {
+            Ok(zone) => {
+                let old = zones.read().unwrap().get(&self.origin);
+                println!(
+                    "Reloaded zone {} with serial {} from {}",
+                    zone.origin,
+                    zone.serial(),
+                    self.file.display()
+                );
+                if old.is_some_and(|old| !serial_newer(zone.serial(), old.serial())) {
+                    eprintln!(
+                        "Serial of zone {} wasn't increased, secondaries won't pick up the change",
+                        self.origin
+                    );
+                }
+
+                zones.write().unwrap().insert(zone);
+                notifier.zone_changed(&self.origin);
+            }
+            Err(e) => eprintln!("Unable to reload zone {}: {}", self.origin, e),
+        }
+    }
+}
+
+/// Where the zone with its master file at `file` is saved after dynamic updates: the same path
+/// with `.dyn` appended.
+pub fn updates_file(file: &Path) -> PathBuf {
+    let mut path = file.as_os_str().to_owned();
+    path.push(".dyn");
+    PathBuf::from(path)
+}
+
+/// Watch the files of the `primaries` for changes, forever. The secondaries are notified right
+/// away, in case they missed the latest version while we were down.
+pub fn run(mut primaries: Vec<Primary>, zones: &RwLock<ZoneStore>, notifier: &Notifier) {
+    for primary in &primaries {
+        if zones.read().unwrap().get(&primary.origin).is_some() {
+            notifier.zone_changed(&primary.origin);
+        }
+    }
+
+    if primaries.is_empty() {
+        return;
+    }
+
+    loop {
+        thread::sleep(CHECK_INTERVAL);
+
+        for primary in primaries.iter_mut() {
+            primary.reload_if_changed(zones, notifier);
+        }
+    }
+}
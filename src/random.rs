@@ -0,0 +1,39 @@
+use std::{fs::File, io::Read};
+
+use crate::packet::Result;
+
+/// Fill `buf` with bytes from the kernel's CSPRNG.
+///
+/// Query ids and other values that an off-path attacker must not be able to guess are drawn from
+/// here, so a predictable generator won't do.
+pub fn fill(buf: &mut [u8]) -> Result<()> {
+    File::open("/dev/urandom")?.read_exact(buf)?;
+
+    Ok(())
+}
+
+/// A random `u16`, e.g. for use as a query id.
+pub fn random_u16() -> Result<u16> {
+    let mut buf = [0; 2];
+    fill(&mut buf)?;
+
+    Ok(u16::from_be_bytes(buf))
+}
+
+/// A random `u32`.
+pub fn random_u32() -> Result<u32> {
+    let mut buf = [0; 4];
+    fill(&mut buf)?;
+
+    Ok(u32::from_be_bytes(buf))
+}
+
+/// A random number in `0..n`. The modulo bias is negligible for the small ranges this is used
+/// with.
+pub fn random_below(n: u32) -> Result<u32> {
+    if n == 0 {
+        return Err("Empty range".into());
+    }
+
+    Ok(random_u32()? % n)
+}
@@ -0,0 +1,888 @@
+use std::{
+    error::Error,
+    fmt,
+    future::Future,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        mpsc::{self, Sender},
+        Arc, Mutex, OnceLock,
+    },
+    task::{Context, Poll, Waker},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    cache::{Cache, CacheConfig},
+    coalesce::Coalescer,
+    packet::{DnsPacket, DnsQuestion, DnsRecord, EdnsOption, QueryType, Result, ResultCode},
+    rtt::RttTable,
+    upstream::{self, AddressFamily, UpstreamConfig, UpstreamError},
+};
+
+/// The IPv4 and IPv6 addresses of the root servers, *a.root-servers.net*
+/// through *m.root-servers.net*.
+const ROOT_SERVERS: [IpAddr; 26] = [
+    IpAddr::V4(Ipv4Addr::new(198, 41, 0, 4)),
+    IpAddr::V4(Ipv4Addr::new(170, 247, 170, 2)),
+    IpAddr::V4(Ipv4Addr::new(192, 33, 4, 12)),
+    IpAddr::V4(Ipv4Addr::new(199, 7, 91, 13)),
+    IpAddr::V4(Ipv4Addr::new(192, 203, 230, 10)),
+    IpAddr::V4(Ipv4Addr::new(192, 5, 5, 241)),
+    IpAddr::V4(Ipv4Addr::new(192, 112, 36, 4)),
+    IpAddr::V4(Ipv4Addr::new(198, 97, 190, 53)),
+    IpAddr::V4(Ipv4Addr::new(192, 36, 148, 17)),
+    IpAddr::V4(Ipv4Addr::new(192, 58, 128, 30)),
+    IpAddr::V4(Ipv4Addr::new(193, 0, 14, 129)),
+    IpAddr::V4(Ipv4Addr::new(199, 7, 83, 42)),
+    IpAddr::V4(Ipv4Addr::new(202, 12, 27, 33)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x503, 0xba3e, 0, 0, 0, 0x2, 0x30)),
+    IpAddr::V6(Ipv6Addr::new(0x2801, 0x1b8, 0x10, 0, 0, 0, 0, 0xb)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0x2, 0, 0, 0, 0, 0xc)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0x2d, 0, 0, 0, 0, 0xd)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0xa8, 0, 0, 0, 0, 0xe)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0x2f, 0, 0, 0, 0, 0xf)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0x12, 0, 0, 0, 0, 0xd0d)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0x1, 0, 0, 0, 0, 0x53)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x7fe, 0, 0, 0, 0, 0, 0x53)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x503, 0xc27, 0, 0, 0, 0x2, 0x30)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x7fd, 0, 0, 0, 0, 0, 0x1)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0x9f, 0, 0, 0, 0, 0x42)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0xdc3, 0, 0, 0, 0, 0, 0x35)),
+];
+
+#[derive(Debug, Clone)]
+pub struct ResolverConfig {
+    pub upstream: UpstreamConfig,
+    pub limits: Limits,
+    pub qname_minimisation: QnameMinimisation,
+    /// Servers to pass queries on to, which do the recursion for us. Without any, names are
+    /// resolved starting from the root.
+    pub forwarders: Vec<SocketAddr>,
+    pub cache: CacheConfig,
+    /// The servers recursion starts out with, the root servers unless told otherwise.
+    pub root_hints: Vec<IpAddr>,
+    /// The port authoritative servers are asked on.
+    pub port: u16,
+    /// How many resolutions started by [`Resolver::resolve`] run at a time, each on a thread of
+    /// its own. Any more wait their turn.
+    pub workers: usize,
+}
+
+impl Default for ResolverConfig {
+    fn default() -> Self {
+        Self {
+            upstream: UpstreamConfig::default(),
+            limits: Limits::default(),
+            qname_minimisation: QnameMinimisation::default(),
+            forwarders: Vec::new(),
+            cache: CacheConfig::default(),
+            root_hints: ROOT_SERVERS.to_vec(),
+            port: 53,
+            workers: 8,
+        }
+    }
+}
+
+/// How much of the query name is revealed to the servers along the way, see
+/// [RFC9156](https://datatracker.ietf.org/doc/html/rfc9156).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QnameMinimisation {
+    /// Every server is sent the full query name.
+    Off,
+    /// Servers are only sent one label more than the zone they're
+    /// authoritative for. If a server handles such a query badly, be it by
+    /// failing to answer, an error or `NXDOMAIN` for what's really an empty
+    /// non-terminal, the full name is sent instead.
+    #[default]
+    Relaxed,
+    /// Like `Relaxed`, but without the fallback. An `NXDOMAIN` for any
+    /// ancestor of the query name is taken at its word.
+    Strict,
+}
+
+impl FromStr for QnameMinimisation {
+    type Err = Box<dyn Error>;
+
+    /// Parse `off`, `relaxed` or `strict`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(QnameMinimisation::Off),
+            "relaxed" => Ok(QnameMinimisation::Relaxed),
+            "strict" => Ok(QnameMinimisation::Strict),
+            _ => Err(format!("Unknown QNAME minimisation mode {}", s).into()),
+        }
+    }
+}
+
+/// When minimising, the first this many queries below a zone cut reveal a
+/// single label each, RFC9156 calls this `MINIMISE_ONE_LAB`.
+const MINIMISE_ONE_LAB: usize = 4;
+/// Beyond `MINIMISE_ONE_LAB`, labels are revealed in larger steps so that no
+/// more than this many minimised queries are made in total, RFC9156 calls
+/// this `MAX_MINIMISE_COUNT`.
+const MAX_MINIMISE_COUNT: usize = 10;
+
+/// Keeps track of how much of the query name has been revealed to the
+/// servers of the current zone.
+struct Minimiser<'a> {
+    labels: Vec<&'a str>,
+    enabled: bool,
+    /// Number of trailing labels of the query name known not to be a zone cut
+    /// below the current zone.
+    revealed: usize,
+    queries: usize,
+}
+
+impl<'a> Minimiser<'a> {
+    fn new(qname: &'a str, mode: QnameMinimisation) -> Self {
+        Self {
+            labels: qname.split('.').filter(|label| !label.is_empty()).collect(),
+            enabled: mode != QnameMinimisation::Off,
+            revealed: 0,
+            queries: 0,
+        }
+    }
+
+    fn step(&self) -> usize {
+        if self.queries < MINIMISE_ONE_LAB {
+            return 1;
+        }
+
+        let hidden = self.labels.len().saturating_sub(self.revealed);
+        let left = MAX_MINIMISE_COUNT.saturating_sub(self.queries).max(1);
+
+        hidden.div_ceil(left).max(1)
+    }
+
+    /// The name to query the current servers for, or `None` if it's time to
+    /// send the full name.
+    fn next_name(&self) -> Option<String> {
+        let count = self.revealed + self.step();
+        if !self.enabled || self.queries >= MAX_MINIMISE_COUNT || count >= self.labels.len() {
+            return None;
+        }
+
+        Some(self.labels[self.labels.len() - count..].join("."))
+    }
+
+    /// The last name sent turned out not to be a zone cut.
+    fn advance(&mut self) {
+        self.revealed += self.step();
+        self.queries += 1;
+    }
+
+    /// We've been referred to the servers of `zone`.
+    fn enter_zone(&mut self, zone: &str) {
+        self.revealed = zone.split('.').filter(|label| !label.is_empty()).count();
+    }
+
+    fn give_up(&mut self) {
+        self.enabled = false;
+    }
+}
+
+/// Upper bounds on the work a single client query may cause. Delegation loops and deliberately
+/// deep chains would otherwise keep us busy forever.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// Referrals followed, counted across all sub-resolutions.
+    pub max_referrals: usize,
+    /// Name servers without glue whose addresses we go off to resolve.
+    pub max_ns_resolutions: usize,
+    /// Packets sent upstream, including retries.
+    pub max_queries: usize,
+    /// `CNAME` records followed from the original name to the final answer.
+    pub max_cname_chain: usize,
+    /// Wall-clock time until we give up.
+    pub timeout: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_referrals: 30,
+            max_ns_resolutions: 8,
+            max_queries: 64,
+            max_cname_chain: 8,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// The reasons a resolution can be cut short by its budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResolveError {
+    TooManyReferrals,
+    TooManyNsResolutions,
+    TooManyQueries,
+    CnameChainTooLong,
+    DeadlineExceeded,
+}
+
+impl ResolveError {
+    /// The Extended DNS Error to report to the client. However the budget ran out, it left us
+    /// without a server to give us the answer, so these all go out as "No Reachable Authority"
+    /// with the reason attached.
+    pub fn extended_error(&self) -> EdnsOption {
+        EdnsOption::extended_error(22, &self.to_string())
+    }
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            ResolveError::TooManyReferrals => "exceeded the maximum number of referrals",
+            ResolveError::TooManyNsResolutions => {
+                "exceeded the maximum number of name server lookups"
+            }
+            ResolveError::TooManyQueries => "exceeded the maximum number of upstream queries",
+            ResolveError::CnameChainTooLong => "CNAME chain too long",
+            ResolveError::DeadlineExceeded => "resolution took too long",
+        };
+
+        write!(f, "{}", msg)
+    }
+}
+
+impl std::error::Error for ResolveError {}
+
+/// What's left of the limits for the client query currently being resolved. A single budget is
+/// shared by every sub-resolution the query causes.
+struct Budget<'a> {
+    limits: &'a Limits,
+    deadline: Instant,
+    referrals: usize,
+    ns_resolutions: usize,
+    queries: usize,
+}
+
+impl<'a> Budget<'a> {
+    fn new(limits: &'a Limits) -> Self {
+        Self {
+            limits,
+            deadline: Instant::now() + limits.timeout,
+            referrals: 0,
+            ns_resolutions: 0,
+            queries: 0,
+        }
+    }
+
+    fn spend(count: &mut usize, max: usize, err: ResolveError) -> Result<()> {
+        *count += 1;
+        if *count > max {
+            return Err(err.into());
+        }
+
+        Ok(())
+    }
+
+    fn referral(&mut self) -> Result<()> {
+        let max = self.limits.max_referrals;
+        Self::spend(&mut self.referrals, max, ResolveError::TooManyReferrals)
+    }
+
+    fn ns_resolution(&mut self) -> Result<()> {
+        let max = self.limits.max_ns_resolutions;
+        Self::spend(
+            &mut self.ns_resolutions,
+            max,
+            ResolveError::TooManyNsResolutions,
+        )
+    }
+
+    /// Charge a single upstream packet, returning the timeout clamped to what's left until the
+    /// deadline.
+    fn query(&mut self, timeout: Duration) -> Result<Duration> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(ResolveError::DeadlineExceeded.into());
+        }
+
+        let max = self.limits.max_queries;
+        Self::spend(&mut self.queries, max, ResolveError::TooManyQueries)?;
+
+        Ok(timeout.min(remaining))
+    }
+}
+
+/// What a resolution found out about a name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Answer {
+    /// The records asked for, along with any `CNAME` records leading up to them.
+    Records(Vec<DnsRecord>),
+    /// The name exists, but has no records of the type asked for. The `SOA` record of its zone
+    /// is there if the server sent one, its TTL says how long this is bound to stay the case.
+    NoData { soa: Option<DnsRecord> },
+    /// The name doesn't exist, with the `SOA` record of the closest zone if the server sent one.
+    NxDomain { soa: Option<DnsRecord> },
+}
+
+impl Answer {
+    /// Make sense of `response` to a query for `qtype`. Response codes other than `NOERROR` and
+    /// `NXDOMAIN` are failures.
+    pub fn from_response(
+        response: &DnsPacket,
+        qtype: QueryType,
+    ) -> std::result::Result<Self, LookupError> {
+        let soa = response
+            .authorities
+            .iter()
+            .find(|record| matches!(record, DnsRecord::SOA { .. }))
+            .cloned();
+
+        match response.header.rescode {
+            ResultCode::NOERROR => {
+                let found = response
+                    .answers
+                    .iter()
+                    .any(|record| record.qtype() == qtype || qtype == QueryType::ANY);
+                if found {
+                    Ok(Answer::Records(response.answers.clone()))
+                } else {
+                    Ok(Answer::NoData { soa })
+                }
+            }
+            ResultCode::NXDOMAIN => Ok(Answer::NxDomain { soa }),
+            rescode => Err(LookupError::Rcode(rescode)),
+        }
+    }
+
+    /// The records found, none for negative answers.
+    pub fn records(&self) -> &[DnsRecord] {
+        match self {
+            Answer::Records(records) => records,
+            Answer::NoData { .. } | Answer::NxDomain { .. } => &[],
+        }
+    }
+
+    /// The addresses among the records found.
+    pub fn addresses(&self) -> Vec<IpAddr> {
+        self.records()
+            .iter()
+            .filter_map(|record| match record {
+                DnsRecord::A { addr, .. } => Some(IpAddr::V4(*addr)),
+                DnsRecord::AAAA { addr, .. } => Some(IpAddr::V6(*addr)),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// Why a resolution came up without an answer. Unlike the errors it's made from, it can be handed
+/// to everyone waiting for the same answer, on whatever thread they're on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LookupError {
+    /// The resolution ran out of budget.
+    Limit(ResolveError),
+    /// The servers we needed to hear from didn't reply.
+    Upstream(UpstreamError),
+    /// A server replied with an error, such as `SERVFAIL` or `REFUSED`.
+    Rcode(ResultCode),
+    /// Anything else, such as a socket failing.
+    Other(String),
+}
+
+impl LookupError {
+    /// The Extended DNS Error to report to clients, where we know why the resolution failed.
+    pub fn extended_error(&self) -> Option<EdnsOption> {
+        match self {
+            LookupError::Limit(e) => Some(e.extended_error()),
+            // No Reachable Authority
+            LookupError::Upstream(e @ UpstreamError::Timeout { .. }) => {
+                Some(EdnsOption::extended_error(22, &e.to_string()))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl From<Box<dyn Error>> for LookupError {
+    fn from(e: Box<dyn Error>) -> Self {
+        if let Some(e) = e.downcast_ref::<ResolveError>() {
+            LookupError::Limit(*e)
+        } else if let Some(e) = e.downcast_ref::<UpstreamError>() {
+            LookupError::Upstream(e.clone())
+        } else {
+            LookupError::Other(e.to_string())
+        }
+    }
+}
+
+impl fmt::Display for LookupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LookupError::Limit(e) => write!(f, "{}", e),
+            LookupError::Upstream(e) => write!(f, "{}", e),
+            LookupError::Rcode(rescode) => write!(f, "Server replied with {:?}", rescode),
+            LookupError::Other(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for LookupError {}
+
+/// A resolver for programs to embed, answering from its cache where it can, and otherwise
+/// recursively from the root or by way of the forwarders in its configuration.
+///
+/// Clones are cheap, and share the cache, what's known about the servers and the resolutions
+/// under way: callers asking the same question at the same time wait for a single resolution.
+#[derive(Clone)]
+pub struct Resolver {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    config: ResolverConfig,
+    rtt: RttTable,
+    cache: Cache,
+    resolutions: Coalescer<(String, QueryType), std::result::Result<DnsPacket, LookupError>>,
+    /// Where `resolve` queues its work, once the workers have been started.
+    jobs: OnceLock<Sender<Job>>,
+}
+
+/// A resolution waiting for a worker.
+type Job = Box<dyn FnOnce() + Send>;
+
+impl Resolver {
+    pub fn new(config: ResolverConfig) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                cache: Cache::new(config.cache.clone()),
+                config,
+                rtt: RttTable::new(),
+                resolutions: Coalescer::new(),
+                jobs: OnceLock::new(),
+            }),
+        }
+    }
+
+    pub fn config(&self) -> &ResolverConfig {
+        &self.shared.config
+    }
+
+    /// Find the records of type `qtype` for `qname`.
+    ///
+    /// The work happens on one of the resolver's own threads, so the returned future can be
+    /// awaited on any executor, and keeps going if it's dropped before completing. There are no
+    /// more of those than `workers` in the configuration, started as they're first needed, and
+    /// resolutions beyond that wait in line.
+    pub fn resolve(&self, qname: &str, qtype: QueryType) -> Resolution {
+        let state = Arc::new(Mutex::new(ResolutionState {
+            outcome: None,
+            waker: None,
+        }));
+
+        let resolver = self.clone();
+        let qname = qname.to_string();
+        let shared = Arc::clone(&state);
+        self.execute(Box::new(move || {
+            let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+                resolver
+                    .query(&qname, qtype)
+                    .and_then(|response| Answer::from_response(&response, qtype))
+            }))
+            .unwrap_or_else(|_| Err(LookupError::Other("Resolution panicked".to_string())));
+
+            let waker = {
+                let mut state = shared.lock().unwrap();
+                state.outcome = Some(outcome);
+                state.waker.take()
+            };
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }));
+
+        Resolution { state }
+    }
+
+    /// Queue `job` for the workers, starting them first if this is the first one.
+    ///
+    /// The workers only hold on to the queue, so once the last clone of the resolver and the
+    /// last job holding one are gone, they find it closed and exit.
+    fn execute(&self, job: Job) {
+        let jobs = self.shared.jobs.get_or_init(|| {
+            let (jobs, queue) = mpsc::channel::<Job>();
+            let queue = Arc::new(Mutex::new(queue));
+
+            for _ in 0..self.shared.config.workers.max(1) {
+                let queue = Arc::clone(&queue);
+                thread::spawn(move || loop {
+                    // The lock is only held while waiting, not while the job runs.
+                    let job = match queue.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        Err(_) => return,
+                    };
+                    job();
+                });
+            }
+
+            jobs
+        });
+
+        // The workers never exit while the resolver is around to send to them.
+        jobs.send(job).unwrap();
+    }
+
+    /// Like `resolve`, but blocking, and giving back the response as it came from upstream. Its
+    /// response code is left for the caller to deal with.
+    pub fn query(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+    ) -> std::result::Result<DnsPacket, LookupError> {
+        let shared = &self.shared;
+        let qname = qname.trim_end_matches('.').to_lowercase();
+        if let Some(response) = shared.cache.get(&qname, qtype) {
+            return Ok(response);
+        }
+
+        shared.resolutions.run((qname.clone(), qtype), || {
+            let response = resolve(&qname, qtype, &shared.config, &shared.rtt)?;
+            shared.cache.insert(&qname, qtype, &response);
+
+            Ok(response)
+        })
+    }
+}
+
+struct ResolutionState {
+    outcome: Option<std::result::Result<Answer, LookupError>>,
+    waker: Option<Waker>,
+}
+
+/// A resolution under way, see [`Resolver::resolve`].
+pub struct Resolution {
+    state: Arc<Mutex<ResolutionState>>,
+}
+
+impl Future for Resolution {
+    type Output = std::result::Result<Answer, LookupError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.state.lock().unwrap();
+        match state.outcome.take() {
+            Some(outcome) => Poll::Ready(outcome),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Resolve `qname` recursively starting from the root, within the limits set out in `config`.
+/// If there are forwarders, they're asked instead.
+///
+/// If the answer is an alias into a zone the answering server wasn't authoritative for, the
+/// chain is followed. Such answers arrive without the target records since those are
+/// out-of-bailiwick and sanitized away, so we have to look them up ourselves.
+///
+/// Round trip times of the servers that are contacted along the way are tracked in `rtt`, which
+/// is also consulted whenever there's more than one server to choose from.
+pub fn resolve(
+    qname: &str,
+    qtype: QueryType,
+    config: &ResolverConfig,
+    rtt: &RttTable,
+) -> Result<DnsPacket> {
+    let mut budget = Budget::new(&config.limits);
+
+    // Forwarders follow aliases for us, so there's nothing left to do with what they say.
+    if !config.forwarders.is_empty() {
+        return upstream::query_with(
+            qname,
+            qtype,
+            &config.forwarders,
+            &config.upstream,
+            Some(rtt),
+            |timeout| budget.query(timeout),
+        );
+    }
+
+    resolve_within(qname, qtype, config, rtt, &mut budget)
+}
+
+fn resolve_within(
+    qname: &str,
+    qtype: QueryType,
+    config: &ResolverConfig,
+    rtt: &RttTable,
+    budget: &mut Budget,
+) -> Result<DnsPacket> {
+    let mut response = recursive_lookup(qname, qtype, config, rtt, budget)?;
+
+    // Each target is only looked up once. If it has no records of the type,
+    // looking again won't change that, and the answer is the alias alone.
+    let mut followed = Vec::new();
+    loop {
+        if response.header.rescode != ResultCode::NOERROR || qtype == QueryType::CNAME {
+            break;
+        }
+
+        // Walk the chain present in the answer section to find the name the
+        // records we're after should be owned by.
+        let mut target = qname.to_string();
+        let mut chain = 0;
+        while let Some(host) = response.answers.iter().find_map(|record| match record {
+            DnsRecord::CNAME { domain, host, .. } if *domain == target => Some(host),
+            _ => None,
+        }) {
+            chain += 1;
+            if chain > budget.limits.max_cname_chain {
+                return Err(ResolveError::CnameChainTooLong.into());
+            }
+
+            target = host.clone();
+        }
+
+        let answered = response
+            .answers
+            .iter()
+            .any(|record| record.domain() == target && record.qtype() == qtype);
+        if target == qname || answered || followed.contains(&target) {
+            break;
+        }
+        if followed.len() >= budget.limits.max_cname_chain {
+            return Err(ResolveError::CnameChainTooLong.into());
+        }
+
+        let next = recursive_lookup(&target, qtype, config, rtt, budget)?;
+        response.header.rescode = next.header.rescode;
+        response.answers.extend(next.answers);
+        response.authorities = next.authorities;
+        response.resources = next.resources;
+        followed.push(target);
+    }
+
+    Ok(response)
+}
+
+fn recursive_lookup(
+    qname: &str,
+    qtype: QueryType,
+    config: &ResolverConfig,
+    rtt: &RttTable,
+    budget: &mut Budget,
+) -> Result<DnsPacket> {
+    // We always start out at the root, with every root server as a candidate.
+    let mut servers = config.root_hints.clone();
+
+    // The zone cut of the servers we're currently talking to, starting from
+    // the root. Anything they tell us about names outside of it is discarded.
+    let mut zone = String::new();
+
+    let mode = config.qname_minimisation;
+    let mut minimiser = Minimiser::new(qname, mode);
+
+    // Since it might take an arbitrary number of steps, we enter a loop which
+    // is only bounded by the budget.
+    loop {
+        // Unless it's time to reveal the full name, the servers only get to
+        // see enough of it to tell us whether there's a zone cut one step
+        // further down. `A` is asked for rather than `NS`, which quite a few
+        // servers and middleboxes handle badly.
+        let minimised = minimiser.next_name();
+        let (query_name, query_type) = match &minimised {
+            Some(name) => (name.as_str(), QueryType::A),
+            None => (qname, qtype),
+        };
+
+        println!(
+            "attempting lookup of {:?} {} with ns {:?}",
+            query_type, query_name, servers
+        );
+
+        // The next step is to send the query to the servers for the zone,
+        // fastest first, moving on to the next one whenever a server fails to
+        // reply in time.
+        let targets: Vec<SocketAddr> = servers.iter().map(|ns| (*ns, config.port).into()).collect();
+        let result = upstream::query_with(
+            query_name,
+            query_type,
+            &targets,
+            &config.upstream,
+            Some(rtt),
+            |timeout| budget.query(timeout),
+        );
+        let mut response = match result {
+            Ok(response) => response,
+            Err(e)
+                if minimised.is_some()
+                    && mode == QnameMinimisation::Relaxed
+                    && !e.is::<ResolveError>() =>
+            {
+                eprintln!(
+                    "Minimised query for {} failed ({}), retrying with the full name",
+                    query_name, e
+                );
+                minimiser.give_up();
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+
+        // Strip out-of-bailiwick records before we look at anything else.
+        response.sanitize(&zone);
+
+        // Anything but a referral in response to a minimised query tells us
+        // whether to keep going down with the same servers.
+        if minimised.is_some() && response.get_delegation(qname, &zone).is_none() {
+            match response.header.rescode {
+                ResultCode::NOERROR => minimiser.advance(),
+                rescode if mode == QnameMinimisation::Strict => {
+                    return Ok(strict_failure(qname, qtype, rescode, response))
+                }
+                rescode => {
+                    eprintln!(
+                        "Minimised query for {} got {:?}, retrying with the full name",
+                        query_name, rescode
+                    );
+                    minimiser.give_up();
+                }
+            }
+
+            continue;
+        }
+
+        if minimised.is_none() {
+            // If there are entries in the answer section, and no errors, we
+            // are done!
+            if !response.answers.is_empty() && response.header.rescode == ResultCode::NOERROR {
+                return Ok(response);
+            }
+
+            // We might also get an `NXDOMAIN` reply, which is the
+            // authoritative name servers way of telling us that the neame
+            // doesn't exist.
+            if response.header.rescode == ResultCode::NXDOMAIN {
+                return Ok(response);
+            }
+        }
+
+        // Only referrals to a zone below the current one, which is also a
+        // parent of `qname`, are followed.
+        let delegation = match response.get_delegation(qname, &zone) {
+            Some(x) => x.to_string(),
+            None => return Ok(response),
+        };
+        budget.referral()?;
+
+        // Otherwise, we'll try to find the new nameservers based on `NS` and
+        // corresponding `A` or `AAAA` records in the additional section. If
+        // this yields addresses we're able to reach, we can switch name
+        // servers and retry the loop.
+        let family = config.upstream.family;
+        let resolved: Vec<IpAddr> = response
+            .get_all_resolved_ns(qname, &zone)
+            .into_iter()
+            .filter(|addr| family.allows(addr))
+            .collect();
+        if !resolved.is_empty() {
+            servers = resolved;
+            minimiser.enter_zone(&delegation);
+            zone = delegation;
+            continue;
+        }
+
+        // If not, we'll have to resolve the ip of a `NS` record. If no `NS`
+        // records exist, we'll go with what the last server told us.
+        //
+        // Here we go down the rabbit hole by starting _another_ lookup
+        // sequence in the midst of our current one. Hopefully, this will give
+        // us the IP of an appropriate name server. Should one name fail to
+        // resolve, the next one is tried. Running out of budget while doing
+        // so ends the whole resolution though.
+        let mut resolved = Vec::new();
+        for new_ns_name in response.get_all_unresolved_ns(qname, &zone) {
+            budget.ns_resolution()?;
+
+            resolved = resolve_ns_addrs(new_ns_name, config, rtt, budget)?;
+
+            if !resolved.is_empty() {
+                break;
+            }
+        }
+
+        // Finally, we restart the loop with the resolved addresses. If there
+        // are none, we again return the last result we got.
+        if resolved.is_empty() {
+            return Ok(response);
+        }
+
+        servers = resolved;
+        minimiser.enter_zone(&delegation);
+        zone = delegation;
+    }
+}
+
+/// What to make of a minimised query failing with `rescode` in strict mode. The response was to
+/// a different question, so it can't be passed on as it is: an `NXDOMAIN` for an ancestor means
+/// the full name doesn't exist either, see [RFC8020](https://datatracker.ietf.org/doc/html/rfc8020),
+/// and any other error means it couldn't be resolved.
+fn strict_failure(
+    qname: &str,
+    qtype: QueryType,
+    rescode: ResultCode,
+    response: DnsPacket,
+) -> DnsPacket {
+    let mut packet = DnsPacket::new();
+    packet.header.id = response.header.id;
+    packet.header.response = true;
+    packet
+        .questions
+        .push(DnsQuestion::new(qname.to_string(), qtype));
+
+    if rescode == ResultCode::NXDOMAIN {
+        packet.header.rescode = ResultCode::NXDOMAIN;
+        packet.authorities = response
+            .authorities
+            .into_iter()
+            .filter(|record| matches!(record, DnsRecord::SOA { .. }))
+            .collect();
+    } else {
+        packet.header.rescode = ResultCode::SERVFAIL;
+    }
+
+    packet
+}
+
+/// Look up the addresses of the name server `host`, in the preferred address
+/// family first and then in the other one if that's allowed and the first
+/// came up empty. Failing to resolve one family isn't fatal as long as the
+/// budget holds.
+fn resolve_ns_addrs(
+    host: &str,
+    config: &ResolverConfig,
+    rtt: &RttTable,
+    budget: &mut Budget,
+) -> Result<Vec<IpAddr>> {
+    let qtypes: &[QueryType] = match config.upstream.family {
+        AddressFamily::PreferIpv4 => &[QueryType::A, QueryType::AAAA],
+        AddressFamily::PreferIpv6 => &[QueryType::AAAA, QueryType::A],
+        AddressFamily::Ipv4Only => &[QueryType::A],
+        AddressFamily::Ipv6Only => &[QueryType::AAAA],
+    };
+
+    let mut addrs = Vec::new();
+    for qtype in qtypes.iter().copied() {
+        if !addrs.is_empty() {
+            break;
+        }
+
+        match resolve_within(host, qtype, config, rtt, budget) {
+            Ok(response) => {
+                addrs.extend(response.get_all_a().into_iter().map(IpAddr::V4));
+                addrs.extend(response.get_all_aaaa().into_iter().map(IpAddr::V6));
+            }
+            Err(e) if e.is::<ResolveError>() => return Err(e),
+            Err(e) => eprintln!("Failed to resolve name server {} {:?}: {}", host, qtype, e),
+        }
+    }
+
+    Ok(addrs)
+}
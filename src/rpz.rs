@@ -0,0 +1,544 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use crate::{
+    acl::Network,
+    packet::{DnsPacket, DnsRecord, EdnsOption, QueryType, ResultCode},
+    resolver::{LookupError, Resolver},
+    zone::{Zone, ZoneStore},
+};
+
+/// How many of the servers of a zone get their addresses looked up for `rpz-nsip` triggers.
+const MAX_NS_LOOKUPS: usize = 4;
+
+/// What set off a policy. Within a policy zone, they take precedence in this order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    /// The address of the client, from names under `rpz-client-ip`.
+    ClientIp,
+    /// The name asked about, or an alias for it in the answer.
+    Qname,
+    /// An address in the answer, from names under `rpz-ip`.
+    ResponseIp,
+    /// The name of a server for the zone the name is in, from names under `rpz-nsdname`.
+    NsDname,
+    /// The address of a server for the zone the name is in, from names under `rpz-nsip`.
+    NsIp,
+}
+
+/// What's done about a query that set off a trigger, as told by the records at the trigger.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// `CNAME .`, the name doesn't exist.
+    NxDomain,
+    /// `CNAME *.`, the name exists but has no records.
+    NoData,
+    /// `CNAME rpz-passthru.`, answer as usual, whatever the policies after this one say.
+    Passthru,
+    /// `CNAME rpz-drop.`, don't answer at all.
+    Drop,
+    /// Any other records, which are answered as if they were the name's own. A `CNAME` rewrites
+    /// the query to its target, where a leading `*` stands for the name asked about.
+    LocalData(Vec<DnsRecord>),
+}
+
+/// Name triggers, for names or whole subtrees with `*.` in front.
+#[derive(Debug, Default)]
+struct Names {
+    exact: HashMap<String, Action>,
+    /// Actions for the names below these.
+    below: HashMap<String, Action>,
+}
+
+impl Names {
+    fn insert(&mut self, name: &str, action: Action) {
+        match name.strip_prefix("*.").or((name == "*").then_some("")) {
+            Some(parent) => self.below.insert(parent.to_string(), action),
+            None => self.exact.insert(name.to_string(), action),
+        };
+    }
+
+    /// The action for `name`. The name itself beats wildcards, and closer wildcards the ones
+    /// further up.
+    fn find(&self, name: &str) -> Option<&Action> {
+        if let Some(action) = self.exact.get(name) {
+            return Some(action);
+        }
+
+        let mut parent = name;
+        while let Some((_, rest)) = parent.split_once('.') {
+            if let Some(action) = self.below.get(rest) {
+                return Some(action);
+            }
+            parent = rest;
+        }
+
+        self.below.get("")
+    }
+
+    fn len(&self) -> usize {
+        self.exact.len() + self.below.len()
+    }
+}
+
+/// Address triggers, for whole networks.
+#[derive(Debug, Default)]
+struct Networks(Vec<(Network, Action)>);
+
+impl Networks {
+    /// The action for `addr`, from the most specific network it's in.
+    fn find(&self, addr: &IpAddr) -> Option<&Action> {
+        self.0
+            .iter()
+            .filter(|(network, _)| network.contains(addr))
+            .max_by_key(|(network, _)| network.prefix_len())
+            .map(|(_, action)| action)
+    }
+}
+
+/// The triggers and actions of a single Response Policy Zone, made out of its records.
+///
+/// Each name in the zone, relative to its origin, is a trigger: a name to rewrite answers for,
+/// with `*.` in front for the names below it, or one that ends in `rpz-client-ip`, `rpz-ip`,
+/// `rpz-nsdname` or `rpz-nsip` for the others. Networks are written as the prefix length followed
+/// by the address the wrong way round, like `24.0.2.0.192` and `48.zz.db8.2001`, where `zz`
+/// stands for `::`. Actions we don't know about, like `rpz-tcp-only`, are skipped.
+#[derive(Debug, Default)]
+pub struct Policy {
+    pub origin: String,
+    serial: u32,
+    /// The `SOA` record that goes with negative answers we make up.
+    soa: Option<DnsRecord>,
+    client_ips: Networks,
+    qnames: Names,
+    response_ips: Networks,
+    nsdnames: Names,
+    nsips: Networks,
+}
+
+impl Policy {
+    pub fn new(zone: &Zone) -> Self {
+        let mut policy = Self {
+            origin: zone.origin.clone(),
+            serial: zone.serial(),
+            soa: zone.negative_soa(),
+            ..Self::default()
+        };
+
+        let mut owners: HashMap<&str, Vec<DnsRecord>> = HashMap::new();
+        for record in zone.records() {
+            owners
+                .entry(record.domain())
+                .or_default()
+                .push(record.clone());
+        }
+
+        // The records at the apex are only there to make it a zone.
+        let suffix = format!(".{}", zone.origin);
+        for (owner, records) in owners {
+            let Some(name) = owner.strip_suffix(&suffix) else {
+                continue;
+            };
+            let Some(action) = action_of(records) else {
+                eprintln!("Skipping unsupported action at {}", owner);
+                continue;
+            };
+
+            let (name, networks) = match name.rsplit_once('.') {
+                Some((name, "rpz-client-ip")) => (name, &mut policy.client_ips),
+                Some((name, "rpz-ip")) => (name, &mut policy.response_ips),
+                Some((name, "rpz-nsip")) => (name, &mut policy.nsips),
+                Some((name, "rpz-nsdname")) => {
+                    policy.nsdnames.insert(name, action);
+                    continue;
+                }
+                _ => {
+                    policy.qnames.insert(name, action);
+                    continue;
+                }
+            };
+
+            match parse_network(name) {
+                Some(network) => networks.0.push((network, action)),
+                None => eprintln!("Skipping bad trigger {}", owner),
+            }
+        }
+
+        policy
+    }
+
+    /// The serial number of the version of the zone the policy was made of.
+    pub fn serial(&self) -> u32 {
+        self.serial
+    }
+
+    /// How many triggers there are.
+    pub fn len(&self) -> usize {
+        self.client_ips.0.len()
+            + self.qnames.len()
+            + self.response_ips.0.len()
+            + self.nsdnames.len()
+            + self.nsips.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether the servers of a zone have to be looked up to tell if the policy applies.
+    fn has_ns_triggers(&self) -> bool {
+        self.nsdnames.len() != 0 || !self.nsips.0.is_empty()
+    }
+
+    /// Whether the policy might only be set off once there's an answer.
+    fn has_response_triggers(&self) -> bool {
+        !self.response_ips.0.is_empty() || self.has_ns_triggers()
+    }
+}
+
+/// The action given by the records at a trigger, if it's one we know.
+fn action_of(records: Vec<DnsRecord>) -> Option<Action> {
+    if let [DnsRecord::CNAME { host, .. }] = records.as_slice() {
+        match host.as_str() {
+            "" => return Some(Action::NxDomain),
+            "*" => return Some(Action::NoData),
+            "rpz-passthru" => return Some(Action::Passthru),
+            "rpz-drop" => return Some(Action::Drop),
+            special if special.starts_with("rpz-") => return None,
+            _ => {}
+        }
+    }
+
+    Some(Action::LocalData(records))
+}
+
+/// The network written as the labels of an address trigger.
+fn parse_network(name: &str) -> Option<Network> {
+    let mut labels = name.split('.');
+    let prefix_len = labels.next()?.parse().ok()?;
+    let labels: Vec<&str> = labels.rev().collect();
+
+    let addr: IpAddr = if labels.len() == 4 && labels.iter().all(|l| l.parse::<u8>().is_ok()) {
+        labels.join(".").parse().ok()?
+    } else {
+        let mut addr = labels
+            .iter()
+            .map(|label| if *label == "zz" { "" } else { label })
+            .collect::<Vec<_>>()
+            .join(":");
+        if addr.starts_with(':') {
+            addr.insert(0, ':');
+        }
+        if addr.ends_with(':') {
+            addr.push(':');
+        }
+        addr.parse().ok()?
+    };
+
+    Network::new(addr, prefix_len).ok()
+}
+
+/// A policy that was set off, and how.
+#[derive(Debug, Clone)]
+pub struct Hit {
+    pub policy: Arc<Policy>,
+    pub trigger: Trigger,
+    pub action: Action,
+}
+
+impl Hit {
+    fn new(policy: &Arc<Policy>, trigger: Trigger, action: &Action) -> Self {
+        Self {
+            policy: policy.clone(),
+            trigger,
+            action: action.clone(),
+        }
+    }
+
+    /// The response to give for `qname` instead of the real one, or none at all if the query is
+    /// dropped. Rewrites to another name are followed with `resolver`, as is `rpz-passthru`.
+    pub fn rewrite(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        resolver: &Resolver,
+    ) -> Result<Option<DnsPacket>, LookupError> {
+        let mut packet = DnsPacket::new();
+
+        let error = match &self.action {
+            Action::Passthru => return resolver.query(qname, qtype).map(Some),
+            Action::Drop => return Ok(None),
+            Action::NxDomain | Action::NoData => {
+                if self.action == Action::NxDomain {
+                    packet.header.rescode = ResultCode::NXDOMAIN;
+                }
+                packet.authorities.extend(self.policy.soa.clone());
+
+                // Blocked
+                EdnsOption::extended_error(15, &format!("Blocked by {}", self.policy.origin))
+            }
+            Action::LocalData(records) => {
+                for record in records {
+                    let mut record = record.clone();
+                    record.set_domain(qname);
+
+                    let target = match &mut record {
+                        DnsRecord::CNAME { host, .. } => {
+                            if let Some(suffix) = host.strip_prefix("*.") {
+                                *host = format!("{}.{}", qname, suffix);
+                            }
+                            Some(host.clone())
+                        }
+                        _ => None,
+                    };
+
+                    match target {
+                        Some(target) if qtype != QueryType::CNAME && qtype != QueryType::ANY => {
+                            packet.answers.push(record);
+
+                            let response = resolver.query(&target, qtype)?;
+                            packet.header.rescode = response.header.rescode;
+                            packet.answers.extend(response.answers);
+                        }
+                        _ if record.qtype() == qtype || qtype == QueryType::ANY => {
+                            packet.answers.push(record)
+                        }
+                        _ => {}
+                    }
+                }
+
+                // Forged Answer
+                EdnsOption::extended_error(4, &format!("Rewritten by {}", self.policy.origin))
+            }
+        };
+
+        packet.resources.push(DnsRecord::OPT {
+            packet_len: 512,
+            flags: 0,
+            options: vec![error],
+        });
+
+        Ok(Some(packet))
+    }
+}
+
+/// The first of `policies` to be set off before resolving `qname` for a client at `client`.
+///
+/// Zone order comes before trigger precedence, so the policies after one that might still be set
+/// off by the answer can't be looked at yet. Those are left for `check_response`.
+pub fn check_query(policies: &[Arc<Policy>], client: &IpAddr, qname: &str) -> Option<Hit> {
+    for policy in policies {
+        if let Some((trigger, action)) = query_trigger(policy, client, qname) {
+            return Some(Hit::new(policy, trigger, action));
+        }
+        if policy.has_response_triggers() {
+            return None;
+        }
+    }
+
+    None
+}
+
+/// The first of `policies` to be set off by a query for `qname` from a client at `client`, or
+/// by `response`, the answer resolved for it. Each policy is checked with all of its triggers
+/// before moving on to the next. Aliases in the answer count as names asked about.
+///
+/// The servers for the zone are only looked up, with `resolver`, if a policy asks about them.
+pub fn check_response(
+    policies: &[Arc<Policy>],
+    client: &IpAddr,
+    qname: &str,
+    response: &DnsPacket,
+    resolver: &Resolver,
+) -> Option<Hit> {
+    let aliases: Vec<&str> = response
+        .answers
+        .iter()
+        .filter_map(|record| match record {
+            DnsRecord::CNAME { host, .. } => Some(host.as_str()),
+            _ => None,
+        })
+        .collect();
+    let addrs: Vec<IpAddr> = response
+        .get_all_a()
+        .into_iter()
+        .map(IpAddr::V4)
+        .chain(response.get_all_aaaa().into_iter().map(IpAddr::V6))
+        .collect();
+    let with_addrs = policies.iter().any(|policy| !policy.nsips.0.is_empty());
+    let mut servers = None;
+
+    for policy in policies {
+        let mut found = query_trigger(policy, client, qname)
+            .or_else(|| {
+                aliases
+                    .iter()
+                    .find_map(|alias| policy.qnames.find(alias))
+                    .map(|action| (Trigger::Qname, action))
+            })
+            .or_else(|| {
+                addrs
+                    .iter()
+                    .find_map(|addr| policy.response_ips.find(addr))
+                    .map(|action| (Trigger::ResponseIp, action))
+            });
+
+        if found.is_none() && policy.has_ns_triggers() {
+            let (names, addrs) =
+                servers.get_or_insert_with(|| name_servers(qname, resolver, with_addrs));
+            found = names
+                .iter()
+                .find_map(|name| policy.nsdnames.find(name))
+                .map(|action| (Trigger::NsDname, action))
+                .or_else(|| {
+                    addrs
+                        .iter()
+                        .find_map(|addr| policy.nsips.find(addr))
+                        .map(|action| (Trigger::NsIp, action))
+                });
+        }
+
+        if let Some((trigger, action)) = found {
+            return Some(Hit::new(policy, trigger, action));
+        }
+    }
+
+    None
+}
+
+/// What sets off `policy` before there's an answer, the client's address beating the name.
+fn query_trigger<'a>(
+    policy: &'a Policy,
+    client: &IpAddr,
+    qname: &str,
+) -> Option<(Trigger, &'a Action)> {
+    policy
+        .client_ips
+        .find(client)
+        .map(|action| (Trigger::ClientIp, action))
+        .or_else(|| {
+            policy
+                .qnames
+                .find(qname)
+                .map(|action| (Trigger::Qname, action))
+        })
+}
+
+/// The names of the servers for the zone that `qname` is in, and if asked for, their addresses.
+/// Whatever can't be looked up is left out.
+fn name_servers(qname: &str, resolver: &Resolver, with_addrs: bool) -> (Vec<String>, Vec<IpAddr>) {
+    let mut zone = qname.to_string();
+    let mut names = Vec::new();
+
+    // A name below the apex has no servers of its own, but the SOA record that comes with the
+    // empty answer tells us where the apex is.
+    for _ in 0..2 {
+        let Ok(response) = resolver.query(&zone, QueryType::NS) else {
+            break;
+        };
+
+        names = response
+            .answers
+            .iter()
+            .filter_map(|record| match record {
+                DnsRecord::NS { domain, host, .. } if *domain == zone => Some(host.clone()),
+                _ => None,
+            })
+            .collect();
+        if !names.is_empty() {
+            break;
+        }
+
+        let apex = response.authorities.iter().find_map(|record| match record {
+            DnsRecord::SOA { domain, .. } => Some(domain.clone()),
+            _ => None,
+        });
+        match apex {
+            Some(apex) if apex != zone => zone = apex,
+            _ => break,
+        }
+    }
+
+    let mut addrs = Vec::new();
+    if with_addrs {
+        for name in names.iter().take(MAX_NS_LOOKUPS) {
+            for qtype in [QueryType::A, QueryType::AAAA] {
+                if let Ok(response) = resolver.query(name, qtype) {
+                    addrs.extend(response.get_all_a().into_iter().map(IpAddr::V4));
+                    addrs.extend(response.get_all_aaaa().into_iter().map(IpAddr::V6));
+                }
+            }
+        }
+    }
+
+    (names, addrs)
+}
+
+/// The Response Policy Zones to apply to answers we resolve, first one first. They're zones like
+/// any other, loaded from a master file or transferred from a primary, and their policies are
+/// remade whenever a new version of the zone comes in.
+#[derive(Debug, Default)]
+pub struct Policies {
+    origins: Vec<String>,
+    /// The policies made so far, by origin.
+    compiled: Mutex<HashMap<String, Arc<Policy>>>,
+}
+
+impl Policies {
+    pub fn new(origins: Vec<String>) -> Self {
+        Self {
+            origins: origins
+                .iter()
+                .map(|origin| origin.trim_end_matches('.').to_lowercase())
+                .collect(),
+            compiled: Mutex::default(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.origins.is_empty()
+    }
+
+    /// The policies as of the versions of their zones in `zones`. Zones we don't have, like
+    /// secondary zones yet to be transferred, are left out.
+    pub fn current(&self, zones: &RwLock<ZoneStore>) -> Vec<Arc<Policy>> {
+        if self.origins.is_empty() {
+            return Vec::new();
+        }
+
+        let zones = zones.read().unwrap();
+        let mut compiled = self.compiled.lock().unwrap();
+
+        let mut policies = Vec::new();
+        for origin in &self.origins {
+            let Some(zone) = zones.get(origin) else {
+                continue;
+            };
+
+            let up_to_date = compiled
+                .get(origin)
+                .filter(|policy| policy.serial == zone.serial())
+                .cloned();
+            let policy = match up_to_date {
+                Some(policy) => policy,
+                None => {
+                    let policy = Arc::new(Policy::new(&zone));
+                    println!(
+                        "Loaded policy zone {} with {} triggers",
+                        origin,
+                        policy.len()
+                    );
+                    compiled.insert(origin.clone(), policy.clone());
+                    policy
+                }
+            };
+
+            policies.push(policy);
+        }
+
+        policies
+    }
+}
@@ -0,0 +1,203 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::random;
+
+/// Servers we haven't heard from yet are given a small random estimate, so that they're tried
+/// early on and in no particular order.
+const UNKNOWN_RTT_MAX_MS: u32 = 32;
+/// The estimate is never allowed to grow past this, no matter how many timeouts pile up.
+const MAX_RTT: Duration = Duration::from_secs(10);
+/// Estimates of servers which weren't picked shrink by this factor on every selection, which
+/// eventually gives penalized servers another chance.
+const DECAY: f64 = 0.98;
+/// One in this many selections puts a random server other than the fastest first.
+const PROBE_ONE_IN: u32 = 20;
+/// Beyond this many entries the least recently used ones are evicted.
+const MAX_ENTRIES: usize = 4096;
+/// This many queries with a randomized case only answered with the case changed, without a proper
+/// reply in between, and the server is taken not to preserve case. A single forged reply is no
+/// reason to stop randomizing, and neither are lost packets.
+const CASE_FAILURES: u32 = 3;
+/// How long case randomization is skipped for a server before it's tried again.
+const CASE_FALLBACK: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    /// Smoothed round trip time.
+    srtt: Duration,
+    /// Whether `srtt` is based on at least one real reply or just a seed value.
+    measured: bool,
+    /// Queries with a randomized case answered with the case changed since the last reply.
+    case_failures: u32,
+    /// When the server was last taken not to echo the query name in the case it was sent.
+    mangles_case: Option<Instant>,
+    last_used: Instant,
+}
+
+/// Smoothed round trip times of the servers we've been talking to, used to decide which one of
+/// several name servers for a zone to ask first.
+///
+/// Estimates are updated the same way TCP does it: every reply moves the estimate an eighth of
+/// the way towards the measured time, while a timeout doubles it.
+///
+/// Alongside the estimate, the table remembers servers which have been found not to preserve
+/// the case of query names, so that 0x20 randomization can be skipped for them for a while.
+#[derive(Debug, Default)]
+pub struct RttTable {
+    entries: Mutex<HashMap<IpAddr, Entry>>,
+}
+
+impl RttTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The current estimate for `server`, if we have one.
+    pub fn srtt(&self, server: IpAddr) -> Option<Duration> {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(&server)
+            .map(|entry| entry.srtt)
+    }
+
+    /// Order `servers` by preference, fastest first. Now and then a random other server is moved
+    /// to the front instead, so that we notice when a slow server has become fast.
+    ///
+    /// `addr` maps each element of `servers` to the address its estimate is kept under.
+    pub fn order<T, F>(&self, servers: &[T], addr: F) -> Vec<T>
+    where
+        T: Copy,
+        F: Fn(&T) -> IpAddr,
+    {
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+
+        let mut ranked: Vec<(Duration, T)> = servers
+            .iter()
+            .map(|server| {
+                let entry = entries.entry(addr(server)).or_insert_with(|| Entry {
+                    srtt: Duration::from_millis(
+                        random::random_below(UNKNOWN_RTT_MAX_MS).unwrap_or(0) as u64,
+                    ),
+                    measured: false,
+                    case_failures: 0,
+                    mangles_case: None,
+                    last_used: now,
+                });
+
+                (entry.srtt, *server)
+            })
+            .collect();
+        ranked.sort_by_key(|(srtt, _)| *srtt);
+
+        let mut ordered: Vec<T> = ranked.into_iter().map(|(_, server)| server).collect();
+        if ordered.len() > 1 && random::random_below(PROBE_ONE_IN).unwrap_or(1) == 0 {
+            let idx = 1 + random::random_below(ordered.len() as u32 - 1).unwrap_or(0) as usize;
+            let probe = ordered.remove(idx);
+            ordered.insert(0, probe);
+        }
+
+        for server in ordered.iter().skip(1) {
+            if let Some(entry) = entries.get_mut(&addr(server)) {
+                entry.srtt = entry.srtt.mul_f64(DECAY);
+            }
+        }
+
+        Self::evict(&mut entries);
+
+        ordered
+    }
+
+    /// Fold a measured round trip time into the estimate for `server`. A reply also clears the
+    /// failures counted towards giving up on case randomization.
+    pub fn record_reply(&self, server: IpAddr, rtt: Duration) {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(server).or_insert(Entry {
+            srtt: rtt,
+            measured: false,
+            case_failures: 0,
+            mangles_case: None,
+            last_used: Instant::now(),
+        });
+
+        entry.srtt = if entry.measured {
+            (entry.srtt * 7 + rtt) / 8
+        } else {
+            rtt
+        };
+        entry.measured = true;
+        entry.case_failures = 0;
+        entry.last_used = Instant::now();
+
+        Self::evict(&mut entries);
+    }
+
+    /// Penalize `server` for not replying within `timeout`.
+    pub fn record_timeout(&self, server: IpAddr, timeout: Duration) {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(server).or_insert(Entry {
+            srtt: timeout,
+            measured: false,
+            case_failures: 0,
+            mangles_case: None,
+            last_used: Instant::now(),
+        });
+
+        entry.srtt = (entry.srtt * 2).max(timeout).min(MAX_RTT);
+        entry.last_used = Instant::now();
+
+        Self::evict(&mut entries);
+    }
+
+    /// Whether `server` can be trusted to echo query names in the case they were sent in. Servers
+    /// that couldn't are given another chance after a while.
+    pub fn preserves_case(&self, server: IpAddr) -> bool {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(&server)
+            .and_then(|entry| entry.mangles_case)
+            .is_none_or(|since| since.elapsed() >= CASE_FALLBACK)
+    }
+
+    /// Count a query to `server` with a randomized case that was only answered with the case
+    /// changed. Enough of them in a row and the server is taken not to preserve the case of query
+    /// names.
+    pub fn record_case_failure(&self, server: IpAddr) {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(server).or_insert(Entry {
+            srtt: Duration::ZERO,
+            measured: false,
+            case_failures: 0,
+            mangles_case: None,
+            last_used: Instant::now(),
+        });
+
+        entry.case_failures += 1;
+        if entry.case_failures >= CASE_FAILURES {
+            entry.case_failures = 0;
+            entry.mangles_case = Some(Instant::now());
+        }
+        entry.last_used = Instant::now();
+
+        Self::evict(&mut entries);
+    }
+
+    fn evict(entries: &mut HashMap<IpAddr, Entry>) {
+        if entries.len() <= MAX_ENTRIES {
+            return;
+        }
+
+        let mut by_age: Vec<Instant> = entries.values().map(|entry| entry.last_used).collect();
+        by_age.sort_unstable();
+        let cutoff = by_age[by_age.len() - MAX_ENTRIES];
+
+        entries.retain(|_, entry| entry.last_used >= cutoff);
+    }
+}
@@ -0,0 +1,278 @@
+use std::{
+    fs,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        mpsc::{Receiver, RecvTimeoutError},
+        RwLock,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{
+    notify::Notifier,
+    packet::{DnsRecord, QueryType, Result, ResultCode},
+    transfer::{self, Update},
+    upstream,
+    zone::{serial_newer, Zone, ZoneStore},
+};
+
+/// How long the primary may keep us waiting at any point of a check or transfer.
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// How soon to try again while we have no copy of the zone, and thus no timers from its `SOA`.
+const INITIAL_RETRY: Duration = Duration::from_secs(60);
+
+/// A zone we serve as a copy of what a primary server has, kept up to date following the timers
+/// in its `SOA` record as described in
+/// [RFC1034](https://datatracker.ietf.org/doc/html/rfc1034#section-4.3.5).
+#[derive(Debug)]
+pub struct Secondary {
+    pub origin: String,
+    pub primary: SocketAddr,
+    /// Where the latest copy is kept, so that it survives a restart.
+    pub file: PathBuf,
+    /// The serial of our copy, if we have one.
+    serial: Option<u32>,
+    refresh: Duration,
+    retry: Duration,
+    expire: Duration,
+    /// When the primary is next due to be asked for a newer version.
+    next_check: Instant,
+    /// When our copy becomes unusable, unless the primary is reached before then.
+    expires_at: Option<Instant>,
+}
+
+impl Secondary {
+    pub fn new(origin: &str, primary: SocketAddr, file: PathBuf) -> Self {
+        Self {
+            origin: origin.trim_end_matches('.').to_lowercase(),
+            primary,
+            file,
+            serial: None,
+            refresh: INITIAL_RETRY,
+            retry: INITIAL_RETRY,
+            expire: Duration::ZERO,
+            next_check: Instant::now(),
+            expires_at: None,
+        }
+    }
+
+    /// Pick up the copy saved by an earlier run, if there is one and it hasn't expired in the
+    /// meantime. Without a usable copy, the zone is marked as expired until the first transfer.
+    ///
+    /// Either way, the primary is checked for a newer version right away.
+    pub fn load(&mut self, zones: &RwLock<ZoneStore>) -> Result<()> {
+        zones.write().unwrap().expire(&self.origin);
+        if !self.file.exists() {
+            return Ok(());
+        }
+
+        let zone = Zone::load(&self.origin, &self.file)?;
+        self.adopt(&zone);
+
+        // The file is touched whenever the primary confirms that the copy is current, so that's
+        // when the expire timer started.
+        let age = fs::metadata(&self.file)?
+            .modified()?
+            .elapsed()
+            .unwrap_or_default();
+        match self.expire.checked_sub(age) {
+            Some(left) if !left.is_zero() => {
+                self.expires_at = Some(Instant::now() + left);
+                zones.write().unwrap().insert(zone);
+            }
+            _ => {
+                eprintln!("Saved copy of zone {} has expired", self.origin);
+                self.serial = None;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// When there's next something to be done for this zone.
+    pub fn next_event(&self) -> Instant {
+        match self.expires_at {
+            Some(expires_at) => expires_at.min(self.next_check),
+            None => self.next_check,
+        }
+    }
+
+    /// The primary let us know that the zone changed, so it's checked right away instead of
+    /// waiting for the refresh timer.
+    pub fn notified(&mut self) {
+        self.next_check = Instant::now();
+    }
+
+    /// Do whatever is due: check the primary for a newer version and transfer it if there is
+    /// one, or let our copy expire if the primary has been out of reach for too long.
+    ///
+    /// Secondaries of our own are notified of any new version.
+    pub fn tick(&mut self, zones: &RwLock<ZoneStore>, notifier: &Notifier) {
+        let now = Instant::now();
+
+        if self.next_check <= now {
+            match self.refresh(zones, notifier) {
+                Ok(()) => {
+                    self.next_check = Instant::now() + self.refresh;
+                    self.expires_at = Some(Instant::now() + self.expire);
+                    self.touch();
+                }
+                Err(e) => {
+                    eprintln!(
+                        "Unable to refresh zone {} from {}: {}",
+                        self.origin, self.primary, e
+                    );
+                    self.next_check = Instant::now() + self.retry;
+                }
+            }
+        }
+
+        if self.expires_at.is_some_and(|expires_at| expires_at <= now) {
+            eprintln!("Zone {} has expired", self.origin);
+            zones.write().unwrap().expire(&self.origin);
+            self.expires_at = None;
+            self.serial = None;
+        }
+    }
+
+    /// Ask the primary for its current serial, and transfer the zone if it's newer than ours.
+    ///
+    /// With a copy at hand, only the changes are asked for. Should that fail for whatever
+    /// reason, such as a primary that doesn't do incremental transfers, we fall back to
+    /// transferring the whole zone.
+    fn refresh(&mut self, zones: &RwLock<ZoneStore>, notifier: &Notifier) -> Result<()> {
+        let response = upstream::lookup(&self.origin, QueryType::SOA, self.primary, TIMEOUT)?;
+        if response.header.rescode != ResultCode::NOERROR {
+            return Err(format!("SOA query failed with {:?}", response.header.rescode).into());
+        }
+
+        let serial = response
+            .answers
+            .iter()
+            .find_map(|record| match record {
+                DnsRecord::SOA { domain, serial, .. } if *domain == self.origin => Some(*serial),
+                _ => None,
+            })
+            .ok_or("No SOA record in response")?;
+
+        if self
+            .serial
+            .is_some_and(|current| !serial_newer(serial, current))
+        {
+            return Ok(());
+        }
+
+        let current = match self.serial {
+            Some(_) => zones.read().unwrap().get(&self.origin),
+            None => None,
+        };
+        let incremental = current.map(|current| self.fetch_changes(&current));
+        let zone = match incremental {
+            Some(Ok(Some(zone))) => zone,
+            Some(Ok(None)) => return Ok(()),
+            Some(Err(e)) => {
+                eprintln!(
+                    "Incremental transfer of zone {} from {} failed, falling back to AXFR: {}",
+                    self.origin, self.primary, e
+                );
+                Zone::new(
+                    &self.origin,
+                    transfer::fetch_axfr(&self.origin, self.primary, TIMEOUT)?,
+                )?
+            }
+            None => Zone::new(
+                &self.origin,
+                transfer::fetch_axfr(&self.origin, self.primary, TIMEOUT)?,
+            )?,
+        };
+        println!(
+            "Transferred zone {} with serial {} from {}",
+            self.origin,
+            zone.serial(),
+            self.primary
+        );
+
+        // Failing to save the copy only costs us a transfer after a restart, which is no reason
+        // not to serve it.
+        if let Err(e) = zone.save(&self.file) {
+            eprintln!("Unable to save zone {}: {}", self.origin, e);
+        }
+
+        self.adopt(&zone);
+        zones.write().unwrap().insert(zone);
+        notifier.zone_changed(&self.origin);
+
+        Ok(())
+    }
+
+    /// Bring `current` up to date with `IXFR`, or return `None` if it already is.
+    fn fetch_changes(&self, current: &Zone) -> Result<Option<Zone>> {
+        match transfer::fetch_ixfr(current, self.primary, TIMEOUT)? {
+            Update::UpToDate => Ok(None),
+            Update::Full(records) => Zone::new(&self.origin, records).map(Some),
+            Update::Incremental(diffs) => current.apply(&diffs).map(Some),
+        }
+    }
+
+    /// Record that our copy was known to be current just now, so that a restart doesn't take the
+    /// time since it last changed for the time since the primary was last reached.
+    fn touch(&self) {
+        let touched = fs::File::options()
+            .write(true)
+            .open(&self.file)
+            .and_then(|file| file.set_modified(SystemTime::now()));
+        if let Err(e) = touched {
+            eprintln!("Unable to touch saved copy of zone {}: {}", self.origin, e);
+        }
+    }
+
+    /// Take over the serial and timers of `zone`, which has become our copy.
+    fn adopt(&mut self, zone: &Zone) {
+        if let Some(DnsRecord::SOA {
+            serial,
+            refresh,
+            retry,
+            expire,
+            ..
+        }) = zone.soa()
+        {
+            self.serial = Some(*serial);
+            self.refresh = Duration::from_secs(*refresh as u64);
+            self.retry = Duration::from_secs(*retry as u64);
+            self.expire = Duration::from_secs(*expire as u64);
+        }
+    }
+}
+
+/// Keep the `secondaries` up to date with their primaries, forever.
+///
+/// The origins of zones that primaries sent a `NOTIFY` for come in through `notifications`.
+pub fn run(
+    mut secondaries: Vec<Secondary>,
+    zones: &RwLock<ZoneStore>,
+    notifier: &Notifier,
+    notifications: Receiver<String>,
+) {
+    loop {
+        for secondary in secondaries.iter_mut() {
+            if secondary.next_event() <= Instant::now() {
+                secondary.tick(zones, notifier);
+            }
+        }
+
+        let Some(next) = secondaries.iter().map(Secondary::next_event).min() else {
+            return;
+        };
+        let wait = next.saturating_duration_since(Instant::now());
+        match notifications.recv_timeout(wait) {
+            Ok(origin) => secondaries
+                .iter_mut()
+                .filter(|secondary| secondary.origin == origin)
+                .for_each(Secondary::notified),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => thread::sleep(wait),
+        }
+    }
+}
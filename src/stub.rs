@@ -0,0 +1,311 @@
+use std::{
+    fs, io,
+    iter::once,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    hosts::Hosts,
+    packet::{Class, DnsPacket, DnsRecord, QueryType, Result, ResultCode},
+    upstream::{self, UpstreamConfig},
+};
+
+/// Where the system keeps the configuration of its resolver.
+pub const RESOLV_CONF: &str = "/etc/resolv.conf";
+/// Where the system keeps its static table of host names.
+pub const HOSTS: &str = "/etc/hosts";
+
+/// Name servers beyond this many are ignored, as they are by the C library.
+const MAXNS: usize = 3;
+
+/// The resolver configuration of the system, see resolv.conf(5). Whatever we don't know about is
+/// ignored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvConf {
+    /// The servers to ask, in order. Ports may be given, unlike for the C library.
+    pub nameservers: Vec<SocketAddr>,
+    /// Domains to try appending to names that don't end in a dot.
+    pub search: Vec<String>,
+    /// Names with at least this many dots are tried as they are before the search list is, the
+    /// others only after it.
+    pub ndots: usize,
+    /// How long to wait for a reply from a server.
+    pub timeout: Duration,
+    /// How many rounds over the servers to make before giving up.
+    pub attempts: u32,
+    /// Spread queries over the servers, rather than always asking the first one first.
+    pub rotate: bool,
+}
+
+impl Default for ResolvConf {
+    fn default() -> Self {
+        Self {
+            nameservers: vec![SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 53)],
+            search: Vec::new(),
+            ndots: 1,
+            timeout: Duration::from_secs(5),
+            attempts: 2,
+            rotate: false,
+        }
+    }
+}
+
+impl ResolvConf {
+    /// Read the configuration from `path`. A file that isn't there leaves everything at the
+    /// defaults, which have the server on the local host answer.
+    pub fn load(path: &Path) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(text) => Ok(Self::parse(&text)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn parse(text: &str) -> Self {
+        let mut conf = Self {
+            nameservers: Vec::new(),
+            ..Self::default()
+        };
+
+        for line in text.lines() {
+            let mut fields = line.split_whitespace();
+            let keyword = match fields.next() {
+                Some(keyword) if !keyword.starts_with(['#', ';']) => keyword,
+                _ => continue,
+            };
+
+            match keyword {
+                "nameserver" => {
+                    let Some(server) = fields.next().and_then(parse_nameserver) else {
+                        eprintln!("Skipping bad resolv.conf line: {}", line);
+                        continue;
+                    };
+                    if conf.nameservers.len() < MAXNS {
+                        conf.nameservers.push(server);
+                    }
+                }
+                // Whichever of `domain` and `search` comes last wins.
+                "domain" => conf.search = fields.take(1).map(normalize).collect(),
+                "search" => conf.search = fields.map(normalize).collect(),
+                "options" => {
+                    for option in fields {
+                        conf.set_option(option);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if conf.nameservers.is_empty() {
+            conf.nameservers = Self::default().nameservers;
+        }
+
+        conf
+    }
+
+    /// Apply a single entry from an `options` line, capping values the way the C library does.
+    fn set_option(&mut self, option: &str) {
+        let (name, value) = match option.split_once(':') {
+            Some((name, value)) => (name, value.parse::<u32>().ok()),
+            None => (option, None),
+        };
+
+        match (name, value) {
+            ("ndots", Some(ndots)) => self.ndots = ndots.min(15) as usize,
+            ("timeout", Some(secs)) => self.timeout = Duration::from_secs(secs.clamp(1, 30).into()),
+            ("attempts", Some(attempts)) => self.attempts = attempts.clamp(1, 5),
+            ("rotate", None) => self.rotate = true,
+            _ => {}
+        }
+    }
+}
+
+/// A name server address, with the port being optional.
+fn parse_nameserver(addr: &str) -> Option<SocketAddr> {
+    addr.parse::<SocketAddr>()
+        .ok()
+        .or_else(|| Some(SocketAddr::new(addr.parse().ok()?, 53)))
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase()
+}
+
+/// Resolves names the way the C library does: from the hosts file if they're in there, and
+/// otherwise by asking the servers from `resolv.conf`, trying the name with each of the search
+/// domains. The servers are expected to do the recursion.
+#[derive(Debug)]
+pub struct StubResolver {
+    conf: ResolvConf,
+    hosts: Hosts,
+    /// Where the next query starts in the list of servers, if they're rotated.
+    next: AtomicUsize,
+}
+
+impl StubResolver {
+    pub fn new(conf: ResolvConf, hosts: Hosts) -> Self {
+        Self {
+            conf,
+            hosts,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// A resolver configured like the one of the system.
+    pub fn from_system() -> Result<Self> {
+        Self::load(Path::new(RESOLV_CONF), Path::new(HOSTS))
+    }
+
+    /// A resolver configured by the files at `resolv_conf` and `hosts`, either of which may be
+    /// missing.
+    pub fn load(resolv_conf: &Path, hosts: &Path) -> Result<Self> {
+        let hosts = match Hosts::load(hosts) {
+            Ok(hosts) => hosts,
+            Err(e) => match e.downcast_ref::<io::Error>() {
+                Some(e) if e.kind() == io::ErrorKind::NotFound => Hosts::new(),
+                _ => return Err(e),
+            },
+        };
+
+        Ok(Self::new(ResolvConf::load(resolv_conf)?, hosts))
+    }
+
+    pub fn conf(&self) -> &ResolvConf {
+        &self.conf
+    }
+
+    /// The names to ask the servers about for `name`, in turn. A name ending in a dot is only
+    /// ever tried as it is.
+    pub fn candidates(&self, name: &str) -> Vec<String> {
+        let name = name.to_lowercase();
+        if let Some(absolute) = name.strip_suffix('.') {
+            return vec![absolute.to_string()];
+        }
+
+        let searched = self
+            .conf
+            .search
+            .iter()
+            .map(|domain| format!("{}.{}", name, domain));
+        if name.matches('.').count() >= self.conf.ndots {
+            once(name.clone()).chain(searched).collect()
+        } else {
+            searched.chain(once(name.clone())).collect()
+        }
+    }
+
+    /// Look up the records of type `qtype` for `name`.
+    ///
+    /// Addresses in the hosts file are answered from there. Otherwise the response for the first
+    /// of the candidate names with records of any kind is returned, or if there's none, the one
+    /// for the name as it was given. A candidate that can't be looked up doesn't stop the search,
+    /// its error is only returned if no other candidate got a response at all.
+    pub fn lookup(&self, name: &str, qtype: QueryType) -> Result<DnsPacket> {
+        if let Some(response) = self.hosts_response(name, qtype) {
+            return Ok(response);
+        }
+
+        let servers = self.servers();
+        let config = UpstreamConfig {
+            timeout: self.conf.timeout,
+            max_timeout: self.conf.timeout,
+            attempts: self.conf.attempts,
+            ..UpstreamConfig::default()
+        };
+
+        let as_given = normalize(name);
+        let mut fallback = None;
+        let mut error = None;
+        for candidate in self.candidates(name) {
+            let response = match upstream::query(&candidate, qtype, &servers, &config) {
+                Ok(response) => response,
+                Err(e) => {
+                    eprintln!("Unable to look up {}: {}", candidate, e);
+                    error.get_or_insert(e);
+                    continue;
+                }
+            };
+            if response.header.rescode == ResultCode::NOERROR && !response.answers.is_empty() {
+                return Ok(response);
+            }
+
+            if candidate == as_given || fallback.is_none() {
+                fallback = Some(response);
+            }
+        }
+
+        match (fallback, error) {
+            (Some(response), _) => Ok(response),
+            (None, Some(e)) => Err(e),
+            (None, None) => Err(format!("Nothing to look up for {}", name).into()),
+        }
+    }
+
+    /// The addresses of `name`, from the hosts file or else its `A` and `AAAA` records.
+    pub fn lookup_ip(&self, name: &str) -> Result<Vec<IpAddr>> {
+        let addrs = self.hosts.addresses(name);
+        if !addrs.is_empty() {
+            return Ok(addrs.to_vec());
+        }
+
+        let mut addrs = Vec::new();
+        let mut error = None;
+        for qtype in [QueryType::A, QueryType::AAAA] {
+            match self.lookup(name, qtype) {
+                Ok(response) => {
+                    addrs.extend(response.get_all_a().into_iter().map(IpAddr::V4));
+                    addrs.extend(response.get_all_aaaa().into_iter().map(IpAddr::V6));
+                }
+                Err(e) => error = Some(e),
+            }
+        }
+
+        match error {
+            Some(e) if addrs.is_empty() => Err(e),
+            _ => Ok(addrs),
+        }
+    }
+
+    /// A response made up of the addresses of `name` in the hosts file, if there are any of the
+    /// family asked for.
+    fn hosts_response(&self, name: &str, qtype: QueryType) -> Option<DnsPacket> {
+        let domain = normalize(name);
+        let mut response = DnsPacket::new();
+        response.header.response = true;
+
+        for addr in self.hosts.addresses(name) {
+            let record = match (addr, qtype) {
+                (IpAddr::V4(addr), QueryType::A) => DnsRecord::A {
+                    domain: domain.clone(),
+                    class: Class::IN,
+                    addr: *addr,
+                    ttl: 0,
+                },
+                (IpAddr::V6(addr), QueryType::AAAA) => DnsRecord::AAAA {
+                    domain: domain.clone(),
+                    class: Class::IN,
+                    addr: *addr,
+                    ttl: 0,
+                },
+                _ => continue,
+            };
+            response.answers.push(record);
+        }
+
+        (!response.answers.is_empty()).then_some(response)
+    }
+
+    /// The servers in the order to ask them this time around.
+    fn servers(&self) -> Vec<SocketAddr> {
+        let mut servers = self.conf.nameservers.clone();
+        if self.conf.rotate && !servers.is_empty() {
+            let start = self.next.fetch_add(1, Ordering::Relaxed) % servers.len();
+            servers.rotate_left(start);
+        }
+
+        servers
+    }
+}
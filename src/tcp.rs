@@ -0,0 +1,46 @@
+use std::io::{Read, Write};
+
+use crate::packet::{BytePacketBuffer, DnsPacket, Result};
+
+/// Read the raw bytes of a single message from a TCP stream, where every message is preceded by
+/// its length as a two byte integer, see
+/// [RFC1035](https://datatracker.ietf.org/doc/html/rfc1035#section-4.2.2).
+///
+/// Returns `None` if the peer closed the connection cleanly in between messages.
+pub fn read_buffer<R: Read>(stream: &mut R) -> Result<Option<BytePacketBuffer>> {
+    let mut len = [0; 2];
+    match stream.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let mut buffer = BytePacketBuffer::with_len(u16::from_be_bytes(len) as usize);
+    stream.read_exact(&mut buffer.buf)?;
+
+    Ok(Some(buffer))
+}
+
+/// Read and parse a single message from a TCP stream, see [`read_buffer`].
+pub fn read_packet<R: Read>(stream: &mut R) -> Result<Option<DnsPacket>> {
+    match read_buffer(stream)? {
+        Some(mut buffer) => DnsPacket::from_buffer(&mut buffer).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Write a single message to a TCP stream, prefixed with its length.
+pub fn write_packet<W: Write>(stream: &mut W, packet: &mut DnsPacket) -> Result<()> {
+    let mut buffer = BytePacketBuffer::with_len(BytePacketBuffer::TCP_LEN);
+    packet.write(&mut buffer)?;
+
+    // The prefix and the message go out in a single write, so they don't end up in separate
+    // segments.
+    let len = buffer.pos();
+    let mut data = Vec::with_capacity(len + 2);
+    data.extend_from_slice(&(len as u16).to_be_bytes());
+    data.extend_from_slice(buffer.get_range(0, len)?);
+    stream.write_all(&data)?;
+
+    Ok(())
+}
@@ -0,0 +1,330 @@
+use std::{
+    collections::VecDeque,
+    io::Write,
+    iter,
+    net::{SocketAddr, TcpStream},
+    time::Duration,
+};
+
+use crate::{
+    packet::{BytePacketBuffer, DnsPacket, DnsQuestion, DnsRecord, QueryType, Result, ResultCode},
+    random, tcp,
+    zone::{same_version, serial_newer, Diff, Zone},
+};
+
+/// The length of the fixed message header.
+const HEADER_LEN: usize = 12;
+
+/// A response to `request` with nothing in it yet. Only the first message of a transfer has to
+/// repeat the question.
+fn response(request: &DnsPacket, first: bool) -> DnsPacket {
+    let mut packet = DnsPacket::new();
+    packet.header.id = request.header.id;
+    packet.header.response = true;
+    packet.header.authoritative_answer = true;
+    packet.header.recursion_desired = request.header.recursion_desired;
+
+    if first {
+        packet.questions = request.questions.clone();
+    }
+
+    packet
+}
+
+/// Send `records` over `stream` in response to `request`, spread over as many messages as it
+/// takes to keep each of them within the size limit of TCP. Messages go out as soon as they're
+/// full, so the records never have to be encoded in one go.
+fn send_records<W, I>(stream: &mut W, request: &DnsPacket, records: I) -> Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = DnsRecord>,
+{
+    // Names are never compressed, so each record takes the same space no matter which message
+    // it ends up in, and can be measured on its own.
+    let mut scratch = BytePacketBuffer::with_len(BytePacketBuffer::TCP_LEN);
+    let mut message = response(request, true);
+    let mut len = HEADER_LEN;
+    for question in &message.questions {
+        scratch.pos = 0;
+        question.write(&mut scratch)?;
+        len += scratch.pos();
+    }
+
+    for record in records {
+        scratch.pos = 0;
+        let record_len = record.write(&mut scratch)?;
+
+        if len + record_len > BytePacketBuffer::TCP_LEN && !message.answers.is_empty() {
+            tcp::write_packet(stream, &mut message)?;
+            message = response(request, false);
+            len = HEADER_LEN;
+        }
+
+        len += record_len;
+        message.answers.push(record);
+    }
+
+    tcp::write_packet(stream, &mut message)
+}
+
+/// Send the whole of `zone` over `stream` in response to the `AXFR` query `request`, as laid out
+/// in [RFC5936](https://datatracker.ietf.org/doc/html/rfc5936#section-2.2). The records start
+/// and end with the zone's `SOA`.
+pub fn send_axfr<W: Write>(stream: &mut W, zone: &Zone, request: &DnsPacket) -> Result<()> {
+    let soa = zone.soa().ok_or("Zone has no SOA record")?.clone();
+    let records = iter::once(soa.clone())
+        .chain(zone.records().filter(|record| **record != soa).cloned())
+        .chain(iter::once(soa.clone()));
+
+    send_records(stream, request, records)
+}
+
+/// Send what changed in `zone` since the version the client has over `stream`, in response to
+/// the `IXFR` query `request`, as laid out in
+/// [RFC1995](https://datatracker.ietf.org/doc/html/rfc1995#section-4).
+///
+/// The client's version is given by the `SOA` in the authority section of the request. Clients
+/// that are up to date get only our `SOA` back, and those at a version older than the journal
+/// goes back get the full zone like for `AXFR`.
+pub fn send_ixfr<W: Write>(stream: &mut W, zone: &Zone, request: &DnsPacket) -> Result<()> {
+    let client_serial = request.authorities.iter().find_map(|record| match record {
+        DnsRecord::SOA { serial, .. } => Some(*serial),
+        _ => None,
+    });
+    let Some(client_serial) = client_serial else {
+        let mut packet = response(request, true);
+        packet.header.rescode = ResultCode::FORMERR;
+        return tcp::write_packet(stream, &mut packet);
+    };
+
+    let soa = zone.soa().ok_or("Zone has no SOA record")?.clone();
+    if !serial_newer(zone.serial(), client_serial) {
+        return send_records(stream, request, iter::once(soa));
+    }
+
+    let Some(diffs) = zone.journal_since(client_serial) else {
+        return send_axfr(stream, zone, request);
+    };
+
+    // Every change is sent as the old SOA followed by what was removed, then the new SOA
+    // followed by what was added, and the whole lot is wrapped in the current SOA.
+    let changes = diffs.iter().flat_map(|diff| {
+        iter::once(diff.from.clone())
+            .chain(diff.removed.iter().cloned())
+            .chain(iter::once(diff.to.clone()))
+            .chain(diff.added.iter().cloned())
+    });
+    let records = iter::once(soa.clone())
+        .chain(changes)
+        .chain(iter::once(soa.clone()));
+
+    send_records(stream, request, records)
+}
+
+/// Reads the records of a transfer one at a time, from as many messages as they come in.
+struct Reader {
+    stream: TcpStream,
+    query: DnsPacket,
+    primary: SocketAddr,
+    pending: VecDeque<DnsRecord>,
+}
+
+impl Reader {
+    /// Ask `primary` for a transfer of type `qtype` of zone `origin`. The `SOA` of our current
+    /// version goes along for incremental transfers.
+    fn start(
+        origin: &str,
+        qtype: QueryType,
+        current: Option<&DnsRecord>,
+        primary: SocketAddr,
+        timeout: Duration,
+    ) -> Result<Self> {
+        let mut stream = TcpStream::connect_timeout(&primary, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+
+        let mut query = DnsPacket::new();
+        query.header.id = random::random_u16()?;
+        query
+            .questions
+            .push(DnsQuestion::new(origin.to_string(), qtype));
+        query.authorities.extend(current.cloned());
+        tcp::write_packet(&mut stream, &mut query)?;
+
+        Ok(Self {
+            stream,
+            query,
+            primary,
+            pending: VecDeque::new(),
+        })
+    }
+
+    /// The next record of the transfer. Records of types we don't know are dropped, as there'd
+    /// be no way to serve them.
+    fn next(&mut self) -> Result<DnsRecord> {
+        loop {
+            if let Some(record) = self.pending.pop_front() {
+                if let DnsRecord::Unknown { .. } = record {
+                    eprintln!(
+                        "Dropping record of unknown type from {}: {:?}",
+                        self.primary, record
+                    );
+                    continue;
+                }
+
+                return Ok(record);
+            }
+
+            let response = tcp::read_packet(&mut self.stream)?.ok_or_else(|| {
+                format!(
+                    "{} hung up during transfer of {}",
+                    self.primary, self.query.questions[0].name
+                )
+            })?;
+            check_response(&self.query, &response, self.primary)?;
+
+            self.pending.extend(response.answers);
+        }
+    }
+
+    /// The first record of the transfer, which has to be the zone's `SOA`.
+    fn first_soa(&mut self) -> Result<DnsRecord> {
+        let origin = self.query.questions[0].name.clone();
+        match self.next()? {
+            soa @ DnsRecord::SOA { .. } if soa.domain() == origin => Ok(soa),
+            _ => Err(format!(
+                "Transfer of {} from {} didn't start with its SOA",
+                origin, self.primary
+            )
+            .into()),
+        }
+    }
+
+    /// Records up to the next `SOA`, and that `SOA`.
+    fn until_soa(&mut self) -> Result<(Vec<DnsRecord>, DnsRecord)> {
+        let mut records = Vec::new();
+        loop {
+            match self.next()? {
+                soa @ DnsRecord::SOA { .. } => return Ok((records, soa)),
+                record => records.push(record),
+            }
+        }
+    }
+}
+
+/// Fetch the whole of zone `origin` from `primary` with `AXFR`, giving up if the primary keeps us
+/// waiting for longer than `timeout` at any point.
+///
+/// The records come back in the order they were sent, starting with the `SOA` but without the
+/// copy of it that ends the transfer.
+pub fn fetch_axfr(origin: &str, primary: SocketAddr, timeout: Duration) -> Result<Vec<DnsRecord>> {
+    let mut reader = Reader::start(origin, QueryType::AXFR, None, primary, timeout)?;
+    let soa = reader.first_soa()?;
+
+    read_full(&mut reader, soa, Vec::new())
+}
+
+/// Read the rest of a full transfer that started with `soa` followed by `records`.
+fn read_full(
+    reader: &mut Reader,
+    soa: DnsRecord,
+    mut records: Vec<DnsRecord>,
+) -> Result<Vec<DnsRecord>> {
+    records.insert(0, soa.clone());
+    loop {
+        let record = reader.next()?;
+        if record == soa {
+            return Ok(records);
+        }
+        records.push(record);
+    }
+}
+
+/// What an incremental transfer brought.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Update {
+    /// We already have the latest version.
+    UpToDate,
+    /// The primary sent the whole zone, as with `AXFR`.
+    Full(Vec<DnsRecord>),
+    /// The changes from our version to the latest, in order.
+    Incremental(Vec<Diff>),
+}
+
+/// Fetch what changed in zone `current` since its version from `primary` with `IXFR`, giving up
+/// if the primary keeps us waiting for longer than `timeout` at any point.
+///
+/// Primaries that can't tell what changed send the whole zone instead, see
+/// [RFC1995](https://datatracker.ietf.org/doc/html/rfc1995#section-4).
+pub fn fetch_ixfr(current: &Zone, primary: SocketAddr, timeout: Duration) -> Result<Update> {
+    let current_soa = current.soa().ok_or("Zone has no SOA record")?;
+    let mut reader = Reader::start(
+        &current.origin,
+        QueryType::IXFR,
+        Some(current_soa),
+        primary,
+        timeout,
+    )?;
+
+    let soa = reader.first_soa()?;
+    if let DnsRecord::SOA { serial, .. } = soa {
+        if !serial_newer(serial, current.serial()) {
+            return Ok(Update::UpToDate);
+        }
+    }
+
+    // An incremental transfer goes on with the SOA of our version, anything else is a full one.
+    let second = reader.next()?;
+    if !same_version(&second, current_soa) {
+        if second == soa {
+            return Ok(Update::Full(vec![soa]));
+        }
+        return read_full(&mut reader, soa, vec![second]).map(Update::Full);
+    }
+
+    let mut diffs = Vec::new();
+    let mut from = second;
+    loop {
+        let (removed, to) = reader.until_soa()?;
+        let (added, next) = reader.until_soa()?;
+
+        let done = to == soa;
+        diffs.push(Diff {
+            from,
+            to,
+            removed,
+            added,
+        });
+
+        if done {
+            if next != soa {
+                return Err(format!("Transfer from {} didn't end with its SOA", primary).into());
+            }
+            return Ok(Update::Incremental(diffs));
+        }
+        from = next;
+    }
+}
+
+/// Make sure that `response` is one of the messages answering the transfer `query`, and that
+/// it doesn't report an error.
+fn check_response(query: &DnsPacket, response: &DnsPacket, primary: SocketAddr) -> Result<()> {
+    if !response.header.response || response.header.id != query.header.id {
+        return Err(format!("Unexpected message from {} during transfer", primary).into());
+    }
+
+    // Only the first message has to repeat the question.
+    if !response.questions.is_empty() && response.questions != query.questions {
+        return Err(format!("Response from {} is for another question", primary).into());
+    }
+
+    if response.header.rescode != ResultCode::NOERROR {
+        return Err(format!(
+            "Transfer of {} from {} failed with {:?}",
+            query.questions[0].name, primary, response.header.rescode
+        )
+        .into());
+    }
+
+    Ok(())
+}
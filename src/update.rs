@@ -0,0 +1,250 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    packet::{
+        in_bailiwick, Class, DnsPacket, DnsRecord, Opcode, QueryType, ResultCode, UpdatePacket,
+    },
+    zone::{serial_newer, serial_of, Zone},
+};
+
+/// Whether records of type `qtype` can't be added or removed, since the type only exists in
+/// questions.
+fn is_meta_type(qtype: QueryType) -> bool {
+    matches!(qtype.as_num(), 41 | 251..=255)
+}
+
+/// Whether `record` comes without any data, as records standing for a whole name or RRset do.
+fn is_empty(record: &DnsRecord) -> bool {
+    matches!(record, DnsRecord::Unknown { data_len: 0, .. })
+}
+
+/// The records of `records` owned by `name` and of type `qtype`, without their TTLs.
+fn rrset(records: &[DnsRecord], name: &str, qtype: QueryType) -> HashSet<DnsRecord> {
+    records
+        .iter()
+        .filter(|record| record.domain() == name && record.qtype() == qtype)
+        .map(|record| {
+            let mut record = record.clone();
+            record.set_ttl(0);
+            record
+        })
+        .collect()
+}
+
+/// Apply the `UPDATE` message `update` to `zone`, as laid out in
+/// [RFC2136](https://datatracker.ietf.org/doc/html/rfc2136#section-3).
+///
+/// Either all of the changes are made or none of them, and the result is the new version of the
+/// zone with its serial increased, or `None` if nothing changed. The response code to report is
+/// returned when the update can't go ahead.
+pub fn apply(zone: &Zone, update: &UpdatePacket) -> Result<Option<Zone>, ResultCode> {
+    let records: Vec<DnsRecord> = zone.records().cloned().collect();
+
+    check_prerequisites(&zone.origin, &records, &update.prerequisites)?;
+    prescan(&zone.origin, &update.updates)?;
+
+    let mut soa = zone.soa().cloned().ok_or(ResultCode::SERVFAIL)?;
+    let mut updated: Vec<DnsRecord> = records
+        .iter()
+        .filter(|record| **record != soa)
+        .cloned()
+        .collect();
+
+    let mut soa_replaced = false;
+    for change in &update.updates {
+        let name = change.domain();
+        let qtype = change.qtype();
+
+        match change.class() {
+            Class::IN if qtype == QueryType::SOA => {
+                // The SOA can't be added or removed, only replaced by one with a newer serial.
+                if name == zone.origin && serial_newer(serial_of(change), serial_of(&soa)) {
+                    soa = change.clone();
+                    soa_replaced = true;
+                }
+            }
+            Class::IN => add(&mut updated, change),
+            Class::ANY if qtype == QueryType::ANY => updated.retain(|record| {
+                record.domain() != name || (name == zone.origin && record.qtype() == QueryType::NS)
+            }),
+            Class::ANY => {
+                if name != zone.origin || qtype != QueryType::NS {
+                    updated.retain(|record| record.domain() != name || record.qtype() != qtype);
+                }
+            }
+            Class::NONE => {
+                // The last name server at the apex stays, a zone can't do without.
+                let apex_ns = updated
+                    .iter()
+                    .filter(|record| {
+                        record.domain() == zone.origin && record.qtype() == QueryType::NS
+                    })
+                    .count();
+                let last_apex_ns = name == zone.origin && qtype == QueryType::NS && apex_ns <= 1;
+
+                if qtype != QueryType::SOA && !last_apex_ns {
+                    let mut deleted = change.clone();
+                    deleted.set_class(Class::IN);
+                    updated.retain(|record| !record.same_data(&deleted));
+                }
+            }
+            _ => return Err(ResultCode::FORMERR),
+        }
+    }
+
+    let before: HashSet<&DnsRecord> = records.iter().collect();
+    let after: HashSet<&DnsRecord> = updated.iter().chain([&soa]).collect();
+    if before == after {
+        return Ok(None);
+    }
+
+    // Secondaries only pick up changes that come with a newer serial.
+    if !soa_replaced {
+        if let DnsRecord::SOA { serial, .. } = &mut soa {
+            *serial = serial.wrapping_add(1);
+        }
+    }
+    updated.push(soa);
+
+    Zone::new(&zone.origin, updated)
+        .map(Some)
+        .map_err(|_| ResultCode::SERVFAIL)
+}
+
+/// Add `record` to `records` following the rules of RFC2136 section 3.4.2.2: a `CNAME` can't
+/// share its name with other records and replaces any previous one, and a record that's already
+/// there only has its TTL updated.
+fn add(records: &mut Vec<DnsRecord>, record: &DnsRecord) {
+    let name = record.domain();
+    let is_cname = |other: &DnsRecord| other.qtype() == QueryType::CNAME;
+
+    let mut at_name = records.iter().filter(|other| other.domain() == name);
+    if is_cname(record) {
+        if at_name.any(|other| !is_cname(other)) {
+            return;
+        }
+        records.retain(|other| other.domain() != name || !is_cname(other));
+    } else if at_name.any(is_cname) {
+        return;
+    }
+
+    records.retain(|other| !other.same_data(record));
+    records.push(record.clone());
+}
+
+/// Check the prerequisites of an update against the current `records` of the zone at `origin`,
+/// following RFC2136 section 3.2.
+fn check_prerequisites(
+    origin: &str,
+    records: &[DnsRecord],
+    prerequisites: &[DnsRecord],
+) -> Result<(), ResultCode> {
+    let in_use = |name: &str| records.iter().any(|record| record.domain() == name);
+
+    // RRsets that have to exist with exactly these records, whatever their TTL.
+    let mut expected: HashMap<(String, QueryType), HashSet<DnsRecord>> = HashMap::new();
+
+    for record in prerequisites {
+        let name = record.domain();
+        let qtype = record.qtype();
+
+        if record.ttl() != 0 {
+            return Err(ResultCode::FORMERR);
+        }
+        if !in_bailiwick(name, origin) {
+            return Err(ResultCode::NOTZONE);
+        }
+
+        match record.class() {
+            Class::ANY | Class::NONE if !is_empty(record) => return Err(ResultCode::FORMERR),
+            Class::ANY if qtype == QueryType::ANY => {
+                if !in_use(name) {
+                    return Err(ResultCode::NXDOMAIN);
+                }
+            }
+            Class::ANY => {
+                if rrset(records, name, qtype).is_empty() {
+                    return Err(ResultCode::NXRRSET);
+                }
+            }
+            Class::NONE if qtype == QueryType::ANY => {
+                if in_use(name) {
+                    return Err(ResultCode::YXDOMAIN);
+                }
+            }
+            Class::NONE => {
+                if !rrset(records, name, qtype).is_empty() {
+                    return Err(ResultCode::YXRRSET);
+                }
+            }
+            Class::IN => {
+                let mut record = record.clone();
+                record.set_ttl(0);
+                expected
+                    .entry((name.to_string(), qtype))
+                    .or_default()
+                    .insert(record);
+            }
+            _ => return Err(ResultCode::FORMERR),
+        }
+    }
+
+    for ((name, qtype), rrs) in expected {
+        if rrset(records, &name, qtype) != rrs {
+            return Err(ResultCode::NXRRSET);
+        }
+    }
+
+    Ok(())
+}
+
+/// Make sure that all of `updates` are within the zone at `origin` and make sense, before any of
+/// them is applied, following RFC2136 section 3.4.1.
+fn prescan(origin: &str, updates: &[DnsRecord]) -> Result<(), ResultCode> {
+    for record in updates {
+        let qtype = record.qtype();
+
+        if !in_bailiwick(record.domain(), origin) {
+            return Err(ResultCode::NOTZONE);
+        }
+
+        match record.class() {
+            Class::IN if is_meta_type(qtype) || is_empty(record) => {
+                return Err(ResultCode::FORMERR);
+            }
+            // We'd have no way of serving records of types we don't know.
+            Class::IN if matches!(record, DnsRecord::Unknown { .. }) => {
+                return Err(ResultCode::NOTIMP);
+            }
+            Class::IN => {}
+            Class::ANY => {
+                if record.ttl() != 0
+                    || !is_empty(record)
+                    || (is_meta_type(qtype) && qtype != QueryType::ANY)
+                {
+                    return Err(ResultCode::FORMERR);
+                }
+            }
+            Class::NONE => {
+                if record.ttl() != 0 || is_meta_type(qtype) {
+                    return Err(ResultCode::FORMERR);
+                }
+            }
+            _ => return Err(ResultCode::FORMERR),
+        }
+    }
+
+    Ok(())
+}
+
+/// The response to `update`, reporting `rescode`.
+pub fn response(update: &UpdatePacket, rescode: ResultCode) -> DnsPacket {
+    let mut packet = DnsPacket::new();
+    packet.header.id = update.header.id;
+    packet.header.opcode = Opcode::UPDATE;
+    packet.header.response = true;
+    packet.header.rescode = rescode;
+    packet.questions = update.zones.clone();
+
+    packet
+}
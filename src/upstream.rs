@@ -0,0 +1,509 @@
+use std::{
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    packet::{BytePacketBuffer, DnsPacket, DnsQuestion, QueryType, Result},
+    random,
+    rtt::RttTable,
+    tcp,
+};
+
+/// Knobs for how patient we are with upstream servers.
+#[derive(Debug, Clone)]
+pub struct UpstreamConfig {
+    /// How long to wait for a reply to the first try.
+    pub timeout: Duration,
+    /// The per-try timeout is doubled after each round, up to this limit.
+    pub max_timeout: Duration,
+    /// How many rounds over the full list of servers to make before giving up.
+    pub attempts: u32,
+    /// Which address families to query servers over.
+    pub family: AddressFamily,
+    /// Randomize the case of the letters in query names and insist on getting them back
+    /// unchanged, which adds a bit of entropy per letter for a spoofer to guess. Known as "DNS
+    /// 0x20", after the bit that distinguishes upper and lower case in ASCII.
+    pub randomize_case: bool,
+    /// What to send queries over.
+    pub transport: Transport,
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_millis(800),
+            max_timeout: Duration::from_secs(4),
+            attempts: 3,
+            family: AddressFamily::default(),
+            randomize_case: false,
+            transport: Transport::default(),
+        }
+    }
+}
+
+/// How queries reach upstream servers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Transport {
+    /// Queries go out over UDP, and are asked again over TCP if the reply comes back truncated.
+    #[default]
+    Udp,
+    /// Every query goes out over a TCP connection of its own, for networks that mangle or drop
+    /// UDP.
+    Tcp,
+}
+
+/// Preference for the address family used to reach upstream servers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AddressFamily {
+    /// Try IPv4 addresses first, but fall back to IPv6.
+    #[default]
+    PreferIpv4,
+    /// Try IPv6 addresses first, but fall back to IPv4.
+    PreferIpv6,
+    /// Never use IPv6.
+    Ipv4Only,
+    /// Never use IPv4, for hosts without an IPv4 route.
+    Ipv6Only,
+}
+
+impl AddressFamily {
+    /// Whether servers at `addr` may be queried at all.
+    pub fn allows(&self, addr: &IpAddr) -> bool {
+        match self {
+            AddressFamily::Ipv4Only => addr.is_ipv4(),
+            AddressFamily::Ipv6Only => addr.is_ipv6(),
+            AddressFamily::PreferIpv4 | AddressFamily::PreferIpv6 => true,
+        }
+    }
+
+    /// Drop the servers which may not be queried and move the preferred family to the front,
+    /// keeping the order within each family.
+    pub fn arrange(&self, servers: &[SocketAddr]) -> Vec<SocketAddr> {
+        let mut servers: Vec<SocketAddr> = servers
+            .iter()
+            .filter(|server| self.allows(&server.ip()))
+            .copied()
+            .collect();
+
+        match self {
+            AddressFamily::PreferIpv4 => servers.sort_by_key(|server| server.is_ipv6()),
+            AddressFamily::PreferIpv6 => servers.sort_by_key(|server| server.is_ipv4()),
+            AddressFamily::Ipv4Only | AddressFamily::Ipv6Only => {}
+        }
+
+        servers
+    }
+}
+
+impl FromStr for AddressFamily {
+    type Err = Box<dyn std::error::Error>;
+
+    /// Parse `prefer-ipv4`, `prefer-ipv6`, `ipv4` or `ipv6`, the last two allowing nothing else.
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "prefer-ipv4" => Ok(AddressFamily::PreferIpv4),
+            "prefer-ipv6" => Ok(AddressFamily::PreferIpv6),
+            "ipv4" => Ok(AddressFamily::Ipv4Only),
+            "ipv6" => Ok(AddressFamily::Ipv6Only),
+            _ => Err(format!("Unknown address family {}", s).into()),
+        }
+    }
+}
+
+impl UpstreamConfig {
+    /// The per-try timeout to use in the given round, starting from zero.
+    fn timeout_for(&self, round: u32) -> Duration {
+        self.timeout
+            .checked_mul(1 << round.min(16))
+            .unwrap_or(self.max_timeout)
+            .min(self.max_timeout)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpstreamError {
+    /// None of the servers replied in time.
+    Timeout { qname: String, servers: usize },
+    /// There was nobody to ask in the first place.
+    NoServers { qname: String },
+    /// No reply matched the query in the case of the query name, though some did in every other
+    /// respect.
+    CaseMismatch { qname: String },
+}
+
+impl fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpstreamError::Timeout { qname, servers } => {
+                write!(f, "Timed out querying {} server(s) for {}", servers, qname)
+            }
+            UpstreamError::NoServers { qname } => write!(f, "No servers to query for {}", qname),
+            UpstreamError::CaseMismatch { qname } => {
+                write!(
+                    f,
+                    "Reply for {} didn't preserve the case of the name",
+                    qname
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for UpstreamError {}
+
+/// Query `servers` in turn until one of them replies.
+///
+/// Servers are only tried over the address families allowed by the configuration, with the
+/// preferred family first. Each round tries every server once, and the per-try timeout doubles
+/// between rounds so that a merely slow server still gets a chance to answer. If no server has
+/// replied after the configured number of rounds, an `UpstreamError::Timeout` is returned.
+pub fn query(
+    qname: &str,
+    qtype: QueryType,
+    servers: &[SocketAddr],
+    config: &UpstreamConfig,
+) -> Result<DnsPacket> {
+    query_with(qname, qtype, servers, config, None, Ok)
+}
+
+/// Like `query`, but `before_try` gets a say before every packet that's sent. It's handed the
+/// timeout the try would use and returns the one it should actually use, or an error to abort
+/// the whole query. This lets callers enforce limits that span more than a single query.
+///
+/// If an `RttTable` is given, the servers are tried fastest first and every reply or timeout is
+/// recorded in it. It's also where servers that don't preserve the case of query names are
+/// remembered, so that case randomization is only skipped for the ones that repeatedly replied
+/// with the case changed. Without a table, it's never skipped.
+pub fn query_with<F>(
+    qname: &str,
+    qtype: QueryType,
+    servers: &[SocketAddr],
+    config: &UpstreamConfig,
+    rtt: Option<&RttTable>,
+    mut before_try: F,
+) -> Result<DnsPacket>
+where
+    F: FnMut(Duration) -> Result<Duration>,
+{
+    let servers = &config.family.arrange(servers);
+    if servers.is_empty() {
+        return Err(UpstreamError::NoServers {
+            qname: qname.to_string(),
+        }
+        .into());
+    }
+
+    for round in 0..config.attempts {
+        // The order is decided afresh every round, since the timeouts of the
+        // previous one will have changed it.
+        let ordered = match rtt {
+            Some(rtt) => config.family.arrange(&rtt.order(servers, SocketAddr::ip)),
+            None => servers.to_vec(),
+        };
+
+        for server in &ordered {
+            let timeout = before_try(config.timeout_for(round))?;
+            let start = Instant::now();
+
+            let randomize_case =
+                config.randomize_case && rtt.is_none_or(|rtt| rtt.preserves_case(server.ip()));
+
+            let result = send_query(
+                qname,
+                qtype,
+                *server,
+                timeout,
+                randomize_case,
+                config.transport,
+            );
+
+            match result {
+                Ok(response) => {
+                    if let Some(rtt) = rtt {
+                        rtt.record_reply(server.ip(), start.elapsed());
+                    }
+
+                    return Ok(response);
+                }
+                Err(e) => {
+                    if let Some(rtt) = rtt {
+                        rtt.record_timeout(server.ip(), timeout);
+
+                        // Only replies with the case changed count against the server, not
+                        // timeouts and other failures that have nothing to do with case.
+                        let mismatch = matches!(
+                            e.downcast_ref::<UpstreamError>(),
+                            Some(UpstreamError::CaseMismatch { .. })
+                        );
+                        if randomize_case && mismatch {
+                            rtt.record_case_failure(server.ip());
+                        }
+                    }
+
+                    eprintln!("Query to {} failed: {}", server, e)
+                }
+            }
+        }
+    }
+
+    Err(UpstreamError::Timeout {
+        qname: qname.to_string(),
+        servers: servers.len(),
+    }
+    .into())
+}
+
+/// Send a single query for `qname` to `server` and wait up to `timeout` for the matching reply.
+///
+/// Every query goes out with a fresh random id from a freshly bound socket, so that the OS picks
+/// a new ephemeral source port each time. Together these give an off-path attacker around 32 bits
+/// to guess instead of none. A reply that comes back truncated is asked for again over TCP.
+pub fn lookup(
+    qname: &str,
+    qtype: QueryType,
+    server: SocketAddr,
+    timeout: Duration,
+) -> Result<DnsPacket> {
+    send_query(qname, qtype, server, timeout, false, Transport::Udp)
+}
+
+/// The question section starts right after the fixed size header.
+const QUESTION_OFFSET: usize = 12;
+
+/// Flip the case of every letter in `qname` at random.
+fn randomize_case(qname: &str) -> Result<String> {
+    let mut bits = vec![0; qname.len()];
+    random::fill(&mut bits)?;
+
+    Ok(qname
+        .chars()
+        .zip(bits)
+        .map(|(c, bit)| {
+            if bit & 1 == 1 {
+                c.to_ascii_uppercase()
+            } else {
+                c.to_ascii_lowercase()
+            }
+        })
+        .collect())
+}
+
+/// Does the work for `lookup`, optionally with the case of `qname` randomized. In that case a
+/// reply has to echo the name exactly. Over UDP, one that differs only in case is discarded like
+/// any other forgery, and if nothing better arrives in time the query fails with
+/// `UpstreamError::CaseMismatch`. Over TCP, where nobody else can reply, it fails straight away.
+fn send_query(
+    qname: &str,
+    qtype: QueryType,
+    server: SocketAddr,
+    timeout: Duration,
+    randomize: bool,
+    transport: Transport,
+) -> Result<DnsPacket> {
+    // Build query packet. We have to remember to set the `recursion_desired`
+    // flag.
+    let mut packet = DnsPacket::new();
+
+    packet.header.id = random::random_u16()?;
+    packet.header.questions = 1;
+    packet.header.recursion_desired = true;
+    let sent_name = if randomize {
+        randomize_case(qname)?
+    } else {
+        qname.to_string()
+    };
+    packet
+        .questions
+        .push(DnsQuestion::new(sent_name.clone(), qtype));
+
+    // Spoofed replies must not extend the time we're willing to wait, so the deadline is fixed
+    // up front and the socket timeout is shrunk to whatever remains of it on every read.
+    let deadline = Instant::now() + timeout;
+
+    let (response, mut res_buffer) = match transport {
+        Transport::Udp => {
+            let expected = randomize.then_some(sent_name.as_str());
+            let (response, res_buffer) =
+                exchange_udp(qname, &mut packet, expected, server, deadline)?;
+
+            // Whatever didn't fit is only to be had over TCP.
+            if response.header.truncated_message {
+                eprintln!("Truncated response from {}, asking again over TCP", server);
+                exchange_tcp(qname, &mut packet, server, deadline)?
+            } else {
+                (response, res_buffer)
+            }
+        }
+        Transport::Tcp => exchange_tcp(qname, &mut packet, server, deadline)?,
+    };
+
+    // Parsing folds names to lowercase, so the case is checked against the raw packet. Replies
+    // over UDP have been checked already.
+    if randomize && res_buffer.read_qname_at(QUESTION_OFFSET)? != sent_name {
+        return Err(mismatched_case(qname).into());
+    }
+
+    Ok(response)
+}
+
+/// The time left until `deadline`, or an `UpstreamError::Timeout` if there's none.
+fn remaining(qname: &str, deadline: Instant) -> Result<Duration> {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining.is_zero() {
+        return Err(timed_out(qname).into());
+    }
+
+    Ok(remaining)
+}
+
+fn timed_out(qname: &str) -> UpstreamError {
+    UpstreamError::Timeout {
+        qname: qname.to_string(),
+        servers: 1,
+    }
+}
+
+fn mismatched_case(qname: &str) -> UpstreamError {
+    UpstreamError::CaseMismatch {
+        qname: qname.to_string(),
+    }
+}
+
+/// Whether `e` is how a socket tells us that its timeout ran out.
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// Send `packet` to `server` over UDP and wait until `deadline` for the reply, which is returned
+/// along with the raw message. If `sent_name` is given, the reply has to echo the query name in
+/// exactly that case.
+fn exchange_udp(
+    qname: &str,
+    packet: &mut DnsPacket,
+    sent_name: Option<&str>,
+    server: SocketAddr,
+    deadline: Instant,
+) -> Result<(DnsPacket, BytePacketBuffer)> {
+    // Bind to an ephemeral port on the wildcard address of the server's family, the reply will
+    // come back to whatever port the OS hands us.
+    let local: IpAddr = match server {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = UdpSocket::bind((local, 0))?;
+
+    // use the `write` method to write the packet to a buffer.
+    let mut req_buffer = BytePacketBuffer::new();
+    packet.write(&mut req_buffer)?;
+
+    // send the packet to the server using our udp socket
+    socket.send_to(&req_buffer.buf[0..req_buffer.pos], server)?;
+
+    // Anyone can send a packet to our port, so we keep reading until something arrives that
+    // actually answers the query we sent. Everything else is dropped on the floor.
+    let mut case_mismatch = false;
+    loop {
+        let left = match remaining(qname, deadline) {
+            Err(_) if case_mismatch => return Err(mismatched_case(qname).into()),
+            left => left?,
+        };
+        socket.set_read_timeout(Some(left))?;
+
+        // create a new `BytePacketBuffer` for receiving the response and ask the
+        // socket to write the response directly to the buffer
+        let mut res_buffer = BytePacketBuffer::new();
+        let src = match socket.recv_from(&mut res_buffer.buf) {
+            Ok((_, src)) => src,
+            Err(e) if is_timeout(&e) && case_mismatch => return Err(mismatched_case(qname).into()),
+            Err(e) if is_timeout(&e) => return Err(timed_out(qname).into()),
+            Err(e) => return Err(e.into()),
+        };
+
+        if src != server {
+            eprintln!("Discarding response from unexpected source {}", src);
+            continue;
+        }
+
+        // `DnsPacket::from_buffer` is used to parse the packet. Garbage is
+        // treated just like a spoofed reply.
+        let response = match DnsPacket::from_buffer(&mut res_buffer) {
+            Ok(response) => response,
+            Err(e) => {
+                eprintln!("Discarding malformed response from {}: {}", src, e);
+                continue;
+            }
+        };
+
+        if !matches_query(packet, &response) {
+            eprintln!("Discarding mismatched response from {}", src);
+            continue;
+        }
+
+        // Which is exactly what a spoofer guessing the case would send.
+        if let Some(sent_name) = sent_name {
+            if res_buffer.read_qname_at(QUESTION_OFFSET)? != sent_name {
+                eprintln!("Discarding response from {} with the case changed", src);
+                case_mismatch = true;
+                continue;
+            }
+        }
+
+        return Ok((response, res_buffer));
+    }
+}
+
+/// Send `packet` to `server` over a fresh TCP connection and wait until `deadline` for the
+/// reply, which is returned along with the raw message.
+///
+/// Nobody but the server can get a message into the connection, so unlike over UDP a reply that
+/// doesn't match the query is an error rather than something to skip.
+fn exchange_tcp(
+    qname: &str,
+    packet: &mut DnsPacket,
+    server: SocketAddr,
+    deadline: Instant,
+) -> Result<(DnsPacket, BytePacketBuffer)> {
+    let mut stream = match TcpStream::connect_timeout(&server, remaining(qname, deadline)?) {
+        Ok(stream) => stream,
+        Err(e) if is_timeout(&e) => return Err(timed_out(qname).into()),
+        Err(e) => return Err(e.into()),
+    };
+    stream.set_write_timeout(Some(remaining(qname, deadline)?))?;
+    tcp::write_packet(&mut stream, packet)?;
+
+    stream.set_read_timeout(Some(remaining(qname, deadline)?))?;
+    let mut res_buffer = match tcp::read_buffer(&mut stream) {
+        Ok(Some(buffer)) => buffer,
+        Ok(None) => return Err(format!("{} closed the connection without a reply", server).into()),
+        Err(e) => match e.downcast_ref::<io::Error>() {
+            Some(e) if is_timeout(e) => return Err(timed_out(qname).into()),
+            _ => return Err(e),
+        },
+    };
+
+    let response = DnsPacket::from_buffer(&mut res_buffer)?;
+    if !matches_query(packet, &response) {
+        return Err(format!("Mismatched response from {} over TCP", server).into());
+    }
+
+    Ok((response, res_buffer))
+}
+
+/// A reply matches a query if it's flagged as a response, carries the same id and echoes the
+/// question we asked.
+fn matches_query(query: &DnsPacket, response: &DnsPacket) -> bool {
+    response.header.response
+        && response.header.id == query.header.id
+        && response.questions.len() == query.questions.len()
+        && response
+            .questions
+            .iter()
+            .zip(query.questions.iter())
+            .all(|(a, b)| a.qtype == b.qtype && a.name.eq_ignore_ascii_case(&b.name))
+}
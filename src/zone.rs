@@ -0,0 +1,537 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    sync::Arc,
+};
+
+use crate::{
+    packet::{in_bailiwick, DnsPacket, DnsRecord, QueryType, Result, ResultCode},
+    zonefile,
+};
+
+/// The longest `CNAME` chain followed within the zones we serve.
+const MAX_CNAME_CHAIN: usize = 8;
+/// How many versions back the journal of a zone goes.
+const MAX_JOURNAL: usize = 64;
+
+/// Whether serial number `a` is newer than `b`, comparing them the way
+/// [RFC1982](https://datatracker.ietf.org/doc/html/rfc1982#section-3.2) asks so that serials can
+/// wrap around.
+pub fn serial_newer(a: u32, b: u32) -> bool {
+    a != b && a.wrapping_sub(b) < 1 << 31
+}
+
+/// The serial number in an `SOA` record.
+pub fn serial_of(soa: &DnsRecord) -> u32 {
+    match soa {
+        DnsRecord::SOA { serial, .. } => *serial,
+        _ => 0,
+    }
+}
+
+/// Whether `record` is an `SOA` for the same version of the same zone as `soa`. Only the name
+/// and serial count, since the TTL and timers of the primary's copy may well differ from ours.
+pub fn same_version(record: &DnsRecord, soa: &DnsRecord) -> bool {
+    matches!(record, DnsRecord::SOA { .. })
+        && record.domain().eq_ignore_ascii_case(soa.domain())
+        && serial_of(record) == serial_of(soa)
+}
+
+/// The changes that take a zone from one version to the next, as sent in incremental transfers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diff {
+    /// The `SOA` of the old version.
+    pub from: DnsRecord,
+    /// The `SOA` of the new version.
+    pub to: DnsRecord,
+    /// Records of the old version that are gone in the new one, other than the `SOA`.
+    pub removed: Vec<DnsRecord>,
+    /// Records of the new version that weren't in the old one, other than the `SOA`.
+    pub added: Vec<DnsRecord>,
+}
+
+impl Diff {
+    pub fn from_serial(&self) -> u32 {
+        serial_of(&self.from)
+    }
+
+    pub fn to_serial(&self) -> u32 {
+        serial_of(&self.to)
+    }
+}
+
+/// The outcome of looking up a name in a single zone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Lookup {
+    /// Records of the requested type.
+    Answer(Vec<DnsRecord>),
+    /// The name is an alias, the `CNAME` record is handed back so the target can be looked up.
+    Alias(DnsRecord),
+    /// The name lies at or below a delegation to another zone, these are its `NS` records.
+    Referral(Vec<DnsRecord>),
+    /// The name exists, but has no records of the requested type.
+    NoData,
+    /// The name doesn't exist.
+    NxDomain,
+}
+
+/// A zone we're authoritative for, kept entirely in memory.
+#[derive(Debug, Clone)]
+pub struct Zone {
+    pub origin: String,
+    records: HashMap<String, Vec<DnsRecord>>,
+    /// Every name in the zone which owns records or has descendants that do. The latter are the
+    /// empty non-terminals, which exist even though they have no records of their own.
+    nodes: HashSet<String>,
+    /// How the zone got to its current version from earlier ones, oldest first.
+    journal: Vec<Diff>,
+}
+
+impl Zone {
+    /// Build a zone from its records. There has to be an `SOA` record at the origin, and every
+    /// record has to be within the zone.
+    pub fn new(origin: &str, records: Vec<DnsRecord>) -> Result<Self> {
+        let origin = origin.trim_end_matches('.').to_lowercase();
+        let mut zone = Self {
+            origin,
+            records: HashMap::new(),
+            nodes: HashSet::new(),
+            journal: Vec::new(),
+        };
+
+        for record in records {
+            zone.insert(record)?;
+        }
+
+        if zone.soa().is_none() {
+            return Err(format!("Zone {} has no SOA record", zone.origin).into());
+        }
+
+        Ok(zone)
+    }
+
+    /// Load a zone from a master file.
+    pub fn load(origin: &str, path: &Path) -> Result<Self> {
+        Self::new(origin, zonefile::parse_file(path, origin)?)
+    }
+
+    /// Write the zone to a master file, see [`zonefile::write_file`].
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut records: Vec<DnsRecord> = self.records().cloned().collect();
+        records.sort();
+
+        // The SOA goes first, as is customary.
+        if let Some(soa) = self.soa() {
+            records.retain(|record| record != soa);
+            records.insert(0, soa.clone());
+        }
+
+        zonefile::write_file(path, &self.origin, &records)
+    }
+
+    /// The serial number of the zone's current version.
+    pub fn serial(&self) -> u32 {
+        self.soa().map_or(0, serial_of)
+    }
+
+    /// The changes that turn `old` into this version of the zone.
+    pub fn diff_from(&self, old: &Zone) -> Option<Diff> {
+        let old_records: HashSet<&DnsRecord> = old.records().collect();
+        let new_records: HashSet<&DnsRecord> = self.records().collect();
+        let (from, to) = (old.soa()?, self.soa()?);
+
+        Some(Diff {
+            from: from.clone(),
+            to: to.clone(),
+            removed: old_records
+                .difference(&new_records)
+                .filter(|record| **record != from)
+                .map(|record| (*record).clone())
+                .collect(),
+            added: new_records
+                .difference(&old_records)
+                .filter(|record| **record != to)
+                .map(|record| (*record).clone())
+                .collect(),
+        })
+    }
+
+    /// Build the version of the zone that results from applying `diffs` in turn. Each of them has
+    /// to start from the version the previous one left off at, the first from this one.
+    pub fn apply(&self, diffs: &[Diff]) -> Result<Zone> {
+        let mut records: HashSet<DnsRecord> = self.records().cloned().collect();
+
+        let mut soa = self.soa().ok_or("Zone has no SOA record")?.clone();
+        for diff in diffs {
+            if !same_version(&diff.from, &soa) {
+                return Err(format!(
+                    "Can't apply changes from serial {} to zone {} at serial {}",
+                    diff.from_serial(),
+                    self.origin,
+                    serial_of(&soa)
+                )
+                .into());
+            }
+
+            records.remove(&soa);
+            for record in &diff.removed {
+                records.remove(record);
+            }
+            records.extend(diff.added.iter().cloned());
+            records.insert(diff.to.clone());
+
+            soa = diff.to.clone();
+        }
+
+        Zone::new(&self.origin, records.into_iter().collect())
+    }
+
+    /// The changes since version `serial`, if the journal goes back that far.
+    pub fn journal_since(&self, serial: u32) -> Option<&[Diff]> {
+        let start = self
+            .journal
+            .iter()
+            .position(|diff| diff.from_serial() == serial)?;
+
+        Some(&self.journal[start..])
+    }
+
+    fn insert(&mut self, record: DnsRecord) -> Result<()> {
+        let owner = record.domain().to_lowercase();
+        if !in_bailiwick(&owner, &self.origin) {
+            return Err(format!("{} is outside of zone {}", owner, self.origin).into());
+        }
+
+        let mut node = owner.as_str();
+        while self.nodes.insert(node.to_string()) && node != self.origin {
+            node = node.split_once('.').map_or("", |(_, parent)| parent);
+        }
+
+        let rrs = self.records.entry(owner).or_default();
+        if !rrs.contains(&record) {
+            rrs.push(record);
+        }
+
+        Ok(())
+    }
+
+    /// The `SOA` record at the zone apex.
+    pub fn soa(&self) -> Option<&DnsRecord> {
+        self.records
+            .get(&self.origin)?
+            .iter()
+            .find(|record| matches!(record, DnsRecord::SOA { .. }))
+    }
+
+    /// The `SOA` record as it goes into the authority section of a negative answer, with its TTL
+    /// capped at the `minimum` field as RFC2308 asks.
+    pub fn negative_soa(&self) -> Option<DnsRecord> {
+        match self.soa()?.clone() {
+            DnsRecord::SOA {
+                domain,
+                class,
+                m_name,
+                r_name,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ttl,
+            } => Some(DnsRecord::SOA {
+                domain,
+                class,
+                m_name,
+                r_name,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ttl: ttl.min(minimum),
+            }),
+            _ => None,
+        }
+    }
+
+    /// All records owned by `name`, of any type.
+    pub fn records_at(&self, name: &str) -> &[DnsRecord] {
+        self.records.get(name).map_or(&[], |rrs| rrs.as_slice())
+    }
+
+    /// Every record in the zone.
+    pub fn records(&self) -> impl Iterator<Item = &DnsRecord> {
+        self.records.values().flatten()
+    }
+
+    /// The names between the zone apex (exclusive) and `qname` (inclusive), from the top down.
+    fn path_to<'a>(&self, qname: &'a str) -> Vec<&'a str> {
+        let mut path = Vec::new();
+
+        let mut name = qname;
+        while name.len() > self.origin.len() {
+            path.push(name);
+            name = name.split_once('.').map_or("", |(_, parent)| parent);
+        }
+        path.reverse();
+
+        path
+    }
+
+    /// The closest encloser of `qname`, i.e. the longest of its ancestors that exists in the
+    /// zone, see [RFC4592](https://datatracker.ietf.org/doc/html/rfc4592#section-3.3.1).
+    fn closest_encloser<'a>(&self, qname: &'a str) -> &'a str {
+        let mut name = qname;
+        while !self.nodes.contains(name) && name.len() > self.origin.len() {
+            name = name.split_once('.').map_or("", |(_, parent)| parent);
+        }
+
+        name
+    }
+
+    /// Look up `qname` following the algorithm of
+    /// [RFC1034](https://datatracker.ietf.org/doc/html/rfc1034#section-4.3.2), minus the parts
+    /// that concern other zones.
+    ///
+    /// Names that don't exist are matched against the wildcard at their closest encloser as
+    /// laid out in [RFC4592](https://datatracker.ietf.org/doc/html/rfc4592). Synthesized records
+    /// are owned by `qname`, and a wildcard never matches names that exist, including empty
+    /// non-terminals, nor anything below them.
+    pub fn lookup(&self, qname: &str, qtype: QueryType) -> Lookup {
+        let qname = qname.to_lowercase();
+        if !in_bailiwick(&qname, &self.origin) {
+            return Lookup::NxDomain;
+        }
+
+        // A zone cut anywhere on the way down means the data belongs to another zone, and all we
+        // can do is point there.
+        for name in self.path_to(&qname) {
+            let ns: Vec<DnsRecord> = self
+                .records_at(name)
+                .iter()
+                .filter(|record| matches!(record, DnsRecord::NS { .. }))
+                .cloned()
+                .collect();
+
+            if !ns.is_empty() {
+                return Lookup::Referral(ns);
+            }
+        }
+
+        if self.nodes.contains(&qname) {
+            return Self::match_rrset(self.records_at(&qname), &qname, qtype);
+        }
+
+        // The name doesn't exist, but there may be a wildcard to synthesize it from.
+        let source = format!("*.{}", self.closest_encloser(&qname));
+        let source = source.trim_end_matches('.');
+        match self.records.get(source) {
+            Some(rrs) => Self::match_rrset(rrs, &qname, qtype),
+            None => Lookup::NxDomain,
+        }
+    }
+
+    /// Pick what answers `qtype` from the records of a single name, renaming them to `qname`.
+    /// `ANY` is answered with all of them.
+    fn match_rrset(rrs: &[DnsRecord], qname: &str, qtype: QueryType) -> Lookup {
+        let owned_by_qname = |record: &DnsRecord| {
+            let mut record = record.clone();
+            record.set_domain(qname);
+            record
+        };
+
+        let answers: Vec<DnsRecord> = rrs
+            .iter()
+            .filter(|record| qtype == QueryType::ANY || record.qtype() == qtype)
+            .map(owned_by_qname)
+            .collect();
+        if !answers.is_empty() {
+            return Lookup::Answer(answers);
+        }
+
+        if let Some(cname) = rrs
+            .iter()
+            .find(|record| matches!(record, DnsRecord::CNAME { .. }))
+        {
+            return Lookup::Alias(owned_by_qname(cname));
+        }
+
+        Lookup::NoData
+    }
+
+    /// Address records for `host` held in this zone, including glue below zone cuts.
+    fn addresses(&self, host: &str) -> impl Iterator<Item = &DnsRecord> {
+        self.records_at(host)
+            .iter()
+            .filter(|record| matches!(record, DnsRecord::A { .. } | DnsRecord::AAAA { .. }))
+    }
+}
+
+/// All the zones we're authoritative for.
+///
+/// Zones are shared, so that one can be handed out and used while it's being replaced by a newer
+/// version. Replacing a zone is a single step: any lookup sees either all of the old version, or
+/// all of the new one.
+#[derive(Debug, Clone, Default)]
+pub struct ZoneStore {
+    zones: HashMap<String, Arc<Zone>>,
+    /// Zones we're supposed to serve, but have no usable data for, such as a secondary zone that
+    /// expired or hasn't been transferred yet.
+    expired: HashSet<String>,
+}
+
+impl ZoneStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a zone, replacing any previous zone with the same origin.
+    ///
+    /// When the new version is newer than the one it replaces, the differences between them are
+    /// added to the journal, so that others can catch up incrementally.
+    pub fn insert(&mut self, mut zone: Zone) {
+        if let Some(old) = self.zones.get(&zone.origin) {
+            if serial_newer(zone.serial(), old.serial()) {
+                zone.journal = old.journal.clone();
+                zone.journal.extend(zone.diff_from(old));
+
+                let excess = zone.journal.len().saturating_sub(MAX_JOURNAL);
+                zone.journal.drain(..excess);
+            }
+        }
+
+        self.expired.remove(&zone.origin);
+        self.zones.insert(zone.origin.clone(), Arc::new(zone));
+    }
+
+    /// Drop the data of the zone at `origin`. Queries for names in the zone fail from then on,
+    /// rather than being answered from elsewhere.
+    pub fn expire(&mut self, origin: &str) {
+        let origin = origin.trim_end_matches('.').to_lowercase();
+        self.zones.remove(&origin);
+        self.expired.insert(origin);
+    }
+
+    pub fn get(&self, origin: &str) -> Option<Arc<Zone>> {
+        self.zones.get(origin).cloned()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.zones.is_empty()
+    }
+
+    /// The most specific zone that `qname` belongs to, if we serve any.
+    pub fn find_zone(&self, qname: &str) -> Option<&Zone> {
+        self.find_origin(qname)
+            .and_then(|origin| self.zones.get(&origin))
+            .map(|zone| zone.as_ref())
+    }
+
+    /// The origin of the most specific zone that `qname` belongs to, whether we have data for it
+    /// or not.
+    fn find_origin(&self, qname: &str) -> Option<String> {
+        let qname = qname.to_lowercase();
+
+        let mut name = qname.as_str();
+        loop {
+            if self.zones.contains_key(name) || self.expired.contains(name) {
+                return Some(name.to_string());
+            }
+            if name.is_empty() {
+                return None;
+            }
+            name = name.split_once('.').map_or("", |(_, parent)| parent);
+        }
+    }
+
+    /// Answer a query from the zones we serve, or return `None` if `qname` isn't in any of them.
+    ///
+    /// Answers carry the authoritative answer flag, except for referrals. Negative answers come
+    /// with the zone's `SOA` in the authority section, and the addresses of name servers and mail
+    /// exchangers that we know of are added to the additional section.
+    ///
+    /// Names in zones that have expired are answered with `SERVFAIL`.
+    pub fn answer(&self, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
+        let origin = self.find_origin(qname)?;
+        let Some(mut zone) = self.zones.get(&origin).map(|zone| zone.as_ref()) else {
+            let mut packet = DnsPacket::new();
+            packet.header.rescode = ResultCode::SERVFAIL;
+            return Some(packet);
+        };
+
+        let mut packet = DnsPacket::new();
+        packet.header.authoritative_answer = true;
+
+        let mut name = qname.to_lowercase();
+        for _ in 0..MAX_CNAME_CHAIN {
+            match zone.lookup(&name, qtype) {
+                Lookup::Answer(records) => {
+                    packet.answers.extend(records);
+                    break;
+                }
+                Lookup::Alias(cname) => {
+                    let target = match &cname {
+                        DnsRecord::CNAME { host, .. } => host.clone(),
+                        _ => break,
+                    };
+                    packet.answers.push(cname);
+
+                    // Follow the alias as long as it points into a zone of ours, otherwise the
+                    // client has to take it from here.
+                    match self.find_zone(&target) {
+                        Some(next) => {
+                            zone = next;
+                            name = target;
+                        }
+                        None => break,
+                    }
+                }
+                Lookup::Referral(ns) => {
+                    // Data below a zone cut isn't ours to vouch for.
+                    if packet.answers.is_empty() {
+                        packet.header.authoritative_answer = false;
+                    }
+                    packet.authorities.extend(ns);
+                    break;
+                }
+                Lookup::NoData => {
+                    packet.authorities.extend(zone.negative_soa());
+                    break;
+                }
+                Lookup::NxDomain => {
+                    packet.header.rescode = ResultCode::NXDOMAIN;
+                    packet.authorities.extend(zone.negative_soa());
+                    break;
+                }
+            }
+        }
+
+        self.add_additional(&mut packet);
+
+        Some(packet)
+    }
+
+    /// Fill the additional section with addresses of the hosts named by `NS` and `MX` records in
+    /// the answer and authority sections.
+    fn add_additional(&self, packet: &mut DnsPacket) {
+        let hosts: Vec<String> = packet
+            .answers
+            .iter()
+            .chain(packet.authorities.iter())
+            .filter_map(|record| match record {
+                DnsRecord::NS { host, .. } | DnsRecord::MX { host, .. } => Some(host.clone()),
+                _ => None,
+            })
+            .collect();
+
+        for host in hosts {
+            if let Some(zone) = self.find_zone(&host) {
+                for record in zone.addresses(&host) {
+                    if !packet.resources.contains(record) {
+                        packet.resources.push(record.clone());
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,537 @@
+use std::{
+    fs,
+    net::{Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+};
+
+use crate::packet::{Class, DnsRecord, Result};
+
+/// How deep `$INCLUDE` directives may nest, which also stops a file from including itself.
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// Parse the master file at `path` in the format described in
+/// [RFC1035](https://datatracker.ietf.org/doc/html/rfc1035#section-5), with `origin` as the
+/// initial origin for relative names.
+///
+/// Supported are the `$ORIGIN`, `$TTL` and `$INCLUDE` directives, `@` for the current origin,
+/// owner names left blank to repeat the previous one, entries spanning several lines in
+/// parentheses, and comments. Names come out lowercase and without the trailing dot.
+///
+/// Records of types we have no representation for, such as `SRV` or `CAA`, are skipped with a
+/// warning rather than failing the whole file, so that the rest of the zone can still be served.
+pub fn parse_file(path: &Path, origin: &str) -> Result<Vec<DnsRecord>> {
+    let mut parser = Parser::new(origin);
+    parser.parse_file(path, 0)?;
+
+    Ok(parser.records)
+}
+
+/// Parse a master file from a string. `$INCLUDE` paths are taken relative to `base_dir`.
+pub fn parse_str(input: &str, origin: &str, base_dir: &Path) -> Result<Vec<DnsRecord>> {
+    let mut parser = Parser::new(origin);
+    parser.parse(input, "<input>", base_dir, 0)?;
+
+    Ok(parser.records)
+}
+
+/// Write `records` to a master file at `path` that [`parse_file`] reads back, with every name
+/// spelled out in full. Records of types that can't be written are left out.
+///
+/// The file is written under a temporary name first and then moved into place, so readers never
+/// see a partially written file.
+pub fn write_file(path: &Path, origin: &str, records: &[DnsRecord]) -> Result<()> {
+    let mut output = format!("; Zone {}\n", fqdn(origin));
+    for record in records {
+        match to_master(record) {
+            Some(line) => {
+                output.push_str(&line);
+                output.push('\n');
+            }
+            None => eprintln!("Not writing record to {}: {:?}", path.display(), record),
+        }
+    }
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, output).map_err(|e| format!("Unable to write {:?}: {}", tmp, e))?;
+    fs::rename(&tmp, path)?;
+
+    Ok(())
+}
+
+/// A name with the trailing dot that marks it as absolute.
+fn fqdn(name: &str) -> String {
+    format!("{}.", name)
+}
+
+/// The master file entry for `record`, if it has one.
+fn to_master(record: &DnsRecord) -> Option<String> {
+    let rdata = match record {
+        DnsRecord::A { addr, .. } => addr.to_string(),
+        DnsRecord::AAAA { addr, .. } => addr.to_string(),
+        DnsRecord::NS { host, .. } => fqdn(host),
+        DnsRecord::CNAME { host, .. } => fqdn(host),
+        DnsRecord::PTR { host, .. } => fqdn(host),
+        DnsRecord::MX { priority, host, .. } => format!("{} {}", priority, fqdn(host)),
+        DnsRecord::TXT { data, .. } => data
+            .iter()
+            .map(|text| format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\"")))
+            .collect::<Vec<_>>()
+            .join(" "),
+        DnsRecord::SOA {
+            m_name,
+            r_name,
+            serial,
+            refresh,
+            retry,
+            expire,
+            minimum,
+            ..
+        } => format!(
+            "{} {} {} {} {} {} {}",
+            fqdn(m_name),
+            fqdn(r_name),
+            serial,
+            refresh,
+            retry,
+            expire,
+            minimum
+        ),
+        DnsRecord::Unknown { .. } | DnsRecord::OPT { .. } => return None,
+    };
+
+    Some(format!(
+        "{} {} {} {:?} {}",
+        fqdn(record.domain()),
+        record.ttl(),
+        record.class(),
+        record.qtype(),
+        rdata
+    ))
+}
+
+/// A single token of an entry. Quoted strings are kept apart since they're never directives,
+/// classes or TTLs.
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    quoted: bool,
+}
+
+/// One logical entry, possibly spanning several physical lines.
+#[derive(Debug)]
+struct Entry {
+    line: usize,
+    /// Entries starting with whitespace have no owner name and reuse the previous one.
+    blank_owner: bool,
+    tokens: Vec<Token>,
+}
+
+/// Split `input` into entries, dropping comments and joining lines inside parentheses.
+fn tokenize(input: &str) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut chars = input.chars().peekable();
+
+    let mut line = 1;
+    let mut current = Entry {
+        line,
+        blank_owner: matches!(chars.peek(), Some(' ' | '\t')),
+        tokens: Vec::new(),
+    };
+    let mut depth = 0;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => {
+                line += 1;
+                if depth == 0 {
+                    let blank_owner = matches!(chars.peek(), Some(' ' | '\t'));
+                    let next = Entry {
+                        line,
+                        blank_owner,
+                        tokens: Vec::new(),
+                    };
+                    entries.push(std::mem::replace(&mut current, next));
+                }
+            }
+            ';' => {
+                while chars.peek().is_some_and(|c| *c != '\n') {
+                    chars.next();
+                }
+            }
+            '(' => depth += 1,
+            ')' => {
+                if depth == 0 {
+                    return Err(format!("line {}: unbalanced ')'", line).into());
+                }
+                depth -= 1;
+            }
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => {
+                            if let Some(c) = chars.next() {
+                                text.push(c);
+                            }
+                        }
+                        Some('\n') | None => {
+                            return Err(format!("line {}: unterminated string", line).into());
+                        }
+                        Some(c) => text.push(c),
+                    }
+                }
+
+                current.tokens.push(Token { text, quoted: true });
+            }
+            c if c.is_whitespace() => {}
+            c => {
+                let mut text = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || matches!(c, ';' | '(' | ')' | '"') {
+                        break;
+                    }
+                    text.push(c);
+                    chars.next();
+                }
+
+                current.tokens.push(Token {
+                    text,
+                    quoted: false,
+                });
+            }
+        }
+    }
+
+    if depth != 0 {
+        return Err(format!("line {}: unbalanced '('", line).into());
+    }
+    entries.push(current);
+
+    Ok(entries
+        .into_iter()
+        .filter(|entry| !entry.tokens.is_empty())
+        .collect())
+}
+
+/// Parse a TTL, either as a plain number of seconds or with unit suffixes as in `1h30m`.
+pub fn parse_ttl(text: &str) -> Result<u32> {
+    if let Ok(ttl) = text.parse::<u32>() {
+        return Ok(ttl);
+    }
+
+    let mut total: u32 = 0;
+    let mut number = String::new();
+    for c in text.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return Err(format!("Invalid TTL {}", text).into()),
+        };
+        if number.is_empty() {
+            return Err(format!("Invalid TTL {}", text).into());
+        }
+
+        let value = number.parse::<u32>()?;
+        total = value
+            .checked_mul(unit)
+            .and_then(|value| total.checked_add(value))
+            .ok_or_else(|| format!("TTL {} out of range", text))?;
+        number.clear();
+    }
+
+    if !number.is_empty() {
+        return Err(format!("Invalid TTL {}", text).into());
+    }
+
+    Ok(total)
+}
+
+/// Turn a name as written in a zone file into an absolute one, relative to `origin`.
+pub fn absolute_name(name: &str, origin: &str) -> String {
+    let name = name.to_lowercase();
+
+    if name == "@" {
+        origin.to_string()
+    } else if name == "." {
+        String::new()
+    } else if let Some(name) = name.strip_suffix('.') {
+        name.to_string()
+    } else if origin.is_empty() {
+        name
+    } else {
+        format!("{}.{}", name, origin)
+    }
+}
+
+fn is_class(text: &str) -> bool {
+    matches!(text.to_uppercase().as_str(), "IN" | "CH" | "HS" | "CS")
+}
+
+fn is_ttl(text: &str) -> bool {
+    text.starts_with(|c: char| c.is_ascii_digit())
+}
+
+struct Parser {
+    origin: String,
+    default_ttl: Option<u32>,
+    last_owner: Option<String>,
+    last_ttl: Option<u32>,
+    records: Vec<DnsRecord>,
+}
+
+impl Parser {
+    fn new(origin: &str) -> Self {
+        Self {
+            origin: absolute_name(origin, ""),
+            default_ttl: None,
+            last_owner: None,
+            last_ttl: None,
+            records: Vec::new(),
+        }
+    }
+
+    fn parse_file(&mut self, path: &Path, depth: usize) -> Result<()> {
+        if depth > MAX_INCLUDE_DEPTH {
+            return Err(format!("{}: $INCLUDE nested too deeply", path.display()).into());
+        }
+
+        let input = fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        let base_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+
+        self.parse(&input, &path.display().to_string(), &base_dir, depth)
+    }
+
+    fn parse(&mut self, input: &str, source: &str, base_dir: &Path, depth: usize) -> Result<()> {
+        for entry in tokenize(input).map_err(|e| format!("{}: {}", source, e))? {
+            self.entry(&entry, base_dir, depth)
+                .map_err(|e| format!("{}:{}: {}", source, entry.line, e))?;
+        }
+
+        Ok(())
+    }
+
+    fn entry(&mut self, entry: &Entry, base_dir: &Path, depth: usize) -> Result<()> {
+        let mut tokens = entry.tokens.iter().peekable();
+
+        let first = &entry.tokens[0];
+        if !entry.blank_owner && !first.quoted && first.text.starts_with('$') {
+            return self.directive(&entry.tokens, base_dir, depth);
+        }
+
+        let owner = if entry.blank_owner {
+            self.last_owner
+                .clone()
+                .ok_or("No previous owner name to repeat")?
+        } else {
+            let token = tokens.next().ok_or("Missing owner name")?;
+            absolute_name(&token.text, &self.origin)
+        };
+
+        // The TTL and class are both optional and may come in either order.
+        let mut ttl = None;
+        for _ in 0..2 {
+            match tokens.peek() {
+                Some(token) if !token.quoted && is_ttl(&token.text) && ttl.is_none() => {
+                    ttl = Some(parse_ttl(&token.text)?);
+                    tokens.next();
+                }
+                Some(token) if !token.quoted && is_class(&token.text) => {
+                    if !token.text.eq_ignore_ascii_case("IN") {
+                        return Err(format!("Unsupported class {}", token.text).into());
+                    }
+                    tokens.next();
+                }
+                _ => break,
+            }
+        }
+
+        let rtype = tokens
+            .next()
+            .ok_or("Missing record type")?
+            .text
+            .to_uppercase();
+        let rdata: Vec<&str> = tokens.map(|token| token.text.as_str()).collect();
+
+        let ttl = match ttl.or(self.default_ttl).or(self.last_ttl) {
+            Some(ttl) => ttl,
+            // Without any TTL to go on, RFC1035 has the SOA minimum apply.
+            None if rtype == "SOA" => parse_ttl(rdata.get(6).ok_or("Missing SOA minimum")?)?,
+            None => return Err("No TTL given and no $TTL in effect".into()),
+        };
+
+        match self.record(&owner, &rtype, &rdata, ttl)? {
+            Some(record) => self.records.push(record),
+            None => eprintln!("Skipping {} record of unsupported type {}", owner, rtype),
+        }
+
+        self.last_owner = Some(owner);
+        self.last_ttl = Some(ttl);
+
+        Ok(())
+    }
+
+    fn directive(&mut self, tokens: &[Token], base_dir: &Path, depth: usize) -> Result<()> {
+        let arg = |idx: usize| -> Result<&str> {
+            tokens
+                .get(idx)
+                .map(|token| token.text.as_str())
+                .ok_or_else(|| format!("{} needs an argument", tokens[0].text).into())
+        };
+
+        match tokens[0].text.to_uppercase().as_str() {
+            "$ORIGIN" => self.origin = absolute_name(arg(1)?, &self.origin),
+            "$TTL" => self.default_ttl = Some(parse_ttl(arg(1)?)?),
+            "$INCLUDE" => {
+                let mut path = PathBuf::from(arg(1)?);
+                if path.is_relative() {
+                    path = base_dir.join(path);
+                }
+
+                // An origin given with the directive only applies to the included file.
+                let saved = self.origin.clone();
+                if let Ok(origin) = arg(2) {
+                    self.origin = absolute_name(origin, &self.origin);
+                }
+                let res = self.parse_file(&path, depth + 1);
+                self.origin = saved;
+
+                res?;
+            }
+            other => return Err(format!("Unknown directive {}", other).into()),
+        }
+
+        Ok(())
+    }
+
+    /// The record of type `rtype` owned by `owner`, or `None` if it's of a type we don't support.
+    fn record(
+        &self,
+        owner: &str,
+        rtype: &str,
+        rdata: &[&str],
+        ttl: u32,
+    ) -> Result<Option<DnsRecord>> {
+        let field = |idx: usize| -> Result<&str> {
+            rdata
+                .get(idx)
+                .copied()
+                .ok_or_else(|| format!("Missing data for {} record", rtype).into())
+        };
+        let expect = |count: usize| -> Result<()> {
+            if rdata.len() != count {
+                return Err(format!(
+                    "{} record takes {} field(s), got {}",
+                    rtype,
+                    count,
+                    rdata.len()
+                )
+                .into());
+            }
+
+            Ok(())
+        };
+        let domain = owner.to_string();
+
+        let record = match rtype {
+            "A" => {
+                expect(1)?;
+                DnsRecord::A {
+                    domain,
+                    class: Class::IN,
+                    addr: field(0)?.parse::<Ipv4Addr>()?,
+                    ttl,
+                }
+            }
+            "AAAA" => {
+                expect(1)?;
+                DnsRecord::AAAA {
+                    domain,
+                    class: Class::IN,
+                    addr: field(0)?.parse::<Ipv6Addr>()?,
+                    ttl,
+                }
+            }
+            "NS" => {
+                expect(1)?;
+                DnsRecord::NS {
+                    domain,
+                    class: Class::IN,
+                    host: absolute_name(field(0)?, &self.origin),
+                    ttl,
+                }
+            }
+            "CNAME" => {
+                expect(1)?;
+                DnsRecord::CNAME {
+                    domain,
+                    class: Class::IN,
+                    host: absolute_name(field(0)?, &self.origin),
+                    ttl,
+                }
+            }
+            "PTR" => {
+                expect(1)?;
+                DnsRecord::PTR {
+                    domain,
+                    class: Class::IN,
+                    host: absolute_name(field(0)?, &self.origin),
+                    ttl,
+                }
+            }
+            "MX" => {
+                expect(2)?;
+                DnsRecord::MX {
+                    domain,
+                    class: Class::IN,
+                    priority: field(0)?.parse()?,
+                    host: absolute_name(field(1)?, &self.origin),
+                    ttl,
+                }
+            }
+            "TXT" => {
+                if rdata.is_empty() {
+                    return Err("Missing data for TXT record".into());
+                }
+                if let Some(text) = rdata.iter().find(|text| text.len() > 0xFF) {
+                    return Err(format!("TXT string longer than 255 bytes: {}", text).into());
+                }
+
+                DnsRecord::TXT {
+                    domain,
+                    class: Class::IN,
+                    data: rdata.iter().map(|text| text.to_string()).collect(),
+                    ttl,
+                }
+            }
+            "SOA" => {
+                expect(7)?;
+                DnsRecord::SOA {
+                    domain,
+                    class: Class::IN,
+                    m_name: absolute_name(field(0)?, &self.origin),
+                    r_name: absolute_name(field(1)?, &self.origin),
+                    serial: field(2)?.parse()?,
+                    refresh: parse_ttl(field(3)?)?,
+                    retry: parse_ttl(field(4)?)?,
+                    expire: parse_ttl(field(5)?)?,
+                    minimum: parse_ttl(field(6)?)?,
+                    ttl,
+                }
+            }
+            _ => return Ok(None),
+        };
+
+        Ok(Some(record))
+    }
+}
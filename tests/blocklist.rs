@@ -0,0 +1,141 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use dns_clone::{
+    blocklist::{BlockResponse, Blocklist, Coverage, DomainSet},
+    packet::{Class, DnsPacket, DnsRecord, EdnsOption, QueryType, ResultCode},
+};
+
+const RULES: &str = "\
+# Hosts file
+127.0.0.1 localhost
+0.0.0.0 ads.example.com tracker.example.com
+! Adblock
+[Adblock Plus 2.0]
+||doubleclick.example^
+@@||good.doubleclick.example^
+||ads.example.net^$third-party
+example.org##.banner
+# Plain list
+Malware.Example.
+*.cdn.example
+";
+
+#[test]
+fn lists_in_every_format_are_matched_by_suffix() {
+    let mut blocklist = Blocklist::new(BlockResponse::NxDomain);
+    blocklist.add_rules(RULES, false);
+    blocklist.add_rules("tracker.example.com\n", true);
+
+    for name in [
+        "ads.example.com",
+        "doubleclick.example",
+        "x.y.doubleclick.example",
+        "malware.example",
+        "MALWARE.example",
+        "img.cdn.example",
+    ] {
+        assert!(blocklist.is_blocked(name), "{} should be blocked", name);
+    }
+
+    for name in [
+        "localhost",
+        "www.ads.example.com",
+        "example.com",
+        "tracker.example.com",
+        "good.doubleclick.example",
+        "www.good.doubleclick.example",
+        "ads.example.net",
+        "example.org",
+        "www.malware.example",
+        "cdn.example",
+    ] {
+        assert!(!blocklist.is_blocked(name), "{} shouldn't be blocked", name);
+    }
+
+    let mut set = DomainSet::new();
+    set.insert("example.com", Coverage::Exact);
+    set.insert("example.com", Coverage::Below);
+    assert_eq!(set.len(), 1);
+    assert!(set.contains("example.com") && set.contains("www.example.com"));
+}
+
+fn blocked(response: &str, qtype: QueryType) -> DnsPacket {
+    let mut blocklist = Blocklist::new(response.parse().unwrap());
+    blocklist.add_rules("ads.example.com", false);
+
+    blocklist.check("ads.example.com", qtype).unwrap()
+}
+
+#[test]
+fn blocked_names_get_the_configured_response() {
+    let response = blocked("nxdomain", QueryType::A);
+    assert_eq!(response.header.rescode, ResultCode::NXDOMAIN);
+    assert!(response.answers.is_empty());
+    assert_eq!(
+        response.get_opt(),
+        Some(&DnsRecord::OPT {
+            packet_len: 512,
+            flags: 0,
+            options: vec![EdnsOption::extended_error(15, "Blocked by a blocklist")],
+        })
+    );
+
+    assert_eq!(
+        blocked("refused", QueryType::A).header.rescode,
+        ResultCode::REFUSED
+    );
+    assert_eq!(
+        blocked("null", QueryType::A).get_all_a(),
+        vec![Ipv4Addr::UNSPECIFIED]
+    );
+    assert_eq!(
+        blocked("null", QueryType::AAAA).get_all_aaaa(),
+        vec![Ipv6Addr::UNSPECIFIED]
+    );
+    assert!(blocked("null", QueryType::MX).answers.is_empty());
+
+    let sinkhole = "sinkhole:192.0.2.1,2001:db8::1";
+    assert_eq!(
+        blocked(sinkhole, QueryType::A).get_all_a(),
+        vec![Ipv4Addr::new(192, 0, 2, 1)]
+    );
+    assert_eq!(
+        blocked(sinkhole, QueryType::AAAA).get_all_aaaa(),
+        vec!["2001:db8::1".parse::<Ipv6Addr>().unwrap()]
+    );
+    assert!("sinkhole:nowhere".parse::<BlockResponse>().is_err());
+}
+
+#[test]
+fn blocked_queries_are_counted() {
+    let mut blocklist = Blocklist::new(BlockResponse::NullAddress);
+    blocklist.add_rules("||tracker.example^\n", false);
+
+    assert!(blocklist.check("www.example.com", QueryType::A).is_none());
+    for _ in 0..3 {
+        blocklist.check("a.tracker.example", QueryType::A).unwrap();
+    }
+    blocklist.check("b.tracker.example", QueryType::A).unwrap();
+
+    // An alias for a blocked name counts too.
+    let mut response = DnsPacket::new();
+    response.answers.push(DnsRecord::CNAME {
+        domain: "metrics.example.com".to_string(),
+        class: Class::IN,
+        host: "c.tracker.example".to_string(),
+        ttl: 300,
+    });
+    let blocked = blocklist
+        .check_response("metrics.example.com", QueryType::A, &response)
+        .unwrap();
+    assert_eq!(blocked.get_all_a(), vec![Ipv4Addr::UNSPECIFIED]);
+
+    assert_eq!(blocklist.blocked_count(), 5);
+    assert_eq!(
+        blocklist.top_blocked(2),
+        vec![
+            ("a.tracker.example".to_string(), 3),
+            ("b.tracker.example".to_string(), 1),
+        ]
+    );
+}
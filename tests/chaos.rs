@@ -0,0 +1,39 @@
+use dns_clone::{
+    chaos::Chaos,
+    packet::{Class, DnsQuestion, DnsRecord, QueryType, ResultCode},
+};
+
+fn question(name: &str, qtype: QueryType) -> DnsQuestion {
+    let mut question = DnsQuestion::new(name.to_string(), qtype);
+    question.class = Class::CH;
+    question
+}
+
+#[test]
+fn servers_tell_who_they_are() {
+    let chaos = Chaos::new(Some("ns1.example.com".to_string()));
+
+    for name in ["id.server", "HOSTNAME.BIND"] {
+        let answer = chaos.answer(&question(name, QueryType::TXT));
+        assert_eq!(answer.header.rescode, ResultCode::NOERROR);
+        assert_eq!(
+            answer.answers,
+            vec![DnsRecord::TXT {
+                domain: name.to_string(),
+                class: Class::CH,
+                data: vec!["ns1.example.com".to_string()],
+                ttl: 0,
+            }]
+        );
+    }
+
+    let version = chaos.answer(&question("version.bind", QueryType::ANY));
+    assert_eq!(version.answers.len(), 1);
+
+    // Other types at known names have no records, unknown names aren't ours to answer.
+    let other = chaos.answer(&question("version.bind", QueryType::A));
+    assert_eq!(other.header.rescode, ResultCode::NOERROR);
+    assert!(other.answers.is_empty());
+    let unknown = chaos.answer(&question("authors.bind", QueryType::TXT));
+    assert_eq!(unknown.header.rescode, ResultCode::REFUSED);
+}
@@ -0,0 +1,125 @@
+use std::{
+    env,
+    fs::{self, File},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::RwLock,
+    time::{Duration, SystemTime},
+};
+
+use dns_clone::{
+    hosts::Hosts,
+    local::{self, LocalRecords, LocalSource},
+    packet::{Class, DnsRecord, QueryType},
+};
+
+const HOSTS: &str = "\
+127.0.0.1   localhost
+192.0.2.10  api.example.com api  # staging
+2001:db8::10 api.example.com
+";
+
+fn ptr(domain: &str, host: &str) -> DnsRecord {
+    DnsRecord::PTR {
+        domain: domain.to_string(),
+        class: Class::IN,
+        host: host.to_string(),
+        ttl: 0,
+    }
+}
+
+#[test]
+fn hosts_and_static_records_are_answered_with_their_ptrs() {
+    let statics = vec![
+        local::parse_record("db.example.com 300 IN A 192.0.2.20").unwrap(),
+        local::parse_record("www.example.com CNAME api.example.com.").unwrap(),
+        local::parse_record("20.2.0.192.in-addr.arpa PTR primary.db.example.com").unwrap(),
+    ];
+    let local = LocalRecords::new(&Hosts::parse(HOSTS), &statics);
+
+    let answer = local.answer("API.example.com", QueryType::A).unwrap();
+    assert!(answer.header.authoritative_answer);
+    assert_eq!(answer.get_all_a(), vec![Ipv4Addr::new(192, 0, 2, 10)]);
+    assert_eq!(
+        local
+            .answer("api.example.com", QueryType::AAAA)
+            .unwrap()
+            .get_all_aaaa(),
+        vec!["2001:db8::10".parse::<Ipv6Addr>().unwrap()]
+    );
+    assert_eq!(
+        local
+            .answer("db.example.com", QueryType::A)
+            .unwrap()
+            .answers[0]
+            .ttl(),
+        300
+    );
+
+    // Names we have, but not of the type asked for, get an empty answer. Aliases are handed back.
+    assert!(local
+        .answer("api.example.com", QueryType::MX)
+        .unwrap()
+        .answers
+        .is_empty());
+    assert_eq!(
+        local
+            .answer("www.example.com", QueryType::A)
+            .unwrap()
+            .answers,
+        vec![statics[1].clone()]
+    );
+    assert!(local.answer("mail.example.com", QueryType::A).is_none());
+
+    // Addresses map back to the first name given for them, unless told otherwise.
+    let reverse = |addr: IpAddr| {
+        local
+            .answer(&local::reverse_name(&addr), QueryType::PTR)
+            .unwrap()
+            .answers
+    };
+    assert_eq!(
+        reverse(Ipv4Addr::new(192, 0, 2, 10).into()),
+        vec![ptr("10.2.0.192.in-addr.arpa", "api.example.com")]
+    );
+    assert_eq!(
+        reverse("2001:db8::10".parse().unwrap()),
+        vec![ptr(
+            "0.1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa",
+            "api.example.com"
+        )]
+    );
+    assert_eq!(
+        reverse(Ipv4Addr::new(192, 0, 2, 20).into()),
+        vec![ptr("20.2.0.192.in-addr.arpa", "primary.db.example.com")]
+    );
+}
+
+#[test]
+fn hosts_file_is_reloaded_when_it_changes() {
+    let dir = env::temp_dir().join(format!("dns-clone-local-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("hosts");
+    fs::write(&path, HOSTS).unwrap();
+
+    let mut source = LocalSource::new(Some(path.clone()), Vec::new());
+    let local = RwLock::new(source.load().unwrap());
+    assert!(local.read().unwrap().answer("api", QueryType::A).is_some());
+
+    // Unchanged, nothing happens.
+    *local.write().unwrap() = LocalRecords::default();
+    source.reload_if_changed(&local);
+    assert!(local.read().unwrap().is_empty());
+
+    fs::write(&path, "192.0.2.99 api\n").unwrap();
+    File::options()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_modified(SystemTime::now() + Duration::from_secs(10))
+        .unwrap();
+    source.reload_if_changed(&local);
+    fs::remove_dir_all(&dir).unwrap();
+
+    let answer = local.read().unwrap().answer("api", QueryType::A).unwrap();
+    assert_eq!(answer.get_all_a(), vec![Ipv4Addr::new(192, 0, 2, 99)]);
+}
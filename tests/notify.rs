@@ -0,0 +1,54 @@
+use std::{
+    net::{Ipv4Addr, UdpSocket},
+    thread,
+};
+
+use dns_clone::{
+    notify,
+    packet::{BytePacketBuffer, DnsPacket, Opcode, QueryType, ResultCode},
+};
+
+#[test]
+fn notifications_are_sent_until_acknowledged() {
+    let secondary = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let addr = secondary.local_addr().unwrap();
+
+    let responder = thread::spawn(move || {
+        let mut buffer = BytePacketBuffer::new();
+        let (_, src) = secondary.recv_from(&mut buffer.buf).unwrap();
+        let request = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+        assert_eq!(request.header.opcode, Opcode::NOTIFY);
+        assert_eq!(request.questions[0].name, "example.com");
+        assert_eq!(request.questions[0].qtype, QueryType::SOA);
+
+        let mut ack = notify::ack(&request);
+        let mut buffer = BytePacketBuffer::new();
+        ack.write(&mut buffer).unwrap();
+        secondary.send_to(&buffer.buf[0..buffer.pos], src).unwrap();
+    });
+
+    notify::send("example.com", addr).unwrap();
+    responder.join().unwrap();
+}
+
+#[test]
+fn refused_notifications_are_errors() {
+    let secondary = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let addr = secondary.local_addr().unwrap();
+
+    let responder = thread::spawn(move || {
+        let mut buffer = BytePacketBuffer::new();
+        let (_, src) = secondary.recv_from(&mut buffer.buf).unwrap();
+        let request = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+        let mut ack = notify::ack(&request);
+        ack.header.rescode = ResultCode::REFUSED;
+        let mut buffer = BytePacketBuffer::new();
+        ack.write(&mut buffer).unwrap();
+        secondary.send_to(&buffer.buf[0..buffer.pos], src).unwrap();
+    });
+
+    assert!(notify::send("example.com", addr).is_err());
+    responder.join().unwrap();
+}
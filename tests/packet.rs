@@ -0,0 +1,171 @@
+use dns_clone::packet::{
+    BytePacketBuffer, Class, DnsPacket, DnsQuestion, DnsRecord, Opcode, QueryType, ResultCode,
+};
+
+fn round_trip(packet: &mut DnsPacket) -> DnsPacket {
+    let mut buffer = BytePacketBuffer::new();
+    packet.write(&mut buffer).unwrap();
+    buffer.pos = 0;
+
+    DnsPacket::from_buffer(&mut buffer).unwrap()
+}
+
+fn opt(flags: u32) -> DnsRecord {
+    DnsRecord::OPT {
+        packet_len: 1232,
+        flags,
+        options: Vec::new(),
+    }
+}
+
+#[test]
+fn header_result_codes_survive_a_round_trip() {
+    for num in 0..16 {
+        let mut packet = DnsPacket::new();
+        packet.header.rescode = ResultCode::from_num(num);
+
+        let read = round_trip(&mut packet);
+        assert_eq!(read.header.rescode.as_num(), num);
+    }
+
+    assert_eq!(ResultCode::from_num(9), ResultCode::NOTAUTH);
+    assert_eq!(ResultCode::from_num(12), ResultCode::Unknown(12));
+}
+
+#[test]
+fn any_questions_survive_a_round_trip() {
+    let mut packet = DnsPacket::new();
+    packet
+        .questions
+        .push(DnsQuestion::new("example.com".to_string(), QueryType::ANY));
+
+    let read = round_trip(&mut packet);
+    assert_eq!(read.questions[0].qtype, QueryType::ANY);
+    assert_eq!(QueryType::ANY.as_num(), 255);
+}
+
+#[test]
+fn extended_result_codes_are_carried_by_the_opt_record() {
+    let mut packet = DnsPacket::new();
+    packet.header.rescode = ResultCode::BADCOOKIE;
+    // The DO bit has to come through untouched.
+    packet.resources.push(opt(0x8000));
+
+    let read = round_trip(&mut packet);
+    assert_eq!(read.header.rescode, ResultCode::BADCOOKIE);
+    assert_eq!(read.get_opt(), Some(&opt(0x8000)));
+
+    let mut buffer = BytePacketBuffer::new();
+    packet.write(&mut buffer).unwrap();
+    // The header only has the lower 4 bits of 23, the OPT's TTL the rest.
+    assert_eq!(buffer.buf[3] & 0x0F, 7);
+    assert_eq!(buffer.buf[buffer.pos - 6], 1);
+}
+
+#[test]
+fn extended_result_codes_need_an_opt_record() {
+    let mut packet = DnsPacket::new();
+    packet.header.rescode = ResultCode::BADVERS;
+
+    let mut buffer = BytePacketBuffer::new();
+    assert!(packet.write(&mut buffer).is_err());
+}
+
+#[test]
+fn opcodes_survive_a_round_trip() {
+    for num in 0..16 {
+        let mut packet = DnsPacket::new();
+        packet.header.opcode = Opcode::from_num(num);
+
+        let read = round_trip(&mut packet);
+        assert_eq!(read.header.opcode.as_num(), num);
+    }
+
+    assert_eq!(Opcode::from_num(5), Opcode::UPDATE);
+    assert_eq!(Opcode::from_num(3), Opcode::Unknown(3));
+}
+
+#[test]
+fn classes_and_txt_records_survive_a_round_trip() {
+    let txt = DnsRecord::TXT {
+        domain: "version.bind".to_string(),
+        class: Class::CH,
+        data: vec!["dns-clone".to_string(), String::new(), "0.1.0".to_string()],
+        ttl: 0,
+    };
+
+    let mut packet = DnsPacket::new();
+    let mut question = DnsQuestion::new("version.bind".to_string(), QueryType::TXT);
+    question.class = Class::CH;
+    packet.questions.push(question.clone());
+    packet.answers.push(txt.clone());
+    packet.authorities.push(DnsRecord::Unknown {
+        domain: "example.com".to_string(),
+        class: Class::NONE,
+        qtype: 1,
+        data_len: 0,
+        ttl: 0,
+    });
+
+    let read = round_trip(&mut packet);
+    assert_eq!(read.questions, vec![question]);
+    assert_eq!(read.answers, vec![txt]);
+    assert_eq!(read.authorities[0].class(), Class::NONE);
+
+    assert_eq!("hs".parse::<Class>().unwrap(), Class::HS);
+    assert_eq!("CLASS42".parse::<Class>().unwrap(), Class::Unknown(42));
+    assert_eq!(Class::Unknown(42).to_string(), "CLASS42");
+}
+
+#[test]
+fn referrals_are_stripped_of_records_outside_the_zone() {
+    let ns = |domain: &str, host: &str| DnsRecord::NS {
+        domain: domain.to_string(),
+        class: Class::IN,
+        host: host.to_string(),
+        ttl: 3600,
+    };
+    let a = |domain: &str, addr: [u8; 4]| DnsRecord::A {
+        domain: domain.to_string(),
+        class: Class::IN,
+        addr: addr.into(),
+        ttl: 3600,
+    };
+
+    // A referral from the servers for `org`, with a name server and addresses for `com` thrown in.
+    let mut referral = DnsPacket::new();
+    referral.authorities = vec![
+        ns("example.org", "ns.example.org"),
+        ns("example.org", "ns.victim.com"),
+        ns("victim.com", "ns.victim.com"),
+    ];
+    referral.resources = vec![
+        a("ns.example.org", [192, 0, 2, 53]),
+        a("ns.victim.com", [203, 0, 113, 66]),
+        a("www.victim.com", [203, 0, 113, 66]),
+    ];
+
+    // Glue for servers outside the zone isn't trusted, even before sanitizing.
+    assert_eq!(
+        referral.get_all_resolved_ns("www.example.org", "org"),
+        vec![std::net::IpAddr::from([192, 0, 2, 53])]
+    );
+
+    referral.sanitize("org");
+    assert_eq!(
+        referral.authorities,
+        vec![
+            ns("example.org", "ns.example.org"),
+            ns("example.org", "ns.victim.com"),
+        ]
+    );
+    assert_eq!(
+        referral.resources,
+        vec![a("ns.example.org", [192, 0, 2, 53])]
+    );
+    assert_eq!(referral.get_delegation("www.victim.com", "org"), None);
+
+    // Labels are compared whole.
+    assert!(dns_clone::packet::in_bailiwick("www.example.org", "ORG"));
+    assert!(!dns_clone::packet::in_bailiwick("notorg", "org"));
+}
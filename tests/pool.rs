@@ -0,0 +1,82 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Barrier, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use dns_clone::{coalesce::Coalescer, pool::WorkerPool};
+
+#[test]
+fn pools_run_jobs_concurrently_up_to_their_limit() {
+    let (done, finished) = mpsc::channel();
+    // Both workers have to be busy at the same time to get past this.
+    let barrier = Barrier::new(2);
+    let (release, blocked) = mpsc::channel::<()>();
+    let blocked = Mutex::new(blocked);
+
+    thread::scope(|s| {
+        let pool = WorkerPool::new(s, 2, 3);
+
+        for _ in 0..2 {
+            let (done, barrier) = (done.clone(), &barrier);
+            assert!(pool.try_execute(move || {
+                barrier.wait();
+                done.send(()).unwrap();
+            }));
+        }
+        finished.recv_timeout(Duration::from_secs(5)).unwrap();
+        finished.recv_timeout(Duration::from_secs(5)).unwrap();
+        while pool.in_flight() > 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // Keep both workers busy and fill the queue, past which nothing more is taken on.
+        for _ in 0..3 {
+            let blocked = &blocked;
+            assert!(pool.try_execute(move || {
+                let _ = blocked.lock().unwrap().recv();
+            }));
+        }
+        assert_eq!(pool.in_flight(), 3);
+        assert!(!pool.try_execute(|| {}));
+
+        drop(release);
+    });
+}
+
+#[test]
+fn identical_concurrent_work_is_done_once() {
+    let coalescer = Coalescer::new();
+    let computed = AtomicUsize::new(0);
+    let barrier = Barrier::new(8);
+
+    let results: Vec<String> = thread::scope(|s| {
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                s.spawn(|| {
+                    barrier.wait();
+                    coalescer.run("example.com", || {
+                        computed.fetch_add(1, Ordering::SeqCst);
+                        // Long enough for everyone else to come asking.
+                        thread::sleep(Duration::from_millis(200));
+                        "answer".to_string()
+                    })
+                })
+            })
+            .collect();
+
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+
+    assert!(results.iter().all(|result| result == "answer"));
+    assert_eq!(computed.load(Ordering::SeqCst), 1);
+
+    // Once done, the next caller does the work again.
+    coalescer.run("example.com", || {
+        computed.fetch_add(1, Ordering::SeqCst).to_string()
+    });
+    assert_eq!(computed.load(Ordering::SeqCst), 2);
+}
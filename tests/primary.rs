@@ -0,0 +1,64 @@
+use std::{env, fs, path::PathBuf, sync::RwLock};
+
+use dns_clone::{
+    packet::QueryType,
+    primary::{updates_file, Primary},
+    zone::ZoneStore,
+};
+
+/// The zone with serial `serial`, and an address for `www` ending in `last`.
+fn version(serial: u32, last: u8) -> String {
+    format!(
+        "\
+$TTL 3600
+@    SOA ns hostmaster {} 3600 600 86400 300
+@    NS  ns
+www  A   192.0.2.{}
+",
+        serial, last
+    )
+}
+
+fn fixture(name: &str, master: &str, updates: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("dns-clone-primary-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("example.com.zone");
+    fs::write(&file, master).unwrap();
+    fs::write(updates_file(&file), updates).unwrap();
+
+    file
+}
+
+/// The last octet of the address `www` has in the zone loaded from `file`.
+fn loaded(file: PathBuf) -> u8 {
+    let zones = RwLock::new(ZoneStore::new());
+    Primary::load("example.com", file, &zones).unwrap();
+
+    let response = zones
+        .read()
+        .unwrap()
+        .answer("www.example.com", QueryType::A)
+        .unwrap();
+    response.get_all_a()[0].octets()[3]
+}
+
+#[test]
+fn dynamic_updates_are_picked_up_if_newer() {
+    let file = fixture("newer", &version(1, 1), &version(2, 2));
+    assert_eq!(
+        updates_file(&file).file_name().unwrap(),
+        "example.com.zone.dyn"
+    );
+    assert_eq!(loaded(file.clone()), 2);
+    fs::remove_dir_all(file.parent().unwrap()).unwrap();
+
+    // Edits to the master file since the updates win once its serial has moved past them.
+    let file = fixture("older", &version(3, 3), &version(2, 2));
+    assert_eq!(loaded(file.clone()), 3);
+    fs::remove_dir_all(file.parent().unwrap()).unwrap();
+
+    // Broken updates are no reason not to serve the zone.
+    let file = fixture("broken", &version(1, 1), "@ A nowhere\n");
+    assert_eq!(loaded(file.clone()), 1);
+    fs::remove_dir_all(file.parent().unwrap()).unwrap();
+}
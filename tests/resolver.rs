@@ -0,0 +1,686 @@
+use std::{
+    future::Future,
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    pin::pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Wake},
+    thread::{self, Thread},
+    time::Duration,
+};
+
+use dns_clone::{
+    packet::{BytePacketBuffer, Class, DnsPacket, DnsRecord, EdnsOption, QueryType, ResultCode},
+    resolver::{
+        Answer, Limits, LookupError, QnameMinimisation, ResolveError, Resolver, ResolverConfig,
+    },
+    upstream::{UpstreamConfig, UpstreamError},
+};
+
+/// Wakes the thread blocking on a future.
+struct Unpark(Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Just enough of an executor to drive a single future to completion.
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = Arc::new(Unpark(thread::current())).into();
+    let mut cx = Context::from_waker(&waker);
+
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+/// A forwarder that answers `count` queries with whatever `respond` makes of them.
+fn forwarder(
+    count: usize,
+    respond: impl Fn(&DnsPacket) -> DnsPacket + Send + 'static,
+) -> (SocketAddr, thread::JoinHandle<()>) {
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let addr = socket.local_addr().unwrap();
+
+    let handle = thread::spawn(move || {
+        for _ in 0..count {
+            let mut buffer = BytePacketBuffer::new();
+            let (_, src) = socket.recv_from(&mut buffer.buf).unwrap();
+            let query = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+            let mut response = respond(&query);
+            response.header.id = query.header.id;
+            response.header.response = true;
+            response.questions = query.questions.clone();
+
+            let mut buffer = BytePacketBuffer::new();
+            response.write(&mut buffer).unwrap();
+            socket.send_to(&buffer.buf[0..buffer.pos], src).unwrap();
+        }
+    });
+
+    (addr, handle)
+}
+
+fn resolver(forwarder: SocketAddr) -> Resolver {
+    Resolver::new(ResolverConfig {
+        forwarders: vec![forwarder],
+        upstream: UpstreamConfig {
+            timeout: Duration::from_millis(200),
+            attempts: 1,
+            ..UpstreamConfig::default()
+        },
+        ..ResolverConfig::default()
+    })
+}
+
+/// A port that's free on the loopback addresses the authorities below listen on, so that they
+/// can all be reached on the same one.
+fn free_port() -> u16 {
+    UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// An authoritative server at `ip`, answering every question with whatever `respond` makes of
+/// it, for as long as the tests run. Gives back how many queries it got.
+fn authority(
+    ip: Ipv4Addr,
+    port: u16,
+    respond: impl Fn(&str, QueryType) -> DnsPacket + Send + 'static,
+) -> Arc<AtomicUsize> {
+    let socket = UdpSocket::bind((ip, port)).unwrap();
+    let count = Arc::new(AtomicUsize::new(0));
+
+    let queries = Arc::clone(&count);
+    thread::spawn(move || loop {
+        let mut buffer = BytePacketBuffer::new();
+        let (_, src) = socket.recv_from(&mut buffer.buf).unwrap();
+        let query = DnsPacket::from_buffer(&mut buffer).unwrap();
+        queries.fetch_add(1, Ordering::SeqCst);
+
+        let question = &query.questions[0];
+        let mut response = respond(&question.name, question.qtype);
+        response.header.id = query.header.id;
+        response.header.response = true;
+        response.questions = query.questions.clone();
+
+        let mut buffer = BytePacketBuffer::new();
+        response.write(&mut buffer).unwrap();
+        socket.send_to(&buffer.buf[0..buffer.pos], src).unwrap();
+    });
+
+    count
+}
+
+/// A resolver that recurses from the root server at `root`, sending every name in full.
+fn recursor(root: Ipv4Addr, port: u16) -> Resolver {
+    limited(root, port, Limits::default())
+}
+
+fn limited(root: Ipv4Addr, port: u16, limits: Limits) -> Resolver {
+    Resolver::new(ResolverConfig {
+        root_hints: vec![root.into()],
+        port,
+        qname_minimisation: QnameMinimisation::Off,
+        limits,
+        upstream: UpstreamConfig {
+            timeout: Duration::from_millis(200),
+            attempts: 1,
+            ..UpstreamConfig::default()
+        },
+        ..ResolverConfig::default()
+    })
+}
+
+/// A resolver that recurses from the root server at `root`, minimising as `mode` says.
+fn minimising(root: Ipv4Addr, port: u16, mode: QnameMinimisation) -> Resolver {
+    Resolver::new(ResolverConfig {
+        root_hints: vec![root.into()],
+        port,
+        qname_minimisation: mode,
+        upstream: UpstreamConfig {
+            timeout: Duration::from_millis(200),
+            attempts: 1,
+            ..UpstreamConfig::default()
+        },
+        ..ResolverConfig::default()
+    })
+}
+
+/// The questions a server was asked, in order.
+type Questions = Arc<Mutex<Vec<(String, QueryType)>>>;
+
+/// A root server at `root` delegating `example.com` to `child`, and the server at `child`
+/// answering with whatever `respond` makes of the queries. Gives back the questions each got.
+fn delegated(
+    root: Ipv4Addr,
+    child: Ipv4Addr,
+    port: u16,
+    respond: impl Fn(&str, QueryType) -> DnsPacket + Send + 'static,
+) -> [Questions; 2] {
+    let seen = [Arc::default(), Arc::default()];
+
+    let questions: Questions = Arc::clone(&seen[0]);
+    authority(root, port, move |name, qtype| {
+        questions.lock().unwrap().push((name.to_string(), qtype));
+        if name == "example.com" || name.ends_with(".example.com") {
+            referral(
+                vec![ns("example.com", "ns.example.com")],
+                vec![a("ns.example.com", child)],
+            )
+        } else {
+            DnsPacket::new()
+        }
+    });
+
+    let questions: Questions = Arc::clone(&seen[1]);
+    authority(child, port, move |name, qtype| {
+        questions.lock().unwrap().push((name.to_string(), qtype));
+        respond(name, qtype)
+    });
+
+    seen
+}
+
+/// The limit a lookup of `qname` ran into.
+fn limit_hit(resolver: &Resolver, qname: &str) -> ResolveError {
+    let err = resolver.query(qname, QueryType::A).unwrap_err();
+    assert_eq!(
+        err.extended_error(),
+        Some(EdnsOption::extended_error(22, &err.to_string()))
+    );
+
+    match err {
+        LookupError::Limit(limit) => limit,
+        other => panic!("Expected to run out of budget, got {:?}", other),
+    }
+}
+
+fn ns(domain: &str, host: &str) -> DnsRecord {
+    DnsRecord::NS {
+        domain: domain.to_string(),
+        class: Class::IN,
+        host: host.to_string(),
+        ttl: 3600,
+    }
+}
+
+fn a(domain: &str, addr: Ipv4Addr) -> DnsRecord {
+    DnsRecord::A {
+        domain: domain.to_string(),
+        class: Class::IN,
+        addr,
+        ttl: 3600,
+    }
+}
+
+/// A response referring the client to the servers in `ns`, with glue for them in `glue`.
+fn referral(ns: Vec<DnsRecord>, glue: Vec<DnsRecord>) -> DnsPacket {
+    let mut response = DnsPacket::new();
+    response.authorities = ns;
+    response.resources = glue;
+    response
+}
+
+fn soa() -> DnsRecord {
+    DnsRecord::SOA {
+        domain: "example.com".to_string(),
+        class: Class::IN,
+        m_name: "ns.example.com".to_string(),
+        r_name: "hostmaster.example.com".to_string(),
+        serial: 1,
+        refresh: 3600,
+        retry: 600,
+        expire: 86400,
+        minimum: 300,
+        ttl: 3600,
+    }
+}
+
+#[test]
+fn answers_are_cached() {
+    let addr = Ipv4Addr::new(192, 0, 2, 1);
+    let (forwarder, handle) = forwarder(1, move |query| {
+        let mut response = DnsPacket::new();
+        response.answers.push(DnsRecord::A {
+            domain: query.questions[0].name.clone(),
+            class: Class::IN,
+            addr,
+            ttl: 60,
+        });
+        response
+    });
+    let resolver = resolver(forwarder);
+
+    let answer = block_on(resolver.resolve("www.example.com", QueryType::A)).unwrap();
+    assert_eq!(answer.addresses(), vec![addr]);
+    handle.join().unwrap();
+
+    // The forwarder is gone, so this can only come from the cache.
+    let answer = block_on(resolver.resolve("WWW.Example.com.", QueryType::A)).unwrap();
+    assert_eq!(answer.addresses(), vec![addr]);
+    assert!(answer.records()[0].ttl() <= 60);
+
+    let err = block_on(resolver.resolve("mail.example.com", QueryType::A)).unwrap_err();
+    assert!(matches!(
+        err,
+        LookupError::Upstream(UpstreamError::Timeout { .. })
+    ));
+    assert!(err.extended_error().is_some());
+}
+
+#[test]
+fn negative_answers_come_with_the_soa() {
+    let (forwarder, handle) = forwarder(3, |query| {
+        let mut response = DnsPacket::new();
+        match query.questions[0].name.as_str() {
+            "www.example.com" => response.authorities.push(soa()),
+            "none.example.com" => {
+                response.header.rescode = ResultCode::NXDOMAIN;
+                response.authorities.push(soa());
+            }
+            _ => response.header.rescode = ResultCode::SERVFAIL,
+        }
+        response
+    });
+    let resolver = resolver(forwarder);
+
+    let answer = block_on(resolver.resolve("www.example.com", QueryType::AAAA)).unwrap();
+    assert_eq!(answer, Answer::NoData { soa: Some(soa()) });
+
+    let answer = block_on(resolver.resolve("none.example.com", QueryType::A)).unwrap();
+    assert_eq!(answer, Answer::NxDomain { soa: Some(soa()) });
+    assert!(answer.records().is_empty());
+
+    let err = block_on(resolver.resolve("broken.example.com", QueryType::A)).unwrap_err();
+    assert_eq!(err, LookupError::Rcode(ResultCode::SERVFAIL));
+    handle.join().unwrap();
+}
+
+#[test]
+fn resolutions_wait_for_a_free_worker() {
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let forwarder = socket.local_addr().unwrap();
+    let addr = Ipv4Addr::new(192, 0, 2, 1);
+
+    // Queries are held until no more arrive, to see how many are out at once.
+    let responder = thread::spawn(move || {
+        socket
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let (mut answered, mut most) = (0, 0);
+        let mut pending = Vec::new();
+        while answered < 6 {
+            let mut buffer = BytePacketBuffer::new();
+            if let Ok((_, src)) = socket.recv_from(&mut buffer.buf) {
+                pending.push((DnsPacket::from_buffer(&mut buffer).unwrap(), src));
+                continue;
+            }
+
+            most = most.max(pending.len());
+            for (query, src) in pending.drain(..) {
+                let mut response = DnsPacket::new();
+                response.header.id = query.header.id;
+                response.header.response = true;
+                response.answers.push(a(&query.questions[0].name, addr));
+                response.questions = query.questions;
+
+                let mut buffer = BytePacketBuffer::new();
+                response.write(&mut buffer).unwrap();
+                socket.send_to(&buffer.buf[0..buffer.pos], src).unwrap();
+                answered += 1;
+            }
+        }
+
+        most
+    });
+
+    let resolver = Resolver::new(ResolverConfig {
+        forwarders: vec![forwarder],
+        workers: 2,
+        upstream: UpstreamConfig {
+            timeout: Duration::from_secs(5),
+            attempts: 1,
+            ..UpstreamConfig::default()
+        },
+        ..ResolverConfig::default()
+    });
+    let resolutions: Vec<_> = (0..6)
+        .map(|n| resolver.resolve(&format!("host{}.example.com", n), QueryType::A))
+        .collect();
+    for resolution in resolutions {
+        assert_eq!(block_on(resolution).unwrap().addresses(), vec![addr]);
+    }
+
+    assert_eq!(responder.join().unwrap(), 2);
+}
+
+#[test]
+fn out_of_bailiwick_records_in_referrals_are_ignored() {
+    let port = free_port();
+    let (root, org, example, victim) = (
+        Ipv4Addr::new(127, 0, 0, 2),
+        Ipv4Addr::new(127, 0, 0, 3),
+        Ipv4Addr::new(127, 0, 0, 4),
+        Ipv4Addr::new(127, 0, 0, 66),
+    );
+
+    authority(root, port, move |name, _| {
+        if name.ends_with("org") {
+            referral(vec![ns("org", "ns.org")], vec![a("ns.org", org)])
+        } else {
+            let mut response = DnsPacket::new();
+            response.header.rescode = ResultCode::NXDOMAIN;
+            response
+        }
+    });
+    // The servers for `org` try to slip in servers and addresses for a zone they have no say
+    // over, both as glue for a name server of `example.org` and on their own.
+    authority(org, port, move |_, _| {
+        referral(
+            vec![
+                ns("example.org", "ns.example.org"),
+                ns("example.org", "ns.victim.com"),
+                ns("victim.com", "ns.victim.com"),
+            ],
+            vec![
+                a("ns.example.org", example),
+                a("ns.victim.com", victim),
+                a("www.victim.com", victim),
+            ],
+        )
+    });
+    authority(example, port, |name, _| {
+        let mut response = DnsPacket::new();
+        response.answers.push(a(name, Ipv4Addr::new(192, 0, 2, 1)));
+        response
+    });
+    let poisoned = authority(victim, port, |name, _| {
+        let mut response = DnsPacket::new();
+        response
+            .answers
+            .push(a(name, Ipv4Addr::new(203, 0, 113, 66)));
+        response
+    });
+
+    let resolver = recursor(root, port);
+    let answer = block_on(resolver.resolve("www.example.org", QueryType::A)).unwrap();
+    assert_eq!(answer.addresses(), vec![IpAddr::from([192, 0, 2, 1])]);
+
+    // Neither the address slipped in nor the server is used, or remembered for later.
+    let answer = block_on(resolver.resolve("www.victim.com", QueryType::A)).unwrap();
+    assert_eq!(answer, Answer::NxDomain { soa: None });
+    assert_eq!(poisoned.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn delegation_loops_run_out_of_budget() {
+    let port = free_port();
+    let root = Ipv4Addr::new(127, 0, 0, 5);
+
+    // Each zone is served by a name server in the other one, and nobody has any glue.
+    authority(root, port, |name, _| {
+        if name.ends_with("loop.test") {
+            referral(vec![ns("loop.test", "ns.loop.other")], Vec::new())
+        } else {
+            referral(vec![ns("loop.other", "ns.loop.test")], Vec::new())
+        }
+    });
+
+    let resolver = recursor(root, port);
+    assert_eq!(
+        limit_hit(&resolver, "www.loop.test"),
+        ResolveError::TooManyNsResolutions
+    );
+
+    let resolver = limited(
+        root,
+        port,
+        Limits {
+            max_queries: 3,
+            ..Limits::default()
+        },
+    );
+    assert_eq!(
+        limit_hit(&resolver, "www.loop.test"),
+        ResolveError::TooManyQueries
+    );
+}
+
+#[test]
+fn deep_delegations_and_long_chains_are_cut_short() {
+    let port = free_port();
+    let root = Ipv4Addr::new(127, 0, 0, 6);
+
+    // Every server refers to the next one down, one label at a time.
+    let levels = [
+        "deep.test",
+        "d.deep.test",
+        "c.d.deep.test",
+        "b.c.d.deep.test",
+    ];
+    for (level, zone) in levels.into_iter().enumerate() {
+        let server = Ipv4Addr::new(127, 0, 0, 6 + level as u8);
+        let next = Ipv4Addr::new(127, 0, 0, 7 + level as u8);
+        authority(server, port, move |name, _| {
+            if name.ends_with("deep.test") {
+                let host = format!("ns.{}", zone);
+                referral(vec![ns(zone, &host)], vec![a(&host, next)])
+            } else {
+                // Aliases of aliases, well beyond what we follow.
+                let mut response = DnsPacket::new();
+                for link in 0..6 {
+                    response.answers.push(DnsRecord::CNAME {
+                        domain: format!("{}.chain.test", link),
+                        class: Class::IN,
+                        host: format!("{}.chain.test", link + 1),
+                        ttl: 300,
+                    });
+                }
+                response
+            }
+        });
+    }
+
+    let limits = Limits {
+        max_referrals: 2,
+        max_cname_chain: 3,
+        ..Limits::default()
+    };
+    let resolver = limited(root, port, limits);
+    assert_eq!(
+        limit_hit(&resolver, "www.a.b.c.d.deep.test"),
+        ResolveError::TooManyReferrals
+    );
+    assert_eq!(
+        limit_hit(&resolver, "0.chain.test"),
+        ResolveError::CnameChainTooLong
+    );
+}
+
+#[test]
+fn aliases_to_names_without_the_type_are_nodata() {
+    let port = free_port();
+    let root = Ipv4Addr::new(127, 0, 0, 19);
+
+    // The target exists, just without any `A` records.
+    let queries = authority(root, port, |name, _| {
+        let mut response = DnsPacket::new();
+        if name == "alias.example.com" {
+            response.answers.push(DnsRecord::CNAME {
+                domain: name.to_string(),
+                class: Class::IN,
+                host: "target.example.net".to_string(),
+                ttl: 300,
+            });
+        } else {
+            response.authorities.push(soa());
+        }
+        response
+    });
+
+    let resolver = recursor(root, port);
+    let response = resolver.query("alias.example.com", QueryType::A).unwrap();
+    assert_eq!(response.header.rescode, ResultCode::NOERROR);
+    assert_eq!(response.answers.len(), 1);
+    assert_eq!(response.answers[0].qtype(), QueryType::CNAME);
+    assert_eq!(response.authorities, vec![soa()]);
+    // The alias, then its target, once.
+    assert_eq!(queries.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn resolutions_give_up_at_the_deadline() {
+    let port = free_port();
+    let root = Ipv4Addr::new(127, 0, 0, 12);
+
+    // Listening, but never answering.
+    let _silent = UdpSocket::bind((root, port)).unwrap();
+    let resolver = Resolver::new(ResolverConfig {
+        root_hints: vec![root.into()],
+        port,
+        limits: Limits {
+            timeout: Duration::from_millis(300),
+            ..Limits::default()
+        },
+        upstream: UpstreamConfig {
+            timeout: Duration::from_millis(200),
+            attempts: 5,
+            ..UpstreamConfig::default()
+        },
+        ..ResolverConfig::default()
+    });
+
+    assert_eq!(
+        limit_hit(&resolver, "www.example.com"),
+        ResolveError::DeadlineExceeded
+    );
+}
+
+#[test]
+fn minimised_queries_reveal_a_label_at_a_time() {
+    let port = free_port();
+    let addr = Ipv4Addr::new(192, 0, 2, 1);
+    let [root, child] = delegated(
+        Ipv4Addr::new(127, 0, 0, 13),
+        Ipv4Addr::new(127, 0, 0, 14),
+        port,
+        move |name, _| {
+            let mut response = DnsPacket::new();
+            if name == "a.b.example.com" {
+                response.answers.push(a(name, addr));
+            }
+            response
+        },
+    );
+    let resolver = minimising(
+        Ipv4Addr::new(127, 0, 0, 13),
+        port,
+        QnameMinimisation::Relaxed,
+    );
+
+    let response = resolver.query("a.b.example.com", QueryType::MX).unwrap();
+    assert_eq!(response.answers, vec![a("a.b.example.com", addr)]);
+
+    let question = |name: &str, qtype| (name.to_string(), qtype);
+    assert_eq!(
+        *root.lock().unwrap(),
+        vec![
+            question("com", QueryType::A),
+            question("example.com", QueryType::A),
+        ]
+    );
+    assert_eq!(
+        *child.lock().unwrap(),
+        vec![
+            question("b.example.com", QueryType::A),
+            question("a.b.example.com", QueryType::MX),
+        ]
+    );
+}
+
+#[test]
+fn long_names_are_revealed_in_larger_steps() {
+    let port = free_port();
+    let [_, child] = delegated(
+        Ipv4Addr::new(127, 0, 0, 15),
+        Ipv4Addr::new(127, 0, 0, 16),
+        port,
+        |_, _| DnsPacket::new(),
+    );
+    let resolver = minimising(
+        Ipv4Addr::new(127, 0, 0, 15),
+        port,
+        QnameMinimisation::Relaxed,
+    );
+
+    let qname = (1..=14)
+        .map(|n| format!("l{}", n))
+        .chain(["example".to_string(), "com".to_string()])
+        .collect::<Vec<_>>()
+        .join(".");
+    resolver.query(&qname, QueryType::A).unwrap();
+
+    // A label at a time at first, then more at once so that no more than ten minimised queries
+    // are made in all, counting the one to the root.
+    let labels: Vec<usize> = child
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, _)| name.split('.').count())
+        .collect();
+    assert_eq!(labels, vec![3, 4, 5, 7, 9, 11, 13, 15, 16]);
+}
+
+#[test]
+fn errors_for_minimised_queries_depend_on_the_mode() {
+    let port = free_port();
+    let addr = Ipv4Addr::new(192, 0, 2, 1);
+    let root = Ipv4Addr::new(127, 0, 0, 17);
+    let [_, child] = delegated(root, Ipv4Addr::new(127, 0, 0, 18), port, move |name, _| {
+        let mut response = DnsPacket::new();
+        match name {
+            // Servers that don't know about empty non-terminals.
+            "b.example.com" => {
+                response.header.rescode = ResultCode::NXDOMAIN;
+                response.authorities.push(soa());
+            }
+            "c.example.com" => response.header.rescode = ResultCode::REFUSED,
+            _ => response.answers.push(a(name, addr)),
+        }
+        response
+    });
+
+    // Relaxed resolvers try again with the full name.
+    let relaxed = minimising(root, port, QnameMinimisation::Relaxed);
+    let response = relaxed.query("a.b.example.com", QueryType::A).unwrap();
+    assert_eq!(response.answers, vec![a("a.b.example.com", addr)]);
+    let response = relaxed.query("a.c.example.com", QueryType::A).unwrap();
+    assert_eq!(response.answers, vec![a("a.c.example.com", addr)]);
+    assert_eq!(child.lock().unwrap().len(), 4);
+
+    // Strict ones take the error as the answer, but for the name asked about.
+    let strict = minimising(root, port, QnameMinimisation::Strict);
+    let response = strict.query("a.b.example.com", QueryType::A).unwrap();
+    assert_eq!(response.header.rescode, ResultCode::NXDOMAIN);
+    assert_eq!(response.questions[0].name, "a.b.example.com");
+    assert!(response.answers.is_empty());
+    assert_eq!(response.authorities, vec![soa()]);
+
+    let response = strict.query("a.c.example.com", QueryType::A).unwrap();
+    assert_eq!(response.header.rescode, ResultCode::SERVFAIL);
+    assert_eq!(response.questions[0].name, "a.c.example.com");
+    assert!(response.answers.is_empty());
+    assert_eq!(child.lock().unwrap().len(), 6);
+}
@@ -0,0 +1,347 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    path::Path,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use dns_clone::{
+    packet::{BytePacketBuffer, Class, DnsPacket, DnsRecord, EdnsOption, QueryType, ResultCode},
+    resolver::{Resolver, ResolverConfig},
+    rpz::{self, Action, Policy, Trigger},
+    upstream::UpstreamConfig,
+    zone::Zone,
+    zonefile,
+};
+
+const POLICY: &str = "\
+$ORIGIN rpz.local.
+$TTL 300
+@                 SOA   localhost. hostmaster 1 3600 600 86400 60
+@                 NS    localhost.
+bad.example       CNAME .
+*.bad.example     CNAME .
+ok.bad.example    CNAME rpz-passthru.
+empty.example     CNAME *.
+silent.example    CNAME rpz-drop.
+tcp.example       CNAME rpz-tcp-only.
+garden.example    A     192.0.2.80
+garden.example    TXT   \"walled\"
+moved.example     CNAME elsewhere.example.
+*.moved.example   CNAME *.walled.example.
+24.0.100.51.198.rpz-ip        CNAME .
+32.1.100.51.198.rpz-ip        CNAME rpz-passthru.
+48.zz.db8.2001.rpz-ip         CNAME *.
+32.9.2.0.192.rpz-client-ip    CNAME rpz-drop.
+ns.evil.example.rpz-nsdname   CNAME .
+32.5.113.0.203.rpz-nsip       CNAME *.
+";
+
+fn policy() -> Vec<Arc<Policy>> {
+    let policy = policy_zone("rpz.local", POLICY);
+    assert_eq!(policy.len(), 14);
+
+    vec![policy]
+}
+
+fn policy_zone(origin: &str, text: &str) -> Arc<Policy> {
+    let records = zonefile::parse_str(text, origin, Path::new(".")).unwrap();
+    Arc::new(Policy::new(&Zone::new(origin, records).unwrap()))
+}
+
+/// A forwarder that answers with whatever `respond` makes of the queries, for as long as the
+/// tests run.
+fn forwarder(respond: impl Fn(&str, QueryType) -> DnsPacket + Send + 'static) -> SocketAddr {
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let addr = socket.local_addr().unwrap();
+
+    thread::spawn(move || loop {
+        let mut buffer = BytePacketBuffer::new();
+        let (_, src) = socket.recv_from(&mut buffer.buf).unwrap();
+        let query = DnsPacket::from_buffer(&mut buffer).unwrap();
+        let question = &query.questions[0];
+
+        let mut response = respond(&question.name, question.qtype);
+        response.header.id = query.header.id;
+        response.header.response = true;
+        response.questions = query.questions.clone();
+
+        let mut buffer = BytePacketBuffer::new();
+        response.write(&mut buffer).unwrap();
+        socket.send_to(&buffer.buf[0..buffer.pos], src).unwrap();
+    });
+
+    addr
+}
+
+fn resolver(forwarder: SocketAddr) -> Resolver {
+    Resolver::new(ResolverConfig {
+        forwarders: vec![forwarder],
+        upstream: UpstreamConfig {
+            timeout: Duration::from_millis(200),
+            attempts: 1,
+            ..UpstreamConfig::default()
+        },
+        ..ResolverConfig::default()
+    })
+}
+
+fn a(domain: &str, addr: Ipv4Addr) -> DnsRecord {
+    DnsRecord::A {
+        domain: domain.to_string(),
+        class: Class::IN,
+        addr,
+        ttl: 300,
+    }
+}
+
+fn cname(domain: &str, host: &str) -> DnsRecord {
+    DnsRecord::CNAME {
+        domain: domain.to_string(),
+        class: Class::IN,
+        host: host.to_string(),
+        ttl: 300,
+    }
+}
+
+#[test]
+fn triggers_are_matched_in_order_of_precedence() {
+    let policies = policy();
+    let client = IpAddr::from(Ipv4Addr::new(192, 0, 2, 1));
+    let check = |client: &IpAddr, name: &str| {
+        rpz::check_query(&policies, client, name).map(|hit| (hit.trigger, hit.action))
+    };
+
+    assert_eq!(
+        check(&client, "bad.example"),
+        Some((Trigger::Qname, Action::NxDomain))
+    );
+    assert_eq!(
+        check(&client, "a.b.bad.example"),
+        Some((Trigger::Qname, Action::NxDomain))
+    );
+    assert_eq!(
+        check(&client, "ok.bad.example"),
+        Some((Trigger::Qname, Action::Passthru))
+    );
+    assert_eq!(check(&client, "good.example"), None);
+    assert_eq!(check(&client, "tcp.example"), None);
+
+    // The client's address beats the name it asks about.
+    let dropped = "::ffff:192.0.2.9".parse().unwrap();
+    assert_eq!(
+        check(&dropped, "bad.example"),
+        Some((Trigger::ClientIp, Action::Drop))
+    );
+
+    // Answers are checked for aliases and addresses, the most specific network winning. None of
+    // these need the servers of the zone looked up.
+    let unused = resolver(SocketAddr::from((Ipv4Addr::LOCALHOST, 9)));
+    let check = |answers: Vec<DnsRecord>| {
+        let mut response = DnsPacket::new();
+        response.answers = answers;
+        rpz::check_response(&policies, &client, "www.example.com", &response, &unused)
+            .map(|hit| (hit.trigger, hit.action))
+    };
+
+    assert_eq!(
+        check(vec![cname("www.example.com", "cdn.bad.example")]),
+        Some((Trigger::Qname, Action::NxDomain))
+    );
+    assert_eq!(
+        check(vec![a("www.example.com", Ipv4Addr::new(198, 51, 100, 7))]),
+        Some((Trigger::ResponseIp, Action::NxDomain))
+    );
+    assert_eq!(
+        check(vec![a("www.example.com", Ipv4Addr::new(198, 51, 100, 1))]),
+        Some((Trigger::ResponseIp, Action::Passthru))
+    );
+    assert_eq!(
+        check(vec![DnsRecord::AAAA {
+            domain: "www.example.com".to_string(),
+            class: Class::IN,
+            addr: "2001:db8::1".parse().unwrap(),
+            ttl: 300,
+        }]),
+        Some((Trigger::ResponseIp, Action::NoData))
+    );
+}
+
+#[test]
+fn answers_are_rewritten() {
+    let policies = policy();
+    let forwarder = forwarder(|name, qtype| {
+        let mut response = DnsPacket::new();
+        let ns = |domain: &str, host: &str| DnsRecord::NS {
+            domain: domain.to_string(),
+            class: Class::IN,
+            host: host.to_string(),
+            ttl: 300,
+        };
+
+        match (name, qtype) {
+            ("elsewhere.example", QueryType::A) => {
+                response
+                    .answers
+                    .push(a("elsewhere.example", Ipv4Addr::new(192, 0, 2, 99)));
+            }
+            // Names below an apex only tell where it is.
+            ("www.evil.example", QueryType::NS) => {
+                response.authorities.push(DnsRecord::SOA {
+                    domain: "evil.example".to_string(),
+                    class: Class::IN,
+                    m_name: "ns.evil.example".to_string(),
+                    r_name: "hostmaster.evil.example".to_string(),
+                    serial: 1,
+                    refresh: 3600,
+                    retry: 600,
+                    expire: 86400,
+                    minimum: 300,
+                    ttl: 300,
+                });
+            }
+            ("evil.example", QueryType::NS) => {
+                response.answers.push(ns("evil.example", "ns.evil.example"))
+            }
+            ("shady.example", QueryType::NS) => response
+                .answers
+                .push(ns("shady.example", "ns.shady.example")),
+            ("ns.shady.example", QueryType::A) => {
+                response
+                    .answers
+                    .push(a("ns.shady.example", Ipv4Addr::new(203, 0, 113, 5)));
+            }
+            _ => {}
+        }
+
+        response
+    });
+    let resolver = resolver(forwarder);
+    let client = IpAddr::from(Ipv4Addr::LOCALHOST);
+    let rewrite = |name: &str, qtype: QueryType| {
+        rpz::check_query(&policies, &client, name)
+            .unwrap()
+            .rewrite(name, qtype, &resolver)
+            .unwrap()
+    };
+
+    let nxdomain = rewrite("bad.example", QueryType::A).unwrap();
+    assert_eq!(nxdomain.header.rescode, ResultCode::NXDOMAIN);
+    assert_eq!(nxdomain.authorities.len(), 1);
+    assert_eq!(nxdomain.authorities[0].domain(), "rpz.local");
+    assert_eq!(
+        nxdomain.get_opt(),
+        Some(&DnsRecord::OPT {
+            packet_len: 512,
+            flags: 0,
+            options: vec![EdnsOption::extended_error(15, "Blocked by rpz.local")],
+        })
+    );
+
+    let nodata = rewrite("empty.example", QueryType::A).unwrap();
+    assert_eq!(nodata.header.rescode, ResultCode::NOERROR);
+    assert!(nodata.answers.is_empty());
+    assert!(rewrite("silent.example", QueryType::A).is_none());
+
+    // Local data is answered for the type asked about only, as if the name owned it.
+    let garden = rewrite("garden.example", QueryType::A).unwrap();
+    assert_eq!(
+        garden.answers,
+        vec![a("garden.example", Ipv4Addr::new(192, 0, 2, 80))]
+    );
+    assert_eq!(
+        garden.get_opt(),
+        Some(&DnsRecord::OPT {
+            packet_len: 512,
+            flags: 0,
+            options: vec![EdnsOption::extended_error(4, "Rewritten by rpz.local")],
+        })
+    );
+    assert!(rewrite("garden.example", QueryType::MX)
+        .unwrap()
+        .answers
+        .is_empty());
+
+    // Rewrites to other names are followed, with the name asked about standing in for `*`.
+    assert_eq!(
+        rewrite("moved.example", QueryType::A).unwrap().answers,
+        vec![
+            cname("moved.example", "elsewhere.example"),
+            a("elsewhere.example", Ipv4Addr::new(192, 0, 2, 99)),
+        ]
+    );
+    assert_eq!(
+        rewrite("www.moved.example", QueryType::CNAME)
+            .unwrap()
+            .answers,
+        vec![cname(
+            "www.moved.example",
+            "www.moved.example.walled.example"
+        )]
+    );
+
+    // The servers of the zone are looked up to check them against the policy.
+    let check = |name: &str| {
+        rpz::check_response(&policies, &client, name, &DnsPacket::new(), &resolver)
+            .map(|hit| (hit.trigger, hit.action))
+    };
+    assert_eq!(
+        check("www.evil.example"),
+        Some((Trigger::NsDname, Action::NxDomain))
+    );
+    assert_eq!(
+        check("shady.example"),
+        Some((Trigger::NsIp, Action::NoData))
+    );
+    assert_eq!(check("fine.example"), None);
+}
+
+#[test]
+fn earlier_policy_zones_win_whatever_the_trigger() {
+    let header = "$TTL 300\n@ SOA localhost. hostmaster 1 3600 600 86400 60\n@ NS localhost.\n";
+    let policies = vec![
+        policy_zone(
+            "first.rpz",
+            &format!(
+                "{}24.0.100.51.198.rpz-ip CNAME .\nfine.example CNAME rpz-passthru.\n",
+                header
+            ),
+        ),
+        policy_zone(
+            "second.rpz",
+            &format!("{}www.example CNAME *.\nfine.example CNAME .\n", header),
+        ),
+    ];
+    let client = IpAddr::from(Ipv4Addr::LOCALHOST);
+    let unused = resolver(SocketAddr::from((Ipv4Addr::LOCALHOST, 9)));
+    let check = |addr: Ipv4Addr| {
+        let mut response = DnsPacket::new();
+        response.answers = vec![a("www.example", addr)];
+        rpz::check_response(&policies, &client, "www.example", &response, &unused)
+            .map(|hit| (hit.policy.origin.clone(), hit.trigger, hit.action))
+    };
+
+    // The first zone might still be set off by the answer, so the name can't be blocked by the
+    // second one yet.
+    assert!(rpz::check_query(&policies, &client, "www.example").is_none());
+    assert_eq!(
+        check(Ipv4Addr::new(198, 51, 100, 7)),
+        Some((
+            "first.rpz".to_string(),
+            Trigger::ResponseIp,
+            Action::NxDomain
+        ))
+    );
+    assert_eq!(
+        check(Ipv4Addr::new(192, 0, 2, 7)),
+        Some(("second.rpz".to_string(), Trigger::Qname, Action::NoData))
+    );
+
+    // Names are still checked zone by zone before resolving.
+    let hit = rpz::check_query(&policies, &client, "fine.example").unwrap();
+    assert_eq!(
+        (hit.policy.origin.as_str(), hit.action),
+        ("first.rpz", Action::Passthru)
+    );
+}
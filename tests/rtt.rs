@@ -0,0 +1,120 @@
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    thread,
+    time::Duration,
+};
+
+use dns_clone::rtt::RttTable;
+
+fn server(n: u32) -> IpAddr {
+    Ipv4Addr::from(0xC000_0200 + n).into()
+}
+
+fn ms(ms: u64) -> Duration {
+    Duration::from_millis(ms)
+}
+
+#[test]
+fn the_fastest_server_is_asked_first() {
+    let rtt = RttTable::new();
+    let servers = [server(1), server(2), server(3)];
+    rtt.record_reply(server(1), ms(50));
+    rtt.record_reply(server(2), ms(10));
+    rtt.record_reply(server(3), ms(100));
+
+    // Now and then another server is probed, but not often.
+    let first = (0..20)
+        .filter(|_| rtt.order(&servers, |s| *s)[0] == server(2))
+        .count();
+    assert!(first >= 15, "fastest server first only {} times", first);
+
+    // Replies after the first move the estimate an eighth of the way.
+    rtt.record_reply(server(5), ms(10));
+    rtt.record_reply(server(5), ms(90));
+    assert_eq!(rtt.srtt(server(5)), Some((ms(10) * 7 + ms(90)) / 8));
+
+    // Servers we know nothing about are tried early.
+    assert!(rtt.srtt(server(4)).is_none());
+    rtt.order(&[server(4)], |s| *s);
+    assert!(rtt.srtt(server(4)).unwrap() < ms(32));
+}
+
+#[test]
+fn servers_passed_over_are_given_another_chance() {
+    let rtt = RttTable::new();
+    let servers = [server(1), server(2), server(3)];
+    rtt.record_reply(server(1), ms(10));
+    rtt.record_reply(server(2), ms(50));
+    rtt.record_reply(server(3), ms(100));
+
+    // Whoever was picked keeps its estimate, the others look a little better each time.
+    let ordered = rtt.order(&servers, |s| *s);
+    let before = [ms(10), ms(50), ms(100)];
+    for (i, server) in servers.iter().enumerate() {
+        let srtt = rtt.srtt(*server).unwrap();
+        if *server == ordered[0] {
+            assert_eq!(srtt, before[i]);
+        } else {
+            assert_eq!(srtt, before[i].mul_f64(0.98));
+        }
+    }
+}
+
+#[test]
+fn timeouts_move_servers_down_the_order() {
+    let rtt = RttTable::new();
+    let servers = [server(1), server(2)];
+    rtt.record_reply(server(1), ms(10));
+    rtt.record_reply(server(2), ms(20));
+
+    rtt.record_timeout(server(1), ms(200));
+    assert_eq!(rtt.srtt(server(1)), Some(ms(200)));
+    rtt.record_timeout(server(1), ms(200));
+    assert_eq!(rtt.srtt(server(1)), Some(ms(400)));
+
+    let first = (0..20)
+        .filter(|_| rtt.order(&servers, |s| *s)[0] == server(2))
+        .count();
+    assert!(first >= 15, "fastest server first only {} times", first);
+
+    // However many timeouts pile up, the estimate stays within bounds.
+    for _ in 0..20 {
+        rtt.record_timeout(server(1), Duration::from_secs(4));
+    }
+    assert_eq!(rtt.srtt(server(1)), Some(Duration::from_secs(10)));
+}
+
+#[test]
+fn slow_servers_are_still_probed() {
+    let rtt = RttTable::new();
+    let servers = [server(1), server(2)];
+
+    let mut probes = 0;
+    for _ in 0..1000 {
+        // Keep the slow server slow, rather than let its estimate decay.
+        rtt.record_reply(server(1), ms(1));
+        rtt.record_timeout(server(2), Duration::from_secs(10));
+
+        if rtt.order(&servers, |s| *s)[0] == server(2) {
+            probes += 1;
+        }
+    }
+
+    // One in twenty, give or take.
+    assert!((10..=120).contains(&probes), "{} probes", probes);
+}
+
+#[test]
+fn the_least_recently_used_servers_are_forgotten() {
+    let rtt = RttTable::new();
+    rtt.record_reply(server(0), ms(10));
+    thread::sleep(ms(2));
+
+    for n in 1..=4096 {
+        rtt.record_reply(server(n), ms(10));
+    }
+
+    assert_eq!(rtt.srtt(server(0)), None);
+    assert_eq!(rtt.srtt(server(1)), Some(ms(10)));
+    assert_eq!(rtt.srtt(server(4096)), Some(ms(10)));
+}
@@ -0,0 +1,372 @@
+use std::{
+    env, fs,
+    net::{Ipv4Addr, UdpSocket},
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    thread,
+    time::Duration,
+};
+
+use dns_clone::packet::{
+    BytePacketBuffer, Class, DnsPacket, DnsQuestion, DnsRecord, EdnsOption, Opcode, QueryType,
+    ResultCode,
+};
+
+const ZONE: &str = "\
+$ORIGIN example.com.
+$TTL 3600
+@    IN SOA ns hostmaster 1 3600 600 86400 300
+@    IN NS  ns
+www  IN A   192.0.2.1
+mail IN A   192.0.2.2
+";
+
+/// The server binary, running for as long as this is around.
+struct Server {
+    child: Child,
+    port: u16,
+    dir: PathBuf,
+}
+
+impl Server {
+    fn start(name: &str, extra_args: &[&str]) -> Self {
+        let dir = env::temp_dir().join(format!("dns-clone-server-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let zone = dir.join("example.com.zone");
+        fs::write(&zone, ZONE).unwrap();
+
+        // Good enough to find a port nobody else is using.
+        let port = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let child = Command::new(env!("CARGO_BIN_EXE_dns-clone"))
+            .arg("--port")
+            .arg(port.to_string())
+            .arg("--zone")
+            .arg(format!("example.com={}", zone.display()))
+            .args(extra_args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let server = Self { child, port, dir };
+
+        // Wait for the server to come up.
+        for _ in 0..50 {
+            let mut probe = query(vec![question("www.example.com")]);
+            if server.try_send(&mut probe).is_some() {
+                return server;
+            }
+        }
+        panic!("Server didn't come up");
+    }
+
+    fn try_send(&self, packet: &mut DnsPacket) -> Option<BytePacketBuffer> {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        socket
+            .send_to(&buffer.buf[0..buffer.pos], (Ipv4Addr::LOCALHOST, self.port))
+            .unwrap();
+
+        let mut response = BytePacketBuffer::new();
+        socket.recv_from(&mut response.buf).ok()?;
+        Some(response)
+    }
+
+    /// Send `packet` and return the response, along with its questions exactly as they came.
+    fn send(&self, packet: &mut DnsPacket) -> (DnsPacket, Vec<DnsQuestion>) {
+        let mut buffer = self.try_send(packet).expect("No response");
+        let questions = DnsPacket::questions_as_sent(&mut buffer).unwrap();
+        buffer.pos = 0;
+
+        (DnsPacket::from_buffer(&mut buffer).unwrap(), questions)
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn question(name: &str) -> DnsQuestion {
+    DnsQuestion::new(name.to_string(), QueryType::A)
+}
+
+fn query(questions: Vec<DnsQuestion>) -> DnsPacket {
+    let mut packet = DnsPacket::new();
+    packet.header.id = 4711;
+    packet.header.recursion_desired = true;
+    packet.questions = questions;
+
+    packet
+}
+
+#[test]
+fn queries_need_exactly_one_question() {
+    let server = Server::start("single", &[]);
+
+    let (response, questions) = server.send(&mut query(vec![]));
+    assert_eq!(response.header.rescode, ResultCode::FORMERR);
+    assert!(questions.is_empty());
+
+    let asked = vec![question("www.example.com"), question("mail.example.com")];
+    let (response, questions) = server.send(&mut query(asked.clone()));
+    assert_eq!(response.header.rescode, ResultCode::FORMERR);
+    assert!(response.answers.is_empty());
+    assert_eq!(questions, asked);
+}
+
+#[test]
+fn questions_are_echoed_exactly() {
+    let server = Server::start("echo", &[]);
+
+    let asked = vec![question("WwW.eXaMpLe.CoM")];
+    let (response, questions) = server.send(&mut query(asked.clone()));
+    assert_eq!(response.header.id, 4711);
+    assert_eq!(response.header.rescode, ResultCode::NOERROR);
+    assert_eq!(response.answers.len(), 1);
+    assert_eq!(questions, asked);
+}
+
+#[test]
+fn multiple_questions_are_answered_when_asked_to() {
+    let server = Server::start("multi", &["--multi-question"]);
+
+    let asked = vec![
+        question("www.example.com"),
+        question("Mail.Example.com"),
+        question("none.example.com"),
+    ];
+    let (response, questions) = server.send(&mut query(asked.clone()));
+    assert_eq!(questions, asked);
+    assert!(response.header.authoritative_answer);
+    // The answers for all names, with the first failure reported.
+    assert_eq!(response.header.rescode, ResultCode::NXDOMAIN);
+    assert_eq!(response.get_all_a().len(), 2);
+}
+
+#[test]
+fn local_records_override_the_zones() {
+    let hosts = env::temp_dir().join(format!("dns-clone-server-hosts-{}", std::process::id()));
+    fs::write(&hosts, "192.0.2.10 www.example.com\n").unwrap();
+    let server = Server::start(
+        "local",
+        &[
+            "--hosts",
+            hosts.to_str().unwrap(),
+            "--local-record",
+            "api.example.com A 192.0.2.20",
+        ],
+    );
+    fs::remove_file(&hosts).unwrap();
+
+    let (response, _) = server.send(&mut query(vec![question("www.example.com")]));
+    assert!(response.header.authoritative_answer);
+    assert_eq!(response.get_all_a(), vec![Ipv4Addr::new(192, 0, 2, 10)]);
+
+    let (response, _) = server.send(&mut query(vec![question("api.example.com")]));
+    assert_eq!(response.get_all_a(), vec![Ipv4Addr::new(192, 0, 2, 20)]);
+
+    // The rest of the zone is still there.
+    let (response, _) = server.send(&mut query(vec![question("mail.example.com")]));
+    assert_eq!(response.get_all_a(), vec![Ipv4Addr::new(192, 0, 2, 2)]);
+
+    let reverse = DnsQuestion::new("20.2.0.192.in-addr.arpa".to_string(), QueryType::PTR);
+    let (response, _) = server.send(&mut query(vec![reverse]));
+    assert_eq!(response.answers.len(), 1);
+    assert_eq!(response.answers[0].qtype(), QueryType::PTR);
+}
+
+#[test]
+fn blocked_names_are_not_resolved() {
+    let list = env::temp_dir().join(format!("dns-clone-blocklist-{}", std::process::id()));
+    fs::write(&list, "||ads.example^\n").unwrap();
+    let server = Server::start(
+        "blocklist",
+        &[
+            "--blocklist",
+            list.to_str().unwrap(),
+            "--block-response",
+            "null",
+        ],
+    );
+    fs::remove_file(&list).unwrap();
+
+    let mut request = query(vec![question("banner.ads.example")]);
+    request.resources.push(DnsRecord::OPT {
+        packet_len: 1232,
+        flags: 0,
+        options: Vec::new(),
+    });
+    let (response, _) = server.send(&mut request);
+    assert_eq!(response.header.rescode, ResultCode::NOERROR);
+    assert_eq!(response.get_all_a(), vec![Ipv4Addr::UNSPECIFIED]);
+    assert_eq!(
+        response.get_opt(),
+        Some(&DnsRecord::OPT {
+            packet_len: 512,
+            flags: 0,
+            options: vec![EdnsOption::extended_error(15, "Blocked by a blocklist")],
+        })
+    );
+}
+
+#[test]
+fn policy_zones_rewrite_answers() {
+    let zone = env::temp_dir().join(format!("dns-clone-rpz-{}.zone", std::process::id()));
+    fs::write(
+        &zone,
+        "\
+$ORIGIN rpz.local.
+$TTL 300
+@                 SOA   localhost. hostmaster 1 3600 600 86400 60
+blocked.example   CNAME .
+garden.example    A     192.0.2.80
+silent.example    CNAME rpz-drop.
+",
+    )
+    .unwrap();
+    let server = Server::start(
+        "rpz",
+        &[
+            "--zone",
+            &format!("rpz.local={}", zone.display()),
+            "--rpz",
+            "rpz.local",
+        ],
+    );
+    fs::remove_file(&zone).unwrap();
+
+    let (response, _) = server.send(&mut query(vec![question("blocked.example")]));
+    assert_eq!(response.header.rescode, ResultCode::NXDOMAIN);
+    assert!(!response.header.authoritative_answer);
+    assert_eq!(response.authorities[0].domain(), "rpz.local");
+
+    let (response, _) = server.send(&mut query(vec![question("garden.example")]));
+    assert_eq!(response.get_all_a(), vec![Ipv4Addr::new(192, 0, 2, 80)]);
+
+    assert!(server
+        .try_send(&mut query(vec![question("silent.example")]))
+        .is_none());
+
+    // Our own zones are left alone.
+    let (response, _) = server.send(&mut query(vec![question("www.example.com")]));
+    assert_eq!(response.get_all_a(), vec![Ipv4Addr::new(192, 0, 2, 1)]);
+}
+
+#[test]
+fn failed_resolutions_are_explained() {
+    // A forwarder that never answers.
+    let silent = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let forwarder = silent.local_addr().unwrap().to_string();
+    let server = Server::start("servfail", &["--forward", &forwarder]);
+
+    let mut request = query(vec![question("www.example.net")]);
+    request.resources.push(DnsRecord::OPT {
+        packet_len: 1232,
+        flags: 0,
+        options: Vec::new(),
+    });
+    let mut buffer = BytePacketBuffer::new();
+    request.write(&mut buffer).unwrap();
+
+    // Giving up on the forwarder takes a few rounds of retries.
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(15)))
+        .unwrap();
+    socket
+        .send_to(
+            &buffer.buf[0..buffer.pos],
+            (Ipv4Addr::LOCALHOST, server.port),
+        )
+        .unwrap();
+    let mut buffer = BytePacketBuffer::new();
+    socket.recv_from(&mut buffer.buf).unwrap();
+    let response = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+    assert_eq!(response.header.rescode, ResultCode::SERVFAIL);
+    let Some(DnsRecord::OPT { options, .. }) = response.get_opt() else {
+        panic!("No OPT record in {:?}", response);
+    };
+    assert_eq!(options.len(), 1);
+    assert_eq!(options[0].code, EdnsOption::EXTENDED_ERROR);
+    // No Reachable Authority
+    assert_eq!(&options[0].data[0..2], &22u16.to_be_bytes());
+}
+
+#[test]
+fn only_our_own_answers_are_authoritative() {
+    // A forwarder claiming to be authoritative for everything.
+    let upstream = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let forwarder = upstream.local_addr().unwrap().to_string();
+    thread::spawn(move || loop {
+        let mut buffer = BytePacketBuffer::new();
+        let Ok((_, src)) = upstream.recv_from(&mut buffer.buf) else {
+            return;
+        };
+        let mut packet = DnsPacket::from_buffer(&mut buffer).unwrap();
+        packet.header.response = true;
+        packet.header.authoritative_answer = true;
+        packet.answers = vec![DnsRecord::A {
+            domain: packet.questions[0].name.clone(),
+            class: Class::IN,
+            addr: Ipv4Addr::new(198, 51, 100, 1),
+            ttl: 300,
+        }];
+
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        let _ = upstream.send_to(&buffer.buf[0..buffer.pos], src);
+    });
+    let server = Server::start("authoritative", &["--forward", &forwarder]);
+
+    let (response, _) = server.send(&mut query(vec![question("www.example.com")]));
+    assert!(response.header.authoritative_answer);
+
+    // Neither when resolved, nor when the answer comes from the cache later.
+    for _ in 0..2 {
+        let (response, _) = server.send(&mut query(vec![question("www.example.net")]));
+        assert_eq!(response.get_all_a(), vec![Ipv4Addr::new(198, 51, 100, 1)]);
+        assert!(!response.header.authoritative_answer);
+    }
+}
+
+#[test]
+fn updates_leave_the_master_file_alone() {
+    let server = Server::start("update", &["--allow-update", "127.0.0.1"]);
+
+    // The zone section goes where the questions are, and the changes where the authorities are.
+    let mut update = DnsPacket::new();
+    update.header.id = 4711;
+    update.header.opcode = Opcode::UPDATE;
+    update.questions = vec![DnsQuestion::new("example.com".to_string(), QueryType::SOA)];
+    update.authorities = vec![DnsRecord::A {
+        domain: "new.example.com".to_string(),
+        class: Class::IN,
+        addr: Ipv4Addr::new(192, 0, 2, 9),
+        ttl: 300,
+    }];
+    let (response, _) = server.send(&mut update);
+    assert_eq!(response.header.rescode, ResultCode::NOERROR);
+
+    let (response, _) = server.send(&mut query(vec![question("new.example.com")]));
+    assert_eq!(response.get_all_a(), vec![Ipv4Addr::new(192, 0, 2, 9)]);
+
+    // The new version is saved next to the file, which stays as it was written.
+    let file = server.dir.join("example.com.zone");
+    assert_eq!(fs::read_to_string(&file).unwrap(), ZONE);
+    let saved = fs::read_to_string(server.dir.join("example.com.zone.dyn")).unwrap();
+    assert!(saved.contains("new.example.com."), "{}", saved);
+}
@@ -0,0 +1,214 @@
+use std::{
+    env, fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    path::PathBuf,
+    thread,
+    time::Duration,
+};
+
+use dns_clone::{
+    hosts::Hosts,
+    packet::{BytePacketBuffer, Class, DnsPacket, DnsRecord, QueryType, ResultCode},
+    stub::{ResolvConf, StubResolver},
+};
+
+/// Write `contents` to a file of its own for the test called `name`.
+fn fixture(name: &str, file: &str, contents: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("dns-clone-stub-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(file);
+    fs::write(&path, contents).unwrap();
+
+    path
+}
+
+#[test]
+fn resolv_conf_is_parsed() {
+    let path = fixture(
+        "parse",
+        "resolv.conf",
+        "\
+# Generated by hand
+; also a comment
+nameserver 192.0.2.53
+nameserver 2001:db8::53
+nameserver not-an-address
+nameserver 192.0.2.54:5353
+nameserver 192.0.2.55
+domain ignored.example
+search Corp.Example. example.net
+options ndots:2 timeout:1 attempts:9 rotate edns0
+",
+    );
+    let conf = ResolvConf::load(&path).unwrap();
+    fs::remove_dir_all(path.parent().unwrap()).unwrap();
+
+    assert_eq!(
+        conf.nameservers,
+        vec![
+            "192.0.2.53:53".parse().unwrap(),
+            "[2001:db8::53]:53".parse().unwrap(),
+            "192.0.2.54:5353".parse().unwrap(),
+        ]
+    );
+    assert_eq!(conf.search, vec!["corp.example", "example.net"]);
+    assert_eq!(conf.ndots, 2);
+    assert_eq!(conf.timeout, Duration::from_secs(1));
+    assert_eq!(conf.attempts, 5);
+    assert!(conf.rotate);
+
+    // Without any servers, the local host is asked.
+    assert_eq!(
+        ResolvConf::parse("search example.com"),
+        ResolvConf {
+            search: vec!["example.com".to_string()],
+            ..ResolvConf::default()
+        }
+    );
+}
+
+#[test]
+fn search_list_is_applied_by_the_number_of_dots() {
+    let conf = ResolvConf::parse("search a.example b.example\noptions ndots:2");
+    let resolver = StubResolver::new(conf, Hosts::new());
+
+    assert_eq!(
+        resolver.candidates("www"),
+        vec!["www.a.example", "www.b.example", "www"]
+    );
+    assert_eq!(
+        resolver.candidates("www.example.com"),
+        vec![
+            "www.example.com",
+            "www.example.com.a.example",
+            "www.example.com.b.example"
+        ]
+    );
+    assert_eq!(
+        resolver.candidates("WWW.example.com."),
+        vec!["www.example.com"]
+    );
+}
+
+#[test]
+fn names_are_looked_up_in_hosts_and_then_along_the_search_list() {
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let server: SocketAddr = socket.local_addr().unwrap();
+    let addr = Ipv4Addr::new(192, 0, 2, 1);
+
+    // Only www.corp.example has an address, and no name has any others.
+    let responder = thread::spawn(move || {
+        let mut asked = Vec::new();
+        for _ in 0..3 {
+            let mut buffer = BytePacketBuffer::new();
+            let (_, src) = socket.recv_from(&mut buffer.buf).unwrap();
+            let query = DnsPacket::from_buffer(&mut buffer).unwrap();
+            let question = query.questions[0].clone();
+
+            let mut response = DnsPacket::new();
+            response.header.id = query.header.id;
+            response.header.response = true;
+            response.questions.push(question.clone());
+            match (question.name.as_str(), question.qtype) {
+                ("www.corp.example", QueryType::A) => response.answers.push(DnsRecord::A {
+                    domain: question.name.clone(),
+                    class: Class::IN,
+                    addr,
+                    ttl: 60,
+                }),
+                ("www.corp.example", _) => {}
+                _ => response.header.rescode = ResultCode::NXDOMAIN,
+            }
+            asked.push((question.name, question.qtype));
+
+            let mut buffer = BytePacketBuffer::new();
+            response.write(&mut buffer).unwrap();
+            socket.send_to(&buffer.buf[0..buffer.pos], src).unwrap();
+        }
+
+        asked
+    });
+
+    let resolv_conf = fixture(
+        "lookup",
+        "resolv.conf",
+        &format!(
+            "nameserver {}\nsearch corp.example\noptions timeout:2",
+            server
+        ),
+    );
+    let hosts = fixture(
+        "lookup",
+        "hosts",
+        "127.0.0.1 localhost\n192.0.2.9 printer printer.corp.example # office\n",
+    );
+    let resolver = StubResolver::load(&resolv_conf, &hosts).unwrap();
+    fs::remove_dir_all(hosts.parent().unwrap()).unwrap();
+
+    let printer: IpAddr = Ipv4Addr::new(192, 0, 2, 9).into();
+    assert_eq!(resolver.lookup_ip("Printer").unwrap(), vec![printer]);
+    assert_eq!(resolver.lookup_ip("www").unwrap(), vec![IpAddr::from(addr)]);
+
+    assert_eq!(
+        responder.join().unwrap(),
+        vec![
+            ("www.corp.example".to_string(), QueryType::A),
+            ("www.corp.example".to_string(), QueryType::AAAA),
+            ("www".to_string(), QueryType::AAAA),
+        ]
+    );
+}
+
+#[test]
+fn failed_candidates_dont_end_the_search() {
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let server: SocketAddr = socket.local_addr().unwrap();
+    let addr = Ipv4Addr::new(192, 0, 2, 1);
+
+    // The servers of the first domain in the search list never reply.
+    thread::spawn(move || loop {
+        let mut buffer = BytePacketBuffer::new();
+        let (_, src) = socket.recv_from(&mut buffer.buf).unwrap();
+        let query = DnsPacket::from_buffer(&mut buffer).unwrap();
+        let question = query.questions[0].clone();
+        if question.name.ends_with("down.example") {
+            continue;
+        }
+
+        let mut response = DnsPacket::new();
+        response.header.id = query.header.id;
+        response.header.response = true;
+        response.questions.push(question.clone());
+        match question.name.as_str() {
+            "www.up.example" => response.answers.push(DnsRecord::A {
+                domain: question.name.clone(),
+                class: Class::IN,
+                addr,
+                ttl: 60,
+            }),
+            _ => response.header.rescode = ResultCode::NXDOMAIN,
+        }
+
+        let mut buffer = BytePacketBuffer::new();
+        response.write(&mut buffer).unwrap();
+        socket.send_to(&buffer.buf[0..buffer.pos], src).unwrap();
+    });
+
+    let resolv_conf = fixture(
+        "failed",
+        "resolv.conf",
+        &format!(
+            "nameserver {}\nsearch down.example up.example\noptions timeout:1 attempts:1",
+            server
+        ),
+    );
+    let hosts = fixture("failed", "hosts", "");
+    let resolver = StubResolver::load(&resolv_conf, &hosts).unwrap();
+    fs::remove_dir_all(hosts.parent().unwrap()).unwrap();
+
+    let response = resolver.lookup("www", QueryType::A).unwrap();
+    assert_eq!(response.get_all_a(), vec![addr]);
+
+    // Without a response for any candidate, the failure is reported.
+    assert!(resolver.lookup("db.down.example.", QueryType::A).is_err());
+}
@@ -0,0 +1,378 @@
+use std::{
+    env, fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    sync::RwLock,
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use dns_clone::{
+    acl::{Acl, Network},
+    notify::Notifier,
+    packet::{BytePacketBuffer, Class, DnsPacket, DnsQuestion, DnsRecord, QueryType, ResultCode},
+    secondary::Secondary,
+    tcp,
+    transfer::{self, Update},
+    zone::{serial_newer, Zone, ZoneStore},
+};
+
+fn soa() -> DnsRecord {
+    soa_with_serial(1)
+}
+
+fn soa_with_serial(serial: u32) -> DnsRecord {
+    DnsRecord::SOA {
+        domain: "example.com".to_string(),
+        class: Class::IN,
+        m_name: "ns.example.com".to_string(),
+        r_name: "hostmaster.example.com".to_string(),
+        serial,
+        refresh: 3600,
+        retry: 600,
+        expire: 86400,
+        minimum: 300,
+        ttl: 3600,
+    }
+}
+
+/// A zone with an `SOA` and `count` address records.
+fn zone(count: u32) -> Zone {
+    let mut records = vec![soa()];
+    for i in 0..count {
+        records.push(DnsRecord::A {
+            domain: format!("host-{}.example.com", i),
+            class: Class::IN,
+            addr: Ipv4Addr::from(0x0A00_0000 + i),
+            ttl: 60,
+        });
+    }
+
+    Zone::new("example.com", records).unwrap()
+}
+
+fn axfr_request() -> DnsPacket {
+    let mut packet = DnsPacket::new();
+    packet.header.id = 4711;
+    packet
+        .questions
+        .push(DnsQuestion::new("example.com".to_string(), QueryType::AXFR));
+
+    packet
+}
+
+#[test]
+fn transfers_are_split_over_messages_between_soas() {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+    let request = axfr_request();
+    let server = thread::spawn({
+        let request = request.clone();
+        move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            transfer::send_axfr(&mut conn, &zone(5000), &request).unwrap();
+        }
+    });
+
+    let mut messages = Vec::new();
+    while let Some(packet) = tcp::read_packet(&mut client).unwrap() {
+        messages.push(packet);
+    }
+    server.join().unwrap();
+    assert!(messages.len() > 1);
+    assert_eq!(messages[0].questions, request.questions);
+
+    let records: Vec<DnsRecord> = messages
+        .iter()
+        .inspect(|packet| {
+            assert_eq!(packet.header.id, request.header.id);
+            assert!(packet.header.response && packet.header.authoritative_answer);
+        })
+        .flat_map(|packet| packet.answers.clone())
+        .collect();
+    assert_eq!(records.len(), 5002);
+    assert_eq!(records.first(), Some(&soa()));
+    assert_eq!(records.last(), Some(&soa()));
+}
+
+#[test]
+fn acls_match_by_prefix() {
+    let acl = Acl::new(vec![
+        "192.0.2.0/25".parse::<Network>().unwrap(),
+        "2001:db8::1".parse::<Network>().unwrap(),
+    ]);
+
+    let allowed = |addr: &str| acl.allows(&addr.parse::<IpAddr>().unwrap());
+    assert!(allowed("192.0.2.127"));
+    assert!(allowed("::ffff:192.0.2.1"));
+    assert!(allowed("2001:db8::1"));
+    assert!(!allowed("192.0.2.128"));
+    assert!(!allowed("2001:db8::2"));
+
+    assert!("192.0.2.0/33".parse::<Network>().is_err());
+
+    // IPv4 networks may be written mapped into IPv6, as long as the prefix covers the mapping.
+    let mapped = "::ffff:10.0.0.0/104".parse::<Network>().unwrap();
+    assert_eq!(mapped, "10.0.0.0/8".parse::<Network>().unwrap());
+    assert!(mapped.contains(&"10.1.2.3".parse().unwrap()));
+    assert!(mapped.contains(&"::ffff:10.1.2.3".parse().unwrap()));
+    assert!(!mapped.contains(&"11.0.0.1".parse().unwrap()));
+    assert_eq!(
+        "::ffff:192.0.2.1".parse::<Network>().unwrap(),
+        "192.0.2.1".parse::<Network>().unwrap()
+    );
+    assert!("::ffff:10.0.0.0/95".parse::<Network>().is_err());
+    assert!("::ffff:10.0.0.0/129".parse::<Network>().is_err());
+}
+
+/// Act as the primary for `zone`: answer one `SOA` query over UDP and then one
+/// transfer of the kind `qtype` over TCP.
+fn serve_primary(zone: Zone, udp: UdpSocket, tcp_listener: TcpListener, qtype: QueryType) {
+    serve_soa(&zone, &udp);
+
+    let (mut conn, _) = tcp_listener.accept().unwrap();
+    let request = tcp::read_packet(&mut conn).unwrap().unwrap();
+    assert_eq!(request.questions[0].qtype, qtype);
+    if qtype == QueryType::IXFR {
+        transfer::send_ixfr(&mut conn, &zone, &request).unwrap();
+    } else {
+        transfer::send_axfr(&mut conn, &zone, &request).unwrap();
+    }
+}
+
+/// Answer one `SOA` query for `zone` over UDP.
+fn serve_soa(zone: &Zone, udp: &UdpSocket) {
+    let mut buffer = BytePacketBuffer::new();
+    let (_, src) = udp.recv_from(&mut buffer.buf).unwrap();
+    let query = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+    let mut packet = DnsPacket::new();
+    packet.header.id = query.header.id;
+    packet.header.response = true;
+    packet.questions = query.questions.clone();
+    packet.answers.push(zone.soa().unwrap().clone());
+    let mut buffer = BytePacketBuffer::new();
+    packet.write(&mut buffer).unwrap();
+    udp.send_to(&buffer.buf[0..buffer.pos], src).unwrap();
+}
+
+#[test]
+fn secondaries_transfer_and_keep_zones() {
+    let tcp_listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let primary: SocketAddr = tcp_listener.local_addr().unwrap();
+    let udp = UdpSocket::bind(primary).unwrap();
+    let responder =
+        thread::spawn(move || serve_primary(zone(10), udp, tcp_listener, QueryType::AXFR));
+
+    let file = env::temp_dir().join(format!("secondary-{}.zone", primary.port()));
+    let zones = RwLock::new(ZoneStore::new());
+    let mut secondary = Secondary::new("example.com", primary, file.clone());
+    secondary.load(&zones).unwrap();
+
+    // Until the first transfer, there's no data to answer from.
+    let answer = zones
+        .read()
+        .unwrap()
+        .answer("host-1.example.com", QueryType::A)
+        .unwrap();
+    assert_eq!(answer.header.rescode, ResultCode::SERVFAIL);
+
+    secondary.tick(&zones, &Notifier::new());
+    responder.join().unwrap();
+
+    let answer = zones
+        .read()
+        .unwrap()
+        .answer("host-1.example.com", QueryType::A)
+        .unwrap();
+    assert_eq!(answer.answers.len(), 1);
+
+    // The saved copy is picked up again after a restart.
+    let zones = RwLock::new(ZoneStore::new());
+    let mut secondary = Secondary::new("example.com", primary, file.clone());
+    secondary.load(&zones).unwrap();
+    let zone = zones.read().unwrap().get("example.com").unwrap();
+    assert_eq!(zone.records().count(), 11);
+    assert_eq!(zone.soa(), Some(&soa()));
+
+    fs::remove_file(file).unwrap();
+}
+
+#[test]
+fn copies_confirmed_as_current_count_as_fresh() {
+    let udp = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let primary = udp.local_addr().unwrap();
+    let responder = thread::spawn(move || serve_soa(&zone(10), &udp));
+
+    // A copy saved most of a day ago, about to expire.
+    let file = env::temp_dir().join(format!("secondary-touched-{}.zone", primary.port()));
+    zone(10).save(&file).unwrap();
+    fs::File::options()
+        .write(true)
+        .open(&file)
+        .unwrap()
+        .set_modified(SystemTime::now() - Duration::from_secs(23 * 3600))
+        .unwrap();
+
+    let zones = RwLock::new(ZoneStore::new());
+    let mut secondary = Secondary::new("example.com", primary, file.clone());
+    secondary.load(&zones).unwrap();
+    secondary.tick(&zones, &Notifier::new());
+    responder.join().unwrap();
+
+    // The primary still has the same version, and the expire timer starts over.
+    let age = fs::metadata(&file).unwrap().modified().unwrap().elapsed();
+    assert!(age.unwrap() < Duration::from_secs(60));
+
+    // So a restart long after the file was written still picks it up.
+    let zones = RwLock::new(ZoneStore::new());
+    let mut secondary = Secondary::new("example.com", primary, file.clone());
+    secondary.load(&zones).unwrap();
+    let answer = zones
+        .read()
+        .unwrap()
+        .answer("host-1.example.com", QueryType::A)
+        .unwrap();
+    assert_eq!(answer.answers.len(), 1);
+
+    fs::remove_file(file).unwrap();
+}
+
+#[test]
+fn serials_wrap_around() {
+    assert!(serial_newer(2, 1));
+    assert!(!serial_newer(1, 2));
+    assert!(!serial_newer(1, 1));
+    assert!(serial_newer(1, u32::MAX));
+}
+
+/// Version `serial` of a zone that gains a host with every version.
+fn zone_version(serial: u32) -> Zone {
+    let mut records = vec![soa_with_serial(serial)];
+    for i in 0..serial {
+        records.push(DnsRecord::A {
+            domain: format!("host-{}.example.com", i),
+            class: Class::IN,
+            addr: Ipv4Addr::from(0x0A00_0000 + i),
+            ttl: 60,
+        });
+    }
+
+    Zone::new("example.com", records).unwrap()
+}
+
+/// Serve a single `IXFR` of `zone` and fetch it as the holder of `current`.
+fn ixfr(zone: &Zone, current: &Zone) -> Update {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let primary = listener.local_addr().unwrap();
+
+    thread::scope(|s| {
+        s.spawn(|| {
+            let (mut conn, _) = listener.accept().unwrap();
+            let request = tcp::read_packet(&mut conn).unwrap().unwrap();
+            transfer::send_ixfr(&mut conn, zone, &request).unwrap();
+        });
+
+        transfer::fetch_ixfr(current, primary, Duration::from_secs(5)).unwrap()
+    })
+}
+
+#[test]
+fn incremental_transfers_send_changes_from_the_journal() {
+    let mut zones = ZoneStore::new();
+    for serial in 1..=3 {
+        zones.insert(zone_version(serial));
+    }
+    let latest = zones.get("example.com").unwrap();
+
+    let Update::Incremental(diffs) = ixfr(&latest, &zone_version(1)) else {
+        panic!("expected an incremental transfer");
+    };
+    assert_eq!(diffs.len(), 2);
+    assert_eq!(diffs[1].added.len(), 1);
+    assert!(diffs[1].removed.is_empty());
+
+    let updated = zone_version(1).apply(&diffs).unwrap();
+    let mut got: Vec<&DnsRecord> = updated.records().collect();
+    let mut want: Vec<&DnsRecord> = latest.records().collect();
+    got.sort();
+    want.sort();
+    assert_eq!(got, want);
+
+    assert_eq!(ixfr(&latest, &latest), Update::UpToDate);
+
+    // Our copy of the SOA may have other timers than the primary's, only the serial matters.
+    let mut records: Vec<DnsRecord> = zone_version(1).records().cloned().collect();
+    for record in &mut records {
+        if let DnsRecord::SOA { refresh, ttl, .. } = record {
+            *refresh = 7200;
+            *ttl = 60;
+        }
+    }
+    let retimed = Zone::new("example.com", records).unwrap();
+    let Update::Incremental(diffs) = ixfr(&latest, &retimed) else {
+        panic!("expected an incremental transfer");
+    };
+    assert_eq!(diffs.len(), 2);
+    assert_eq!(retimed.apply(&diffs).unwrap().serial(), 3);
+
+    // Versions from before the journal started get the whole zone.
+    let Update::Full(records) = ixfr(&latest, &zone_version(0)) else {
+        panic!("expected a full transfer");
+    };
+    assert_eq!(records.len(), 4);
+}
+
+#[test]
+fn secondaries_apply_changes_whatever_the_timers_of_their_soa() {
+    let mut zones = ZoneStore::new();
+    for serial in 1..=3 {
+        zones.insert(zone_version(serial));
+    }
+    let latest = zones.get("example.com").unwrap();
+
+    let tcp_listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let primary: SocketAddr = tcp_listener.local_addr().unwrap();
+    let udp = UdpSocket::bind(primary).unwrap();
+
+    // Our copy is of the first version, with an SOA that differs from the primary's in
+    // everything but name and serial.
+    let mut records: Vec<DnsRecord> = zone_version(1).records().cloned().collect();
+    for record in &mut records {
+        if let DnsRecord::SOA {
+            refresh,
+            retry,
+            ttl,
+            ..
+        } = record
+        {
+            *refresh = 7200;
+            *retry = 1200;
+            *ttl = 60;
+        }
+    }
+    let file = env::temp_dir().join(format!("secondary-{}.zone", primary.port()));
+    Zone::new("example.com", records)
+        .unwrap()
+        .save(&file)
+        .unwrap();
+
+    // Only an incremental transfer is served, so falling back to a full one would fail.
+    let responder = thread::spawn({
+        let latest = (*latest).clone();
+        move || serve_primary(latest, udp, tcp_listener, QueryType::IXFR)
+    });
+
+    let zones = RwLock::new(ZoneStore::new());
+    let mut secondary = Secondary::new("example.com", primary, file.clone());
+    secondary.load(&zones).unwrap();
+    secondary.tick(&zones, &Notifier::new());
+    responder.join().unwrap();
+
+    let zone = zones.read().unwrap().get("example.com").unwrap();
+    assert_eq!(zone.serial(), 3);
+    assert_eq!(zone.soa(), latest.soa());
+    assert_eq!(zone.records().count(), latest.records().count());
+
+    fs::remove_file(file).unwrap();
+}
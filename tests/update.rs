@@ -0,0 +1,134 @@
+use std::net::Ipv4Addr;
+
+use dns_clone::{
+    packet::{Class, DnsPacket, DnsQuestion, DnsRecord, QueryType, ResultCode, UpdatePacket},
+    update,
+    zone::Zone,
+};
+
+fn zone() -> Zone {
+    Zone::new(
+        "example.com",
+        vec![
+            DnsRecord::SOA {
+                domain: "example.com".to_string(),
+                class: Class::IN,
+                m_name: "ns.example.com".to_string(),
+                r_name: "hostmaster.example.com".to_string(),
+                serial: 1,
+                refresh: 3600,
+                retry: 600,
+                expire: 86400,
+                minimum: 300,
+                ttl: 3600,
+            },
+            DnsRecord::NS {
+                domain: "example.com".to_string(),
+                class: Class::IN,
+                host: "ns.example.com".to_string(),
+                ttl: 3600,
+            },
+            a("www.example.com", 1, 60),
+        ],
+    )
+    .unwrap()
+}
+
+fn a(domain: &str, last: u8, ttl: u32) -> DnsRecord {
+    DnsRecord::A {
+        domain: domain.to_string(),
+        class: Class::IN,
+        addr: Ipv4Addr::new(192, 0, 2, last),
+        ttl,
+    }
+}
+
+/// A record standing for a whole name or RRset, as used to delete them or
+/// in prerequisites.
+fn empty(domain: &str, class: Class, qtype: u16) -> DnsRecord {
+    DnsRecord::Unknown {
+        domain: domain.to_string(),
+        class,
+        qtype,
+        data_len: 0,
+        ttl: 0,
+    }
+}
+
+fn in_class(mut record: DnsRecord, class: Class) -> DnsRecord {
+    record.set_class(class);
+    record
+}
+
+fn packet(prerequisites: Vec<DnsRecord>, updates: Vec<DnsRecord>) -> UpdatePacket {
+    UpdatePacket {
+        header: DnsPacket::new().header,
+        zones: vec![DnsQuestion::new("example.com".to_string(), QueryType::SOA)],
+        prerequisites,
+        updates,
+        additional: Vec::new(),
+    }
+}
+
+#[test]
+fn updates_add_and_delete_records_and_bump_the_serial() {
+    let update = packet(
+        vec![],
+        vec![
+            a("mail.example.com", 2, 60),
+            in_class(a("www.example.com", 1, 0), Class::NONE),
+        ],
+    );
+
+    let updated = update::apply(&zone(), &update).unwrap().unwrap();
+    assert_eq!(updated.serial(), 2);
+    assert!(updated
+        .records()
+        .any(|r| *r == a("mail.example.com", 2, 60)));
+    assert!(!updated.records().any(|r| r.domain() == "www.example.com"));
+}
+
+#[test]
+fn updates_that_change_nothing_keep_the_zone() {
+    let update = packet(vec![], vec![a("www.example.com", 1, 60)]);
+
+    assert!(update::apply(&zone(), &update).unwrap().is_none());
+}
+
+#[test]
+fn the_apex_keeps_its_name_servers() {
+    let update = packet(vec![], vec![empty("example.com", Class::ANY, 255)]);
+
+    // Everything else at the apex goes, but the NS and SOA records stay.
+    assert!(update::apply(&zone(), &update).unwrap().is_none());
+}
+
+#[test]
+fn failed_prerequisites_are_reported() {
+    let change = || a("mail.example.com", 2, 60);
+    let check = |prerequisite: DnsRecord| {
+        update::apply(&zone(), &packet(vec![prerequisite], vec![change()])).unwrap_err()
+    };
+
+    assert_eq!(
+        check(empty("www.example.com", Class::NONE, 255)),
+        ResultCode::YXDOMAIN
+    );
+    assert_eq!(
+        check(empty("www.example.com", Class::ANY, QueryType::MX.as_num())),
+        ResultCode::NXRRSET
+    );
+    assert_eq!(check(a("www.example.com", 9, 0)), ResultCode::NXRRSET);
+    assert_eq!(
+        check(empty("www.example.org", Class::ANY, 255)),
+        ResultCode::NOTZONE
+    );
+
+    // Satisfied prerequisites let the update through.
+    let satisfied = a("www.example.com", 1, 0);
+    assert!(
+        update::apply(&zone(), &packet(vec![satisfied], vec![change()]))
+            .unwrap()
+            .is_some()
+    );
+}
@@ -0,0 +1,392 @@
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, UdpSocket},
+    thread,
+    time::Duration,
+};
+
+use dns_clone::{
+    packet::{BytePacketBuffer, Class, DnsPacket, DnsQuestion, DnsRecord, QueryType},
+    rtt::RttTable,
+    tcp,
+    upstream::{
+        lookup, query, query_with, AddressFamily, Transport, UpstreamConfig, UpstreamError,
+    },
+};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Receive a single query on `socket`.
+fn recv_query(socket: &UdpSocket) -> (DnsPacket, SocketAddr) {
+    let mut buffer = BytePacketBuffer::new();
+    let (_, src) = socket.recv_from(&mut buffer.buf).unwrap();
+
+    (DnsPacket::from_buffer(&mut buffer).unwrap(), src)
+}
+
+/// Build a response to `query` with a single `A` record for the question name.
+fn answer(query: &DnsPacket, addr: Ipv4Addr) -> DnsPacket {
+    let question = &query.questions[0];
+
+    let mut packet = DnsPacket::new();
+    packet.header.id = query.header.id;
+    packet.header.response = true;
+    packet.questions.push(question.clone());
+    packet.answers.push(DnsRecord::A {
+        domain: question.name.clone(),
+        class: Class::IN,
+        addr,
+        ttl: 60,
+    });
+
+    packet
+}
+
+fn send(socket: &UdpSocket, packet: &mut DnsPacket, dst: SocketAddr) {
+    let mut buffer = BytePacketBuffer::new();
+    packet.write(&mut buffer).unwrap();
+    socket.send_to(&buffer.buf[0..buffer.pos], dst).unwrap();
+}
+
+fn local_server() -> (UdpSocket, SocketAddr) {
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let addr = socket.local_addr().unwrap();
+
+    (socket, addr)
+}
+
+#[test]
+fn spoofed_responses_are_discarded() {
+    let (server, addr) = local_server();
+    let genuine = Ipv4Addr::new(192, 0, 2, 1);
+    let spoofed = Ipv4Addr::new(203, 0, 113, 66);
+
+    let responder = thread::spawn(move || {
+        let (query, client) = recv_query(&server);
+
+        // Wrong id
+        let mut packet = answer(&query, spoofed);
+        packet.header.id = query.header.id.wrapping_add(1);
+        send(&server, &mut packet, client);
+
+        // Wrong question
+        let mut packet = answer(&query, spoofed);
+        packet.questions[0] = DnsQuestion::new("evil.example".to_string(), QueryType::A);
+        send(&server, &mut packet, client);
+
+        // Not flagged as a response
+        let mut packet = answer(&query, spoofed);
+        packet.header.response = false;
+        send(&server, &mut packet, client);
+
+        // Right id and question, but from an address that isn't the server
+        let attacker = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        send(&attacker, &mut answer(&query, spoofed), client);
+
+        // Garbage
+        server.send_to(&[0xFF; 7], client).unwrap();
+
+        send(&server, &mut answer(&query, genuine), client);
+    });
+
+    let response = lookup("www.example.com", QueryType::A, addr, TIMEOUT).unwrap();
+    responder.join().unwrap();
+
+    assert_eq!(response.get_random_a(), Some(genuine));
+}
+
+#[test]
+fn question_name_is_matched_case_insensitively() {
+    let (server, addr) = local_server();
+
+    let responder = thread::spawn(move || {
+        let (query, client) = recv_query(&server);
+
+        let mut packet = answer(&query, Ipv4Addr::new(192, 0, 2, 1));
+        packet.questions[0].name = "WWW.Example.COM".to_string();
+        send(&server, &mut packet, client);
+    });
+
+    let response = lookup("www.example.com", QueryType::A, addr, TIMEOUT).unwrap();
+    responder.join().unwrap();
+
+    assert_eq!(response.answers.len(), 1);
+}
+
+#[test]
+fn queries_use_fresh_ids_and_source_ports() {
+    let (server, addr) = local_server();
+
+    let responder = thread::spawn(move || {
+        let mut seen = Vec::new();
+        for _ in 0..8 {
+            let (query, client) = recv_query(&server);
+            seen.push((query.header.id, client.port()));
+            send(&server, &mut answer(&query, Ipv4Addr::LOCALHOST), client);
+        }
+
+        seen
+    });
+
+    for _ in 0..8 {
+        lookup("www.example.com", QueryType::A, addr, TIMEOUT).unwrap();
+    }
+    let seen = responder.join().unwrap();
+
+    // With 16 random bits per id, eight identical ids in a row is practically impossible.
+    assert!(seen.iter().any(|(id, _)| *id != seen[0].0));
+    assert!(seen.iter().any(|(_, port)| *port != seen[0].1));
+}
+
+#[test]
+fn silent_servers_are_skipped() {
+    let (silent, silent_addr) = local_server();
+    let (server, addr) = local_server();
+
+    let responder = thread::spawn(move || {
+        let (query, client) = recv_query(&server);
+        send(&server, &mut answer(&query, Ipv4Addr::LOCALHOST), client);
+    });
+
+    let config = UpstreamConfig {
+        timeout: Duration::from_millis(50),
+        ..UpstreamConfig::default()
+    };
+    let response = query(
+        "www.example.com",
+        QueryType::A,
+        &[silent_addr, addr],
+        &config,
+    )
+    .unwrap();
+    responder.join().unwrap();
+    drop(silent);
+
+    assert_eq!(response.get_random_a(), Some(Ipv4Addr::LOCALHOST));
+}
+
+#[test]
+fn unresponsive_servers_time_out() {
+    let (silent, addr) = local_server();
+
+    let config = UpstreamConfig {
+        timeout: Duration::from_millis(10),
+        max_timeout: Duration::from_millis(20),
+        attempts: 3,
+        ..UpstreamConfig::default()
+    };
+    let err = query("www.example.com", QueryType::A, &[addr], &config).unwrap_err();
+    drop(silent);
+
+    assert_eq!(
+        err.downcast_ref::<UpstreamError>(),
+        Some(&UpstreamError::Timeout {
+            qname: "www.example.com".to_string(),
+            servers: 1,
+        })
+    );
+}
+
+#[test]
+fn queries_go_out_over_ipv6() {
+    let server = match UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)) {
+        Ok(server) => server,
+        // No IPv6 on this host, nothing to test
+        Err(_) => return,
+    };
+    let addr = server.local_addr().unwrap();
+
+    let responder = thread::spawn(move || {
+        let (query, client) = recv_query(&server);
+        send(&server, &mut answer(&query, Ipv4Addr::LOCALHOST), client);
+    });
+
+    let config = UpstreamConfig {
+        family: AddressFamily::Ipv6Only,
+        ..UpstreamConfig::default()
+    };
+    let response = query("www.example.com", QueryType::A, &[addr], &config).unwrap();
+    responder.join().unwrap();
+
+    assert_eq!(response.answers.len(), 1);
+}
+
+#[test]
+fn disallowed_families_are_not_queried() {
+    let (server, addr) = local_server();
+
+    let config = UpstreamConfig {
+        family: "ipv6".parse().unwrap(),
+        ..UpstreamConfig::default()
+    };
+    assert_eq!(config.family, AddressFamily::Ipv6Only);
+    let err = query("www.example.com", QueryType::A, &[addr], &config).unwrap_err();
+    drop(server);
+
+    assert_eq!(
+        err.downcast_ref::<UpstreamError>(),
+        Some(&UpstreamError::NoServers {
+            qname: "www.example.com".to_string(),
+        })
+    );
+}
+
+/// Receive a single query on `socket`, along with the question name as it was sent.
+fn recv_query_raw(socket: &UdpSocket) -> (DnsPacket, String, SocketAddr) {
+    let mut buffer = BytePacketBuffer::new();
+    let (_, src) = socket.recv_from(&mut buffer.buf).unwrap();
+    let name = buffer.read_qname_at(12).unwrap();
+
+    (DnsPacket::from_buffer(&mut buffer).unwrap(), name, src)
+}
+
+#[test]
+fn randomized_case_must_be_echoed() {
+    let (server, addr) = local_server();
+
+    let responder = thread::spawn(move || {
+        let (query, name, client) = recv_query_raw(&server);
+
+        // Same id and question, but a spoofer who doesn't know the case, beating the real reply.
+        let mut packet = answer(&query, Ipv4Addr::new(203, 0, 113, 66));
+        packet.questions[0].name = name.to_lowercase();
+        send(&server, &mut packet, client);
+
+        let mut packet = answer(&query, Ipv4Addr::LOCALHOST);
+        packet.questions[0].name = name.clone();
+        send(&server, &mut packet, client);
+
+        name
+    });
+
+    let config = UpstreamConfig {
+        randomize_case: true,
+        attempts: 1,
+        ..UpstreamConfig::default()
+    };
+    let rtt = RttTable::new();
+    let name = "abcdefghijklmnopqrstuvwxyz.example.com";
+    let response = query_with(name, QueryType::A, &[addr], &config, Some(&rtt), Ok).unwrap();
+    let sent = responder.join().unwrap();
+
+    assert!(sent.eq_ignore_ascii_case(name));
+    assert_ne!(sent, name, "26 letters left in lowercase by chance");
+    // The forgery was ignored, and is no reason to stop randomizing.
+    assert_eq!(response.get_all_a(), vec![Ipv4Addr::LOCALHOST]);
+    assert!(rtt.preserves_case(addr.ip()));
+}
+
+#[test]
+fn servers_that_mangle_case_are_eventually_asked_without_it() {
+    let (server, addr) = local_server();
+
+    let responder = thread::spawn(move || {
+        let mut sent = Vec::new();
+        for _ in 0..4 {
+            let (query, name, client) = recv_query_raw(&server);
+            let mut packet = answer(&query, Ipv4Addr::LOCALHOST);
+            packet.questions[0].name = name.to_lowercase();
+            send(&server, &mut packet, client);
+            sent.push(name);
+        }
+
+        sent
+    });
+
+    let config = UpstreamConfig {
+        randomize_case: true,
+        timeout: Duration::from_millis(50),
+        attempts: 4,
+        ..UpstreamConfig::default()
+    };
+    let rtt = RttTable::new();
+    let name = "abcdefghijklmnopqrstuvwxyz.example.com";
+    let response = query_with(name, QueryType::A, &[addr], &config, Some(&rtt), Ok).unwrap();
+    let sent = responder.join().unwrap();
+
+    // Every randomized try went unanswered, until the server was given up on after the third.
+    assert_eq!(response.answers.len(), 1);
+    assert!(sent[..3].iter().all(|sent| sent != name));
+    assert_eq!(sent[3], name);
+    assert!(!rtt.preserves_case(addr.ip()));
+}
+
+#[test]
+fn lost_packets_are_no_reason_to_stop_randomizing_case() {
+    let (server, addr) = local_server();
+
+    let responder = thread::spawn(move || {
+        let mut sent = Vec::new();
+        for i in 0..4 {
+            let (query, name, client) = recv_query_raw(&server);
+            // Only the last try gets through.
+            if i == 3 {
+                let mut packet = answer(&query, Ipv4Addr::LOCALHOST);
+                packet.questions[0].name = name.clone();
+                send(&server, &mut packet, client);
+            }
+            sent.push(name);
+        }
+
+        sent
+    });
+
+    let config = UpstreamConfig {
+        randomize_case: true,
+        timeout: Duration::from_millis(50),
+        attempts: 4,
+        ..UpstreamConfig::default()
+    };
+    let rtt = RttTable::new();
+    let name = "abcdefghijklmnopqrstuvwxyz.example.com";
+    let response = query_with(name, QueryType::A, &[addr], &config, Some(&rtt), Ok).unwrap();
+    let sent = responder.join().unwrap();
+
+    assert_eq!(response.answers.len(), 1);
+    assert!(sent.iter().all(|sent| sent != name));
+    assert!(rtt.preserves_case(addr.ip()));
+}
+
+#[test]
+fn truncated_responses_are_retried_over_tcp() {
+    let (server, addr) = local_server();
+    let listener = TcpListener::bind(addr).unwrap();
+    let full = Ipv4Addr::new(192, 0, 2, 1);
+
+    let responder = thread::spawn(move || {
+        let (query, client) = recv_query(&server);
+        let mut packet = answer(&query, Ipv4Addr::LOCALHOST);
+        packet.header.truncated_message = true;
+        packet.answers.clear();
+        send(&server, &mut packet, client);
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let query = tcp::read_packet(&mut stream).unwrap().unwrap();
+        tcp::write_packet(&mut stream, &mut answer(&query, full)).unwrap();
+    });
+
+    let response = lookup("example.com", QueryType::A, addr, TIMEOUT).unwrap();
+    responder.join().unwrap();
+
+    assert_eq!(response.get_all_a(), vec![full]);
+}
+
+#[test]
+fn queries_go_out_over_tcp_when_configured() {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let responder = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let query = tcp::read_packet(&mut stream).unwrap().unwrap();
+        tcp::write_packet(&mut stream, &mut answer(&query, Ipv4Addr::LOCALHOST)).unwrap();
+    });
+
+    let config = UpstreamConfig {
+        transport: Transport::Tcp,
+        ..UpstreamConfig::default()
+    };
+    let response = query("example.com", QueryType::A, &[addr], &config).unwrap();
+    responder.join().unwrap();
+
+    assert_eq!(response.get_all_a(), vec![Ipv4Addr::LOCALHOST]);
+}
@@ -0,0 +1,385 @@
+use std::{net::Ipv4Addr, path::Path};
+
+use dns_clone::{
+    packet::{Class, DnsPacket, DnsRecord, QueryType, ResultCode},
+    zone::{Lookup, Zone, ZoneStore},
+    zonefile,
+};
+
+const EXAMPLE: &str = "\
+$TTL 3600
+@               SOA     ns hostmaster 1 3600 600 86400 300
+@               NS      ns
+@               MX      10 mail
+ns              A       192.0.2.1
+mail            A       192.0.2.2
+www             A       192.0.2.3
+alias           CNAME   www
+elsewhere       CNAME   www.example.org.
+outside         CNAME   www.example.net.
+sub             NS      ns.sub
+sub             NS      ns.example.net.
+ns.sub          A       192.0.2.53
+";
+
+const OTHER: &str = "\
+$TTL 3600
+@               SOA     ns.example.com. hostmaster.example.com. 1 3600 600 86400 300
+www             A       198.51.100.1
+";
+
+/// Wildcards in all the places RFC 4592 has something to say about.
+const WILDCARDS: &str = "\
+$TTL 3600
+@               SOA     ns hostmaster 1 3600 600 86400 300
+@               NS      ns
+ns              A       192.0.2.1
+*               A       192.0.2.10
+*               MX      10 mail
+host            A       192.0.2.20
+leaf.ent        A       192.0.2.30
+*.cdn           CNAME   host
+*.text          TXT     \"wild\"
+sub             NS      ns.sub
+ns.sub          A       192.0.2.53
+";
+
+fn zone(origin: &str, text: &str) -> Zone {
+    let records = zonefile::parse_str(text, origin, Path::new(".")).unwrap();
+    Zone::new(origin, records).unwrap()
+}
+
+fn store() -> ZoneStore {
+    let mut zones = ZoneStore::new();
+    zones.insert(zone("example.com", EXAMPLE));
+    zones.insert(zone("example.org", OTHER));
+    zones
+}
+
+fn store_of(zone: Zone) -> ZoneStore {
+    let mut zones = ZoneStore::new();
+    zones.insert(zone);
+    zones
+}
+
+fn a(domain: &str, addr: [u8; 4]) -> DnsRecord {
+    DnsRecord::A {
+        domain: domain.to_string(),
+        class: Class::IN,
+        addr: Ipv4Addr::from(addr),
+        ttl: 3600,
+    }
+}
+
+fn cname(domain: &str, host: &str) -> DnsRecord {
+    DnsRecord::CNAME {
+        domain: domain.to_string(),
+        class: Class::IN,
+        host: host.to_string(),
+        ttl: 3600,
+    }
+}
+
+fn ns(domain: &str, host: &str) -> DnsRecord {
+    DnsRecord::NS {
+        domain: domain.to_string(),
+        class: Class::IN,
+        host: host.to_string(),
+        ttl: 3600,
+    }
+}
+
+/// The `SOA` of example.com as it goes with negative answers, its TTL down to the minimum.
+fn negative_soa() -> DnsRecord {
+    DnsRecord::SOA {
+        domain: "example.com".to_string(),
+        class: Class::IN,
+        m_name: "ns.example.com".to_string(),
+        r_name: "hostmaster.example.com".to_string(),
+        serial: 1,
+        refresh: 3600,
+        retry: 600,
+        expire: 86400,
+        minimum: 300,
+        ttl: 300,
+    }
+}
+
+fn answer(zones: &ZoneStore, qname: &str, qtype: QueryType) -> DnsPacket {
+    zones.answer(qname, qtype).unwrap()
+}
+
+#[test]
+fn names_are_answered_with_their_records() {
+    let zones = store();
+
+    let response = answer(&zones, "WWW.Example.com", QueryType::A);
+    assert!(response.header.authoritative_answer);
+    assert_eq!(response.header.rescode, ResultCode::NOERROR);
+    assert_eq!(response.answers, vec![a("www.example.com", [192, 0, 2, 3])]);
+    assert!(response.authorities.is_empty());
+
+    // The addresses of mail exchangers and name servers come along.
+    let response = answer(&zones, "example.com", QueryType::MX);
+    assert_eq!(
+        response.resources,
+        vec![a("mail.example.com", [192, 0, 2, 2])]
+    );
+    let response = answer(&zones, "example.com", QueryType::NS);
+    assert_eq!(response.answers, vec![ns("example.com", "ns.example.com")]);
+    assert_eq!(
+        response.resources,
+        vec![a("ns.example.com", [192, 0, 2, 1])]
+    );
+
+    assert!(zones.answer("www.example.net", QueryType::A).is_none());
+}
+
+#[test]
+fn negative_answers_come_with_the_soa() {
+    let zones = store();
+
+    let nxdomain = answer(&zones, "nowhere.example.com", QueryType::A);
+    assert!(nxdomain.header.authoritative_answer);
+    assert_eq!(nxdomain.header.rescode, ResultCode::NXDOMAIN);
+    assert!(nxdomain.answers.is_empty());
+    assert_eq!(nxdomain.authorities, vec![negative_soa()]);
+
+    let nodata = answer(&zones, "www.example.com", QueryType::AAAA);
+    assert!(nodata.header.authoritative_answer);
+    assert_eq!(nodata.header.rescode, ResultCode::NOERROR);
+    assert!(nodata.answers.is_empty());
+    assert_eq!(nodata.authorities, vec![negative_soa()]);
+
+    // Names above the ones that own records exist, they just have none.
+    let mut zones = store();
+    zones.insert(zone(
+        "example.com",
+        &format!("{}a.b.deep A 192.0.2.4\n", EXAMPLE),
+    ));
+    let nodata = answer(&zones, "b.deep.example.com", QueryType::A);
+    assert_eq!(nodata.header.rescode, ResultCode::NOERROR);
+    assert_eq!(nodata.authorities, vec![negative_soa()]);
+}
+
+#[test]
+fn aliases_are_followed_through_our_zones() {
+    let zones = store();
+
+    let response = answer(&zones, "alias.example.com", QueryType::A);
+    assert_eq!(
+        response.answers,
+        vec![
+            cname("alias.example.com", "www.example.com"),
+            a("www.example.com", [192, 0, 2, 3]),
+        ]
+    );
+
+    let response = answer(&zones, "elsewhere.example.com", QueryType::A);
+    assert_eq!(
+        response.answers,
+        vec![
+            cname("elsewhere.example.com", "www.example.org"),
+            DnsRecord::A {
+                domain: "www.example.org".to_string(),
+                class: Class::IN,
+                addr: Ipv4Addr::new(198, 51, 100, 1),
+                ttl: 3600,
+            },
+        ]
+    );
+
+    // Other zones are up to the client.
+    let response = answer(&zones, "outside.example.com", QueryType::A);
+    assert_eq!(
+        response.answers,
+        vec![cname("outside.example.com", "www.example.net")]
+    );
+    assert!(response.authorities.is_empty());
+
+    // Aliases are answered as themselves when asked for.
+    let response = answer(&zones, "alias.example.com", QueryType::CNAME);
+    assert_eq!(
+        response.answers,
+        vec![cname("alias.example.com", "www.example.com")]
+    );
+}
+
+#[test]
+fn delegated_names_are_referred_with_glue() {
+    let zones = store();
+
+    for qname in [
+        "sub.example.com",
+        "www.sub.example.com",
+        "ns.sub.example.com",
+    ] {
+        let response = answer(&zones, qname, QueryType::A);
+        assert!(!response.header.authoritative_answer, "{}", qname);
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert!(response.answers.is_empty());
+        assert_eq!(
+            response.authorities,
+            vec![
+                ns("sub.example.com", "ns.sub.example.com"),
+                ns("sub.example.com", "ns.example.net"),
+            ]
+        );
+        // Only the server inside the zone needs glue, the other one can be looked up.
+        assert_eq!(
+            response.resources,
+            vec![a("ns.sub.example.com", [192, 0, 2, 53])]
+        );
+    }
+}
+
+#[test]
+fn expired_zones_fail_rather_than_answer() {
+    let mut zones = store();
+    zones.expire("example.com.");
+
+    let response = answer(&zones, "www.example.com", QueryType::A);
+    assert_eq!(response.header.rescode, ResultCode::SERVFAIL);
+    assert!(response.answers.is_empty());
+
+    // A new version brings it back.
+    zones.insert(zone("example.com", EXAMPLE));
+    let response = answer(&zones, "www.example.com", QueryType::A);
+    assert_eq!(response.answers, vec![a("www.example.com", [192, 0, 2, 3])]);
+}
+
+#[test]
+fn any_is_answered_with_every_record() {
+    let zones = store();
+
+    let response = answer(&zones, "www.example.com", QueryType::ANY);
+    assert_eq!(response.header.rescode, ResultCode::NOERROR);
+    assert_eq!(response.answers, vec![a("www.example.com", [192, 0, 2, 3])]);
+
+    let response = answer(&zones, "example.com", QueryType::ANY);
+    let mut types: Vec<QueryType> = response.answers.iter().map(DnsRecord::qtype).collect();
+    types.sort_by_key(|qtype| qtype.as_num());
+    assert_eq!(types, vec![QueryType::NS, QueryType::SOA, QueryType::MX]);
+
+    // Aliases included, which aren't followed.
+    let response = answer(&zones, "alias.example.com", QueryType::ANY);
+    assert_eq!(
+        response.answers,
+        vec![cname("alias.example.com", "www.example.com")]
+    );
+
+    let nxdomain = answer(&zones, "nowhere.example.com", QueryType::ANY);
+    assert_eq!(nxdomain.header.rescode, ResultCode::NXDOMAIN);
+
+    // Wildcards too.
+    let zone = zone("example.com", WILDCARDS);
+    let Lookup::Answer(records) = zone.lookup("nothing.example.com", QueryType::ANY) else {
+        panic!("No answer from the wildcard");
+    };
+    assert_eq!(records.len(), 2);
+    assert!(records
+        .iter()
+        .all(|record| record.domain() == "nothing.example.com"));
+}
+
+#[test]
+fn wildcards_answer_for_names_that_dont_exist() {
+    let zone = zone("example.com", WILDCARDS);
+
+    // The records are made out to the name asked about, however deep below the wildcard.
+    for qname in ["nothing.example.com", "a.b.c.example.com"] {
+        assert_eq!(
+            zone.lookup(qname, QueryType::A),
+            Lookup::Answer(vec![a(qname, [192, 0, 2, 10])])
+        );
+    }
+
+    // The wildcard itself is a name like any other.
+    assert_eq!(
+        zone.lookup("*.example.com", QueryType::A),
+        Lookup::Answer(vec![a("*.example.com", [192, 0, 2, 10])])
+    );
+
+    // Types the wildcard has no records of are NODATA, not NXDOMAIN.
+    assert_eq!(
+        zone.lookup("nothing.example.com", QueryType::AAAA),
+        Lookup::NoData
+    );
+    assert_eq!(
+        zone.lookup("x.text.example.com", QueryType::A),
+        Lookup::NoData
+    );
+    assert_eq!(
+        zone.lookup("x.text.example.com", QueryType::TXT),
+        Lookup::Answer(vec![DnsRecord::TXT {
+            domain: "x.text.example.com".to_string(),
+            class: Class::IN,
+            data: vec!["wild".to_string()],
+            ttl: 3600,
+        }])
+    );
+
+    let nodata = store_of(zone)
+        .answer("nothing.example.com", QueryType::AAAA)
+        .unwrap();
+    assert_eq!(nodata.header.rescode, ResultCode::NOERROR);
+    assert_eq!(nodata.authorities, vec![negative_soa()]);
+}
+
+#[test]
+fn wildcards_dont_cover_names_that_exist() {
+    let zone = zone("example.com", WILDCARDS);
+
+    // Not even for the types the name has no records of.
+    assert_eq!(
+        zone.lookup("host.example.com", QueryType::MX),
+        Lookup::NoData
+    );
+
+    // Empty non-terminals exist too, and the wildcard above them doesn't reach below them.
+    assert_eq!(zone.lookup("ent.example.com", QueryType::A), Lookup::NoData);
+    assert_eq!(zone.lookup("cdn.example.com", QueryType::A), Lookup::NoData);
+    assert_eq!(
+        zone.lookup("other.ent.example.com", QueryType::A),
+        Lookup::NxDomain
+    );
+    assert_eq!(
+        zone.lookup("deeper.leaf.ent.example.com", QueryType::A),
+        Lookup::NxDomain
+    );
+}
+
+#[test]
+fn wildcards_dont_cross_delegations() {
+    let zone = zone("example.com", WILDCARDS);
+
+    for qname in ["sub.example.com", "nothing.sub.example.com"] {
+        assert_eq!(
+            zone.lookup(qname, QueryType::A),
+            Lookup::Referral(vec![ns("sub.example.com", "ns.sub.example.com")]),
+            "{}",
+            qname
+        );
+    }
+}
+
+#[test]
+fn wildcard_aliases_are_followed() {
+    let zone = zone("example.com", WILDCARDS);
+
+    assert_eq!(
+        zone.lookup("img.cdn.example.com", QueryType::A),
+        Lookup::Alias(cname("img.cdn.example.com", "host.example.com"))
+    );
+
+    let response = store_of(zone)
+        .answer("img.cdn.example.com", QueryType::A)
+        .unwrap();
+    assert_eq!(
+        response.answers,
+        vec![
+            cname("img.cdn.example.com", "host.example.com"),
+            a("host.example.com", [192, 0, 2, 20]),
+        ]
+    );
+}
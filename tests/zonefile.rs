@@ -0,0 +1,272 @@
+use std::{
+    env, fs,
+    net::{Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+};
+
+use dns_clone::{
+    packet::{Class, DnsRecord},
+    zonefile::{self, parse_ttl},
+};
+
+/// A directory of its own for the test called `name`, with `files` written to it.
+fn fixture(name: &str, files: &[(&str, &str)]) -> PathBuf {
+    let dir = env::temp_dir().join(format!(
+        "dns-clone-zonefile-{}-{}",
+        name,
+        std::process::id()
+    ));
+    fs::create_dir_all(&dir).unwrap();
+    for (file, contents) in files {
+        fs::write(dir.join(file), contents).unwrap();
+    }
+
+    dir
+}
+
+fn parse(input: &str) -> dns_clone::packet::Result<Vec<DnsRecord>> {
+    zonefile::parse_str(input, "example.com", Path::new("."))
+}
+
+fn a(domain: &str, addr: [u8; 4], ttl: u32) -> DnsRecord {
+    DnsRecord::A {
+        domain: domain.to_string(),
+        class: Class::IN,
+        addr: Ipv4Addr::from(addr),
+        ttl,
+    }
+}
+
+fn soa(domain: &str, ttl: u32) -> DnsRecord {
+    DnsRecord::SOA {
+        domain: domain.to_string(),
+        class: Class::IN,
+        m_name: "ns.example.com".to_string(),
+        r_name: "hostmaster.example.com".to_string(),
+        serial: 2024010101,
+        refresh: 3600,
+        retry: 600,
+        expire: 86400,
+        minimum: 300,
+        ttl,
+    }
+}
+
+#[test]
+fn entries_are_read_with_their_directives() {
+    let records = parse(
+        "\
+$TTL 1h
+@   IN  SOA ns hostmaster (
+            2024010101  ; serial
+            1h          ; refresh
+            10m         ; retry
+            1d          ; expire
+            5m )        ; minimum
+    IN  NS  ns
+ns      A   192.0.2.1
+www 300 IN  A   192.0.2.2
+        IN 2d A 192.0.2.3
+mail.example.com. A 192.0.2.4
+$ORIGIN sub
+host    A   192.0.2.5
+$ORIGIN other.example.
+@       A   192.0.2.6
+",
+    )
+    .unwrap();
+
+    assert_eq!(
+        records,
+        vec![
+            soa("example.com", 3600),
+            DnsRecord::NS {
+                domain: "example.com".to_string(),
+                class: Class::IN,
+                host: "ns.example.com".to_string(),
+                ttl: 3600,
+            },
+            a("ns.example.com", [192, 0, 2, 1], 3600),
+            a("www.example.com", [192, 0, 2, 2], 300),
+            // The owner is repeated, and the TTL may come after the class.
+            a("www.example.com", [192, 0, 2, 3], 2 * 86400),
+            a("mail.example.com", [192, 0, 2, 4], 3600),
+            // A relative origin is relative to the one before.
+            a("host.sub.example.com", [192, 0, 2, 5], 3600),
+            a("other.example", [192, 0, 2, 6], 3600),
+        ]
+    );
+}
+
+#[test]
+fn record_data_is_parsed_by_type() {
+    let records = parse(
+        "\
+$TTL 60
+@       MX      10 mail
+@       TXT     \"v=spf1 -all\" \"with \\\"quotes\\\"\"
+alias   CNAME   www.example.net.
+www     AAAA    2001:db8::1
+",
+    )
+    .unwrap();
+
+    assert_eq!(
+        records,
+        vec![
+            DnsRecord::MX {
+                domain: "example.com".to_string(),
+                class: Class::IN,
+                priority: 10,
+                host: "mail.example.com".to_string(),
+                ttl: 60,
+            },
+            DnsRecord::TXT {
+                domain: "example.com".to_string(),
+                class: Class::IN,
+                data: vec!["v=spf1 -all".to_string(), "with \"quotes\"".to_string()],
+                ttl: 60,
+            },
+            DnsRecord::CNAME {
+                domain: "alias.example.com".to_string(),
+                class: Class::IN,
+                host: "www.example.net".to_string(),
+                ttl: 60,
+            },
+            DnsRecord::AAAA {
+                domain: "www.example.com".to_string(),
+                class: Class::IN,
+                addr: "2001:db8::1".parse::<Ipv6Addr>().unwrap(),
+                ttl: 60,
+            },
+        ]
+    );
+}
+
+#[test]
+fn ttls_come_with_units() {
+    assert_eq!(parse_ttl("300").unwrap(), 300);
+    assert_eq!(parse_ttl("90s").unwrap(), 90);
+    assert_eq!(parse_ttl("1h30m").unwrap(), 5400);
+    assert_eq!(parse_ttl("1W2D").unwrap(), 9 * 86400);
+
+    for bad in ["h", "1x", "10m5", "99999999w"] {
+        assert!(parse_ttl(bad).is_err(), "{} parsed", bad);
+    }
+
+    // Without any TTL, the SOA minimum is all there is to go on.
+    let records = parse("@ SOA ns hostmaster 2024010101 3600 600 86400 300\n").unwrap();
+    assert_eq!(records, vec![soa("example.com", 300)]);
+}
+
+#[test]
+fn records_of_unsupported_types_are_skipped() {
+    let records = parse(
+        "\
+$TTL 60
+_dns._udp   SRV     0 0 53 ns
+www         A       192.0.2.1
+            CAA     0 issue \"ca.example.net\"
+            A       192.0.2.2
+",
+    )
+    .unwrap();
+
+    // The rest of the zone is there, owner names carried over the skipped records included.
+    assert_eq!(
+        records,
+        vec![
+            a("www.example.com", [192, 0, 2, 1], 60),
+            a("www.example.com", [192, 0, 2, 2], 60),
+        ]
+    );
+}
+
+#[test]
+fn bad_input_is_rejected() {
+    let cases = [
+        ("$TTL 60\n@ A (192.0.2.1\n", "unbalanced '('"),
+        ("$TTL 60\n@ A 192.0.2.1)\n", "unbalanced ')'"),
+        ("$TTL 60\n@ TXT \"open\n", "unterminated string"),
+        ("$GENERATE 1-10 host$ A 192.0.2.$\n", "Unknown directive"),
+        ("$TTL 60\n@ CH TXT \"chaos\"\n", "Unsupported class"),
+        ("$TTL 60\n@ A 192.0.2.1 192.0.2.2\n", "takes 1 field(s)"),
+        ("$TTL 60\n@ A 192.0.2.256\n", "invalid"),
+        ("www A 192.0.2.1\n", "No TTL given"),
+        ("  A 192.0.2.1\n", "No previous owner"),
+        ("$ORIGIN\n", "needs an argument"),
+    ];
+
+    for (input, error) in cases {
+        let e = parse(input).unwrap_err().to_string();
+        assert!(e.contains(error), "{:?} gave {:?}", input, e);
+    }
+
+    // Errors point at where they are.
+    let e = parse("$TTL 60\n\n@ A 192.0.2.1\nwww A nowhere\n").unwrap_err();
+    assert!(e.to_string().starts_with("<input>:4:"), "{}", e);
+}
+
+#[test]
+fn files_are_included_with_an_origin_of_their_own() {
+    let dir = fixture(
+        "include",
+        &[
+            (
+                "example.com.zone",
+                "$TTL 60\n$INCLUDE hosts.inc lan\nafter A 192.0.2.9\n",
+            ),
+            ("hosts.inc", "printer A 192.0.2.1\n$INCLUDE nested.inc\n"),
+            ("nested.inc", "@ A 192.0.2.2\n"),
+            ("loop.zone", "$TTL 60\n$INCLUDE loop.zone\n"),
+        ],
+    );
+
+    let records = zonefile::parse_file(&dir.join("example.com.zone"), "example.com").unwrap();
+    assert_eq!(
+        records,
+        vec![
+            a("printer.lan.example.com", [192, 0, 2, 1], 60),
+            a("lan.example.com", [192, 0, 2, 2], 60),
+            // The origin given with the directive ends with the included file.
+            a("after.example.com", [192, 0, 2, 9], 60),
+        ]
+    );
+
+    // Files including themselves give up eventually.
+    let e = zonefile::parse_file(&dir.join("loop.zone"), "example.com").unwrap_err();
+    assert!(e.to_string().contains("nested too deeply"), "{}", e);
+
+    let e = zonefile::parse_file(&dir.join("missing.zone"), "example.com").unwrap_err();
+    assert!(e.to_string().contains("Unable to read"), "{}", e);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn written_files_read_back_the_same() {
+    let records = parse(
+        "\
+$TTL 3600
+@       SOA     ns hostmaster 2024010101 3600 600 86400 300
+@       NS      ns
+@       MX      10 mail
+@       TXT     \"back\\\\slash\" \"\\\"quoted\\\"\"
+ns      A       192.0.2.1
+ns      AAAA    2001:db8::1
+www  60 CNAME   ns
+1.2     PTR     ns
+",
+    )
+    .unwrap();
+
+    let dir = fixture("write", &[]);
+    let path = dir.join("example.com.zone");
+    zonefile::write_file(&path, "example.com", &records).unwrap();
+
+    assert_eq!(zonefile::parse_file(&path, "example.com").unwrap(), records);
+    // Nothing is left behind from writing the file.
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+    fs::remove_dir_all(dir).unwrap();
+}